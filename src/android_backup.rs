@@ -0,0 +1,569 @@
+/* reading and writing the XML backups of the Android app "SMS Backup & Restore":
+
+<smses count="2">
+	<sms address="+12024561111" date="1589921285000" type="1" body="hello" ... />
+	<mms date="1589921299000" msg_box="2" address="+12024561111~+12024561414" ...>
+		<parts>
+			<part seq="0" ct="text/plain" cl="txt000.txt" text="hi" ... />
+			<part seq="1" ct="image/png" cl="red.png" data="(base64)" ... />
+		</parts>
+		<addrs>
+			<addr address="+12024561111" type="137" charset="106" />
+		</addrs>
+	</mms>
+</smses>
+
+dates are in milliseconds, like MessageInfo::time. */
+
+use quick_xml::events::{BytesStart, Event};
+use rusqlite::Connection;
+
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+use crate::{db, export, store};
+use crate::types::*;
+
+#[derive(Debug)]
+pub enum BackupError {
+	Xml(quick_xml::Error),
+	Db(rusqlite::Error),
+	Io(io::Error),
+}
+
+impl std::fmt::Display for BackupError {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self {
+			BackupError::Xml(e) => write!(f, "could not parse backup: {}", e),
+			BackupError::Db(e) => write!(f, "database error: {}", e),
+			BackupError::Io(e) => write!(f, "{}", e),
+		}
+	}
+}
+
+impl From<quick_xml::Error> for BackupError {
+	fn from(e: quick_xml::Error) -> Self {
+		BackupError::Xml(e)
+	}
+}
+
+impl From<quick_xml::events::attributes::AttrError> for BackupError {
+	fn from(e: quick_xml::events::attributes::AttrError) -> Self {
+		BackupError::Xml(e.into())
+	}
+}
+
+impl From<rusqlite::Error> for BackupError {
+	fn from(e: rusqlite::Error) -> Self {
+		BackupError::Db(e)
+	}
+}
+
+impl From<io::Error> for BackupError {
+	fn from(e: io::Error) -> Self {
+		BackupError::Io(e)
+	}
+}
+
+/* MMS address types */
+const ADDR_FROM: &str = "137";
+const ADDR_TO: &str = "151";
+
+const BASE64_CHARS: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(data: &[u8]) -> String {
+	let mut out = String::with_capacity((data.len() + 2) / 3 * 4);
+	for chunk in data.chunks(3) {
+		let n = chunk.iter().enumerate().fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+		for i in 0..4 {
+			if i <= chunk.len() {
+				out.push(BASE64_CHARS[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+			} else {
+				out.push('=');
+			}
+		}
+	}
+	out
+}
+
+fn base64_decode(s: &str) -> Option<Vec<u8>> {
+	let mut out = Vec::with_capacity(s.len() / 4 * 3);
+	let (mut n, mut bits) = (0u32, 0);
+	for c in s.bytes() {
+		let v = match c {
+			b'A'..=b'Z' => c - b'A',
+			b'a'..=b'z' => c - b'a' + 26,
+			b'0'..=b'9' => c - b'0' + 52,
+			b'+' => 62,
+			b'/' => 63,
+			b'=' => break,
+			b' ' | b'\n' | b'\r' | b'\t' => continue,
+			_ => return None,
+		};
+		n = n << 6 | v as u32;
+		bits += 6;
+		if bits >= 8 {
+			bits -= 8;
+			out.push((n >> bits) as u8);
+		}
+	}
+	Some(out)
+}
+
+/* attribute values of an element, with "null" (how the app writes missing values) as None */
+struct Attrs(Vec<(String, String)>);
+
+impl Attrs {
+	fn of(e: &BytesStart) -> Result<Attrs, BackupError> {
+		let mut attrs = vec![];
+		for attr in e.attributes() {
+			let attr = attr?;
+			let key = String::from_utf8_lossy(attr.key.as_ref()).into_owned();
+			attrs.push((key, attr.unescape_value()?.into_owned()));
+		}
+		Ok(Attrs(attrs))
+	}
+
+	fn get(&self, key: &str) -> Option<&str> {
+		self.0.iter()
+			.find(|(k, _v)| k == key)
+			.map(|(_k, v)| &**v)
+			.filter(|v| *v != "null")
+	}
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ImportStats {
+	pub imported: usize,
+	/* already in the database */
+	pub duplicates: usize,
+	/* addresses that could not be parsed as phone numbers, or unusable dates */
+	pub skipped: usize,
+}
+
+/* reads a backup, storing its attachments. it doesn't touch the database, so it can run off the
+GTK thread; the Backup it reads is then inserted in one go. */
+pub struct Importer<'a> {
+	pub my_number: Number,
+	pub my_country: Country,
+	pub attachment_dir: &'a Path,
+}
+
+/* the messages of a backup, whose attachment items index the attachments alongside them until
+they are inserted */
+pub struct Backup {
+	messages: Vec<(MessageInfo, Vec<Attachment>)>,
+	/* addresses that could not be parsed as phone numbers, or unusable dates */
+	skipped: usize,
+}
+
+/* an <mms> element whose parts and addrs are being read */
+struct PendingMms {
+	attrs: Attrs,
+	parts: Vec<Attrs>,
+	addrs: Vec<Attrs>,
+}
+
+impl<'a> Importer<'a> {
+	pub fn read_file(&self, path: &Path) -> Result<Backup, BackupError> {
+		self.read(io::BufReader::new(std::fs::File::open(path)?))
+	}
+
+	pub fn read<R: BufRead>(&self, src: R) -> Result<Backup, BackupError> {
+		let mut reader = quick_xml::Reader::from_reader(src);
+		let mut backup = Backup { messages: vec![], skipped: 0 };
+		let mut mms: Option<PendingMms> = None;
+		let mut buf = vec![];
+		loop {
+			let (e, empty) = match reader.read_event_into(&mut buf)? {
+				Event::Start(e) => (e, false),
+				Event::Empty(e) => (e, true),
+				Event::End(e) => {
+					if e.name().as_ref() == b"mms" {
+						if let Some(pending) = mms.take() {
+							self.read_mms(pending, &mut backup)?;
+						}
+					}
+					buf.clear();
+					continue
+				},
+				Event::Eof => break,
+				_ => {
+					buf.clear();
+					continue
+				},
+			};
+			match (e.name().as_ref(), &mut mms) {
+				(b"sms", None) => self.read_sms(Attrs::of(&e)?, &mut backup),
+				(b"mms", None) => {
+					let pending = PendingMms { attrs: Attrs::of(&e)?, parts: vec![], addrs: vec![] };
+					if empty {
+						self.read_mms(pending, &mut backup)?;
+					} else {
+						mms = Some(pending);
+					}
+				},
+				(b"part", Some(pending)) => pending.parts.push(Attrs::of(&e)?),
+				(b"addr", Some(pending)) => pending.addrs.push(Attrs::of(&e)?),
+				_ => (),
+			}
+			buf.clear();
+		}
+		Ok(backup)
+	}
+
+	fn number(&self, address: &str) -> Option<Number> {
+		Number::normalize(address, self.my_country)
+	}
+
+	fn read_sms(&self, attrs: Attrs, backup: &mut Backup) {
+		let (other, time) = match (attrs.get("address").and_then(|a| self.number(a)), attrs.get("date").and_then(|d| d.parse().ok())) {
+			(Some(other), Some(time)) => (other, time),
+			_ => {
+				backup.skipped += 1;
+				return
+			},
+		};
+		/* 1 inbox, 2 sent, 3 draft, 4 outbox, 5 failed, 6 queued */
+		let (sender, status) = match attrs.get("type") {
+			Some("1") => (other, MessageStatus::Received),
+			Some("2") => (self.my_number, MessageStatus::Sent),
+			Some("3") => (self.my_number, MessageStatus::Draft),
+			_ => (self.my_number, MessageStatus::Failed),
+		};
+		let mut chat = vec![other, self.my_number];
+		chat.sort();
+		chat.dedup();
+		let contents = vec![MessageItem::Text(attrs.get("body").unwrap_or("").to_owned())];
+		backup.messages.push((MessageInfo { sender, chat, time, seq: 0, contents, status }, vec![]));
+	}
+
+	fn read_mms(&self, mms: PendingMms, backup: &mut Backup) -> Result<(), BackupError> {
+		let time = match mms.attrs.get("date").and_then(|d| d.parse().ok()) {
+			Some(time) => time,
+			None => {
+				backup.skipped += 1;
+				return Ok(())
+			},
+		};
+		let mut chat = vec![self.my_number];
+		let mut from = None;
+		for addr in &mms.addrs {
+			let number = match addr.get("address").and_then(|a| self.number(a)) {
+				Some(n) => n,
+				None => continue,
+			};
+			if addr.get("type") == Some(ADDR_FROM) {
+				from = Some(number);
+			}
+			chat.push(number);
+		}
+		/* backups without <addrs> still list the other members in the address attribute */
+		if mms.addrs.is_empty() {
+			if let Some(address) = mms.attrs.get("address") {
+				chat.extend(address.split('~').filter_map(|a| self.number(a)));
+			}
+		}
+		chat.sort();
+		chat.dedup();
+
+		/* 1 inbox, 2 sent, 3 draft, 4 outbox */
+		let (sender, status) = match mms.attrs.get("msg_box") {
+			Some("1") => match from.or_else(|| chat.iter().cloned().find(|n| *n != self.my_number)) {
+				Some(from) => (from, MessageStatus::Received),
+				None => {
+					backup.skipped += 1;
+					return Ok(())
+				},
+			},
+			Some("2") => (self.my_number, MessageStatus::Sent),
+			Some("3") => (self.my_number, MessageStatus::Draft),
+			_ => (self.my_number, MessageStatus::Failed),
+		};
+		if chat.len() < 2 {
+			backup.skipped += 1;
+			return Ok(())
+		}
+
+		let mut contents = vec![];
+		let mut attachments = vec![];
+		for part in &mms.parts {
+			let mime_type = part.get("ct").unwrap_or("application/octet-stream");
+			if mime_type == "application/smil" {
+				continue
+			}
+			if let Some(text) = part.get("text") {
+				if mime_type.starts_with("text/plain") {
+					contents.push(MessageItem::Text(text.to_owned()));
+					continue
+				}
+			}
+			let data = match part.get("data").and_then(base64_decode) {
+				Some(data) => data,
+				None => match part.get("text") {
+					Some(text) => text.as_bytes().to_vec(),
+					None => continue,
+				},
+			};
+			let path = store::import_bytes(self.attachment_dir, &data)?;
+			let name = part.get("cl").or(part.get("name")).or(part.get("fn")).unwrap_or("attachment");
+			contents.push(MessageItem::Attachment(attachments.len() as AttachmentId));
+			attachments.push(Attachment {
+				name: name.into(),
+				mime_type: mime_type.to_owned(),
+				data: (path, 0, data.len() as u64),
+			});
+		}
+		backup.messages.push((MessageInfo { sender, chat, time, seq: 0, contents, status }, attachments));
+		Ok(())
+	}
+}
+
+impl Backup {
+	/* add the messages to the database in one transaction, so a failed import adds none */
+	pub fn insert(self, conn: &mut Connection, next_message_id: &mut MessageId,
+		next_attachment_id: &mut AttachmentId) -> Result<ImportStats, BackupError> {
+		let mut stats = ImportStats { skipped: self.skipped, ..ImportStats::default() };
+		conn.execute_batch("BEGIN")?;
+		let inserted = (|| -> Result<(), BackupError> {
+			for (msg, attachments) in self.messages {
+				insert_message(conn, msg, attachments, next_message_id, next_attachment_id, &mut stats)?;
+			}
+			Ok(())
+		})();
+		match inserted {
+			Ok(()) => conn.execute_batch("COMMIT")?,
+			Err(e) => {
+				let _ = conn.execute_batch("ROLLBACK");
+				return Err(e)
+			},
+		}
+		Ok(stats)
+	}
+}
+
+fn insert_message(conn: &mut Connection, mut msg: MessageInfo, attachments: Vec<Attachment>,
+	next_message_id: &mut MessageId, next_attachment_id: &mut AttachmentId,
+	stats: &mut ImportStats) -> Result<(), BackupError> {
+	/* re-importing a backup finds the messages it imported last time, as does a message that
+	is in it twice */
+	let text = msg.contents.iter().filter_map(|item| match item {
+		MessageItem::Text(t) => Some(&**t),
+		_ => None,
+	}).collect::<Vec<_>>().join("\n");
+	if db::message_exists(conn, &msg.chat, msg.sender, msg.time, &text)? {
+		stats.duplicates += 1;
+		return Ok(())
+	}
+	let mut ids = vec![];
+	for att in &attachments {
+		let id = *next_attachment_id;
+		*next_attachment_id += 1;
+		db::insert_attachment(conn, &id, att)?;
+		ids.push(id);
+	}
+	for item in &mut msg.contents {
+		if let MessageItem::Attachment(idx) = item {
+			*idx = ids[*idx as usize];
+		}
+	}
+	if db::find_chat(conn, &msg.chat)?.is_none() {
+		db::insert_chat(conn, &Chat { numbers: msg.chat.clone() }, -1, None)?;
+	}
+	let id = *next_message_id;
+	next_message_id.increment();
+	db::insert_message(conn, &id, &msg)?;
+	stats.imported += 1;
+	Ok(())
+}
+
+fn xml_attr(s: &str) -> String {
+	let mut out = String::with_capacity(s.len());
+	for c in s.chars() {
+		match c {
+			'&' => out.push_str("&amp;"),
+			'<' => out.push_str("&lt;"),
+			'>' => out.push_str("&gt;"),
+			'"' => out.push_str("&quot;"),
+			'\'' => out.push_str("&apos;"),
+			/* attribute value normalization would turn these into spaces */
+			'\n' => out.push_str("&#10;"),
+			'\r' => out.push_str("&#13;"),
+			'\t' => out.push_str("&#9;"),
+			c => out.push(c),
+		}
+	}
+	out
+}
+
+fn address(n: &Number) -> String {
+	format!("+{}", n.to_string())
+}
+
+/* write every chat in the database as a backup, from the point of view of the own number
+own_number_of gives for each. returns the number of messages written. */
+pub fn export<W: Write, F: Fn(&Chat) -> Number>(conn: &Connection, own_number_of: F, w: &mut W) -> Result<usize, BackupError> {
+	let mut histories = vec![];
+	for (chat, _tab_id, _last) in db::get_all_chats(conn)? {
+		histories.push(export::History::load(conn, &chat, own_number_of(&chat))?);
+	}
+	let count = histories.iter().map(|h| h.messages.len()).sum();
+
+	writeln!(w, "<?xml version='1.0' encoding='UTF-8' standalone='yes' ?>")?;
+	writeln!(w, "<smses count=\"{}\">", count)?;
+	for history in &histories {
+		for (_id, msg) in &history.messages {
+			write_message(history, msg, w)?;
+		}
+	}
+	writeln!(w, "</smses>")?;
+	w.flush()?;
+	Ok(count)
+}
+
+fn write_message<W: Write>(history: &export::History, msg: &MessageInfo, w: &mut W) -> Result<(), BackupError> {
+	let my_number = history.my_number;
+	let others = msg.chat.iter().filter(|n| **n != my_number).collect::<Vec<_>>();
+	let outgoing = match msg.status {
+		MessageStatus::Received => false,
+		_ => true,
+	};
+
+	/* one-to-one text messages are SMS, everything else MMS */
+	if let ([other], [MessageItem::Text(body)]) = (&*others, &*msg.contents) {
+		let kind = match msg.status {
+			MessageStatus::Received => 1,
+			MessageStatus::Sent | MessageStatus::Delivered | MessageStatus::Read => 2,
+			MessageStatus::Draft => 3,
+			MessageStatus::Sending => 4,
+			MessageStatus::Failed | MessageStatus::Unknown => 5,
+		};
+		writeln!(w, "\t<sms protocol=\"0\" address=\"{}\" date=\"{}\" type=\"{}\" subject=\"null\" body=\"{}\" \
+			toa=\"null\" sc_toa=\"null\" service_center=\"null\" read=\"1\" status=\"-1\" locked=\"0\" date_sent=\"0\" />",
+			xml_attr(&address(other)), msg.time, kind, xml_attr(body))?;
+		return Ok(())
+	}
+
+	let msg_box = match msg.status {
+		MessageStatus::Received => 1,
+		MessageStatus::Sent | MessageStatus::Delivered | MessageStatus::Read => 2,
+		MessageStatus::Draft => 3,
+		MessageStatus::Sending | MessageStatus::Failed | MessageStatus::Unknown => 4,
+	};
+	let text_only = msg.contents.iter().all(|item| match item {
+		MessageItem::Text(_) => true,
+		_ => false,
+	});
+	let addresses = others.iter().map(|n| address(n)).collect::<Vec<_>>().join("~");
+	writeln!(w, "\t<mms date=\"{}\" msg_box=\"{}\" address=\"{}\" m_type=\"{}\" read=\"1\" locked=\"0\" text_only=\"{}\" \
+		ct_t=\"application/vnd.wap.multipart.related\" sub=\"null\" m_id=\"null\" rr=\"null\" read_status=\"null\">",
+		msg.time, msg_box, xml_attr(&addresses), if outgoing { 128 } else { 132 }, text_only as u8)?;
+	writeln!(w, "\t\t<parts>")?;
+	for (seq, item) in msg.contents.iter().enumerate() {
+		match item {
+			MessageItem::Text(t) => writeln!(w, "\t\t\t<part seq=\"{}\" ct=\"text/plain\" name=\"null\" chset=\"106\" cd=\"null\" fn=\"null\" \
+				cid=\"null\" cl=\"txt{:03}.txt\" ctt_s=\"null\" ctt_t=\"null\" text=\"{}\" />", seq, seq, xml_attr(t))?,
+			MessageItem::Attachment(att_id) => {
+				let att = match history.attachments.get(att_id) {
+					Some(att) => att,
+					None => {
+						eprintln!("attachment {} not found; leaving it out of the backup", att_id);
+						continue
+					},
+				};
+				let data = match att.with_data(base64_encode) {
+					Ok(data) => data,
+					Err(e) => {
+						eprintln!("attachment {} could not be read; leaving it out of the backup: {}", att_id, e);
+						continue
+					},
+				};
+				let name = xml_attr(&att.name.to_string_lossy());
+				writeln!(w, "\t\t\t<part seq=\"{}\" ct=\"{}\" name=\"{}\" chset=\"null\" cd=\"null\" fn=\"null\" \
+					cid=\"&lt;{}&gt;\" cl=\"{}\" ctt_s=\"null\" ctt_t=\"null\" text=\"null\" data=\"{}\" />",
+					seq, xml_attr(&att.mime_type), name, name, name, data)?
+			},
+		}
+	}
+	writeln!(w, "\t\t</parts>")?;
+	writeln!(w, "\t\t<addrs>")?;
+	let from = if outgoing { my_number } else { msg.sender };
+	for n in msg.chat.iter() {
+		let kind = if *n == from { ADDR_FROM } else { ADDR_TO };
+		writeln!(w, "\t\t\t<addr address=\"{}\" type=\"{}\" charset=\"106\" />", xml_attr(&address(n)), kind)?;
+	}
+	writeln!(w, "\t\t</addrs>")?;
+	writeln!(w, "\t</mms>")?;
+	Ok(())
+}
+
+#[test]
+fn test_base64() {
+	for data in &[&b""[..], b"a", b"ab", b"abc", b"\xff\x00\x10\x80"] {
+		assert_eq!(base64_decode(&base64_encode(data)).unwrap(), *data);
+	}
+	assert_eq!(base64_encode(b"vgmms"), "dmdtbXM=");
+}
+
+#[test]
+fn test_import_roundtrip() {
+	let dir = std::env::temp_dir().join(format!("vgmms-test-backup-{}", std::process::id()));
+	let mut conn = Connection::open_in_memory().unwrap();
+	db::migrate(&mut conn).unwrap();
+	let country = Number::get_country("+12024561111").unwrap();
+	let me = Number::normalize("+12024561111", country).unwrap();
+	let backup = r#"<?xml version='1.0' encoding='UTF-8' standalone='yes' ?>
+<smses count="3">
+	<sms protocol="0" address="(202) 456-1414" date="1589921285000" type="1" body="line one&#10;&lt;two&gt;" read="1" />
+	<sms protocol="0" address="+12024561414" date="1589921286000" type="2" body="reply" read="1" />
+	<mms date="1589921299000" msg_box="1" address="+12024561414~+12024561111">
+		<parts>
+			<part seq="-1" ct="application/smil" text="&lt;smil&gt;&lt;/smil&gt;" />
+			<part seq="0" ct="text/plain" text="look" />
+			<part seq="1" ct="image/png" cl="red.png" data="dmdtbXM=" />
+		</parts>
+		<addrs>
+			<addr address="+12024561414" type="137" charset="106" />
+			<addr address="+12024561111" type="151" charset="106" />
+		</addrs>
+	</mms>
+</smses>"#;
+
+	let mut next_message_id = [0u8; 20];
+	next_message_id.increment();
+	let mut next_attachment_id = 1;
+	let importer = Importer { my_number: me, my_country: country, attachment_dir: &dir };
+	let mut import = |conn: &mut Connection, xml: &[u8]| importer.read(xml).unwrap()
+		.insert(conn, &mut next_message_id, &mut next_attachment_id).unwrap();
+	let stats = import(&mut conn, backup.as_bytes());
+	assert_eq!((stats.imported, stats.duplicates, stats.skipped), (3, 0, 0));
+	/* importing again changes nothing */
+	let stats = import(&mut conn, backup.as_bytes());
+	assert_eq!((stats.imported, stats.duplicates), (0, 3));
+
+	let other = Number::normalize("+12024561414", country).unwrap();
+	let mut numbers = vec![me, other];
+	numbers.sort();
+	let history = export::History::load(&conn, &Chat { numbers }, me).unwrap();
+	let msgs = history.messages.iter().map(|(_id, m)| m).collect::<Vec<_>>();
+	assert_eq!(msgs.len(), 3);
+	assert!(matches!(&*msgs[0].contents, [MessageItem::Text(t)] if t == "line one\n<two>"));
+	assert_eq!(msgs[1].sender, me);
+	assert!(matches!(&*msgs[2].contents, [MessageItem::Text(_), MessageItem::Attachment(_)]));
+
+	/* what we export imports into an empty database as the same messages */
+	let mut xml = vec![];
+	assert_eq!(export(&conn, |_| me, &mut xml).unwrap(), 3);
+	let mut fresh = Connection::open_in_memory().unwrap();
+	db::migrate(&mut fresh).unwrap();
+	let stats = importer.read(&xml[..]).unwrap().insert(&mut fresh, &mut [1u8; 20], &mut 1).unwrap();
+	assert_eq!(stats.imported, 3);
+	let stats = import(&mut fresh, backup.as_bytes());
+	assert_eq!((stats.imported, stats.duplicates), (0, 3));
+
+	/* a different message sent at the same moment is no duplicate */
+	let same_time = backup.replace("body=\"reply\"", "body=\"another reply\"");
+	let stats = import(&mut fresh, same_time.as_bytes());
+	let _ = std::fs::remove_dir_all(&dir);
+	assert_eq!((stats.imported, stats.duplicates), (1, 2));
+}
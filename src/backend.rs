@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use crate::types::{Attachment, AttachmentId, MessageId, MessageInfo, MessageStatus};
+
+/* a modem, named however the backend names it (e.g. by an object path) */
+pub type ModemId = String;
+
+/* a part of a received MMS, as a range of a file the backend stored it in */
+#[derive(Debug, Clone)]
+pub struct ReceivedAttachment {
+	pub name: String,
+	pub mime_type: String,
+	pub disk_path: PathBuf,
+	pub start: u64,
+	pub len: u64,
+}
+
+#[derive(Debug, Clone)]
+pub enum Notification {
+	/* the sent message the backend named path changed status */
+	StatusUpdate {
+		path: String,
+		status: MessageStatus,
+	},
+	/* the backend is done with the sent message it named path */
+	MessageRemoved {
+		path: String,
+	},
+	MmsReceived {
+		id: [u8; 20],
+		date: String,
+		subject: Option<String>,
+		sender: String,
+		recipients: Vec<String>,
+		attachments: Vec<ReceivedAttachment>,
+		smil: Option<String>,
+	},
+	SmsReceived {
+		message: String,
+		date: String,
+		sender: String,
+		/* the modem it arrived on */
+		modem: ModemId,
+		/* what to hand to taken_sms once it is saved, if the backend keeps it until then */
+		stored: Option<String>,
+	},
+	/* mmsd (re)started or added a service; messages it received meanwhile should be fetched */
+	MmsServiceAppeared,
+	/* mmsd exited or removed a service */
+	MmsServiceVanished,
+	/* a modem came online, registered with a network or activated a data context;
+	queued messages may go through now */
+	ModemAvailable,
+	/* oFono or ModemManager (re)started, or we reconnected to it; its modems should be looked up again */
+	ModemServiceAppeared,
+	/* oFono or ModemManager exited, taking its modems with it */
+	ModemServiceVanished,
+	/* a new modem was found */
+	ModemAdded {
+		modem: ModemId,
+	},
+	ModemRemoved {
+		modem: ModemId,
+	},
+	/* the SIM of a modem now needs the PIN or PUK of pin_type, or "none" once unlocked */
+	PinRequired {
+		modem: ModemId,
+		pin_type: String,
+	},
+	/* the SIM of a modem now reports a subscriber number, e.g. once read after unlocking */
+	OwnNumberChanged {
+		modem: ModemId,
+	},
+	/* not from the backend but from vgmms's send worker: what send_message returned for a
+	message of the outbox */
+	SendFinished {
+		id: MessageId,
+		result: Result<Option<String>, Error>,
+	},
+	/* not from the backend but from vgmms's backfill worker: what stored_mms returned */
+	StoredMmsFetched {
+		result: Result<Vec<StoredMms>, Error>,
+	},
+}
+
+pub type NotifStream = futures::stream::BoxStream<'static, Notification>;
+pub type NotifSink = futures::channel::mpsc::UnboundedSender<Notification>;
+
+pub fn notify(sink: &NotifSink, notif: Notification) {
+	/* the stream is only dropped when vgmms exits */
+	let _ = sink.unbounded_send(notif);
+}
+
+/* a message stored by the backend's MMS service */
+#[derive(Debug, Clone)]
+pub struct StoredMms {
+	pub path: String,
+	/* its status, if it is one a message we sent can have */
+	pub sent_status: Option<MessageStatus>,
+	/* the message as if it had just arrived, if it parses as a received one */
+	pub received: Option<Notification>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Error {
+	/* there is no MMS service for the modem an MMS is to go out on */
+	NoMmsService,
+	/* the stack failed or refused, e.g. on a wrong PIN, saying why */
+	Failed(String),
+	/* the stack refused the request as given, e.g. for an invalid number, so trying it again
+	unchanged won't help */
+	Rejected(String),
+}
+
+impl std::fmt::Display for Error {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self {
+			Error::NoMmsService => write!(f, "no MMS service for this modem"),
+			Error::Failed(why) | Error::Rejected(why) => write!(f, "{}", why),
+		}
+	}
+}
+
+/**
+  the telephony stack vgmms sends and receives messages through. status changes of sent
+  messages, incoming messages and modems coming and going arrive on the stream from start_recv.
+  messages are sent, and stored MMS fetched, from threads of their own, while the window keeps
+  calling the rest.
+*/
+pub trait MessagingBackend: Send + Sync {
+	/* subscribe to the stack's signals; called once, before anything else */
+	fn start_recv(&self) -> NotifStream;
+
+	fn modems(&self) -> Result<Vec<ModemId>, Error>;
+
+	/* the subscriber number of a modem's SIM, if it reports one */
+	fn own_number(&self, modem: &str) -> Result<Option<String>, Error>;
+
+	/* which PIN or PUK the SIM of a modem waits for ("none" if it isn't locked), and how
+	many attempts at it are left if known */
+	fn pin_required(&self, modem: &str) -> Result<(String, Option<u8>), Error>;
+
+	/* unlock a SIM with its PIN, or with its PUK if a new PIN is given */
+	fn enter_pin(&self, modem: &str, pin_type: &str, pin: &str, new_pin: Option<&str>) -> Result<(), Error>;
+
+	fn set_delivery_reports(&self, modem: &str, enabled: bool) -> Result<(), Error>;
+
+	/* hand a message to the stack to send, returning the name of what reports its status in
+	StatusUpdates if there is one */
+	fn send_message(&self, modem: &str, msg: &MessageInfo,
+		atts: &HashMap<AttachmentId, Attachment>) -> Result<Option<String>, Error>;
+
+	/* the MMS the stack has stored, for catching up on ones that arrived while we weren't
+	listening. chat_numbers are the numbers of the chats we know of. */
+	fn stored_mms(&self, chat_numbers: &[String]) -> Result<Vec<StoredMms>, Error>;
+
+	/* SMS a modem has stored rather than announced, as SmsReceived notifications. stacks
+	that hand every SMS over as it arrives have none. */
+	fn stored_sms(&self, _modem: &str) -> Result<Vec<Notification>, Error> {
+		Ok(vec![])
+	}
+
+	/* the stored SMS of an SmsReceived has been saved, so the modem can forget it */
+	fn taken_sms(&self, _modem: &str, _stored: &str) -> Result<(), Error> {
+		Ok(())
+	}
+}
+
+/* the backend named by a SETTING_BACKEND value, looking for a running stack if it is
+"auto" or unknown */
+pub fn choose(name: &str) -> Box<dyn MessagingBackend> {
+	match name {
+		"ofono" => return Box::new(crate::dbus::OfonoBackend),
+		"modemmanager" => return Box::new(crate::modemmanager::ModemManagerBackend::new(crate::dbus::Bus::System, crate::dbus::Bus::Session)),
+		"auto" => (),
+		other => eprintln!("unknown backend {:?}, choosing automatically", other),
+	}
+	let running = |name| crate::dbus::name_has_owner(name).unwrap_or_else(|e| {
+		eprintln!("error looking for {}: {}", name, e);
+		false
+	});
+	/* oFono first, as that is what vgmms was written for */
+	if !running("org.ofono") && running("org.freedesktop.ModemManager1") {
+		Box::new(crate::modemmanager::ModemManagerBackend::new(crate::dbus::Bus::System, crate::dbus::Bus::Session))
+	} else {
+		Box::new(crate::dbus::OfonoBackend)
+	}
+}
+
+/* stands in until the window is handed the backend it starts with: no modems, and nothing can
+be sent */
+pub struct NoBackend;
+
+impl MessagingBackend for NoBackend {
+	fn start_recv(&self) -> NotifStream {
+		use futures::stream::StreamExt;
+		futures::stream::empty().boxed()
+	}
+
+	fn modems(&self) -> Result<Vec<ModemId>, Error> {
+		Ok(vec![])
+	}
+
+	fn own_number(&self, _modem: &str) -> Result<Option<String>, Error> {
+		Ok(None)
+	}
+
+	fn pin_required(&self, _modem: &str) -> Result<(String, Option<u8>), Error> {
+		Ok(("none".into(), None))
+	}
+
+	fn enter_pin(&self, _modem: &str, _pin_type: &str, _pin: &str, _new_pin: Option<&str>) -> Result<(), Error> {
+		Err(Error::Failed("no backend".into()))
+	}
+
+	fn set_delivery_reports(&self, _modem: &str, _enabled: bool) -> Result<(), Error> {
+		Ok(())
+	}
+
+	fn send_message(&self, _modem: &str, _msg: &MessageInfo,
+		_atts: &HashMap<AttachmentId, Attachment>) -> Result<Option<String>, Error> {
+		Err(Error::Failed("no backend".into()))
+	}
+
+	fn stored_mms(&self, _chat_numbers: &[String]) -> Result<Vec<StoredMms>, Error> {
+		Ok(vec![])
+	}
+}
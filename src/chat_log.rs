@@ -14,15 +14,20 @@ use crate::input_box::InputBox;
 #[derive(Clone, Default)]
 pub struct ChatLog {
 	pub state: Arc<RwLock<VgmmsState>>,
-	pub on_send: Callback<(Chat, Vec<DraftItem>)>,
+	pub on_send: Callback<(Chat, Number, Vec<DraftItem>)>,
 	pub chat: Chat,
+	/* message to scroll into view instead of following the end of the log */
+	pub scroll_to: Option<MessageId>,
 }
 
 #[derive(Clone, Debug)]
 pub enum UiMessage {
-	Send(Vec<DraftItem>),
+	Send(Number, Vec<DraftItem>),
 	AskDelete(MessageId),
 	Delete(MessageId),
+	LoadOlder,
+	Retry(MessageId),
+	Cancel(MessageId),
 	Nop,
 }
 
@@ -106,22 +111,48 @@ fn image_widget<T: Component>(pixbuf: Pixbuf, halign: gtk::Align) -> VNode<T> {
 	gtk! { <Image pixbuf=Some(pixbuf) halign=halign /> }
 }
 
+/* once a row has been laid out, scroll the ScrolledWindow containing it so it is visible */
+fn scroll_into_view(row: &ListBoxRow) {
+	use glib::object::Cast;
+	let row = row.clone();
+	glib::idle_add_local(move || {
+		let mut w: Widget = row.clone().upcast();
+		while let Some(parent) = w.get_parent() {
+			if let Some(sw) = parent.downcast_ref::<ScrolledWindow>() {
+				if let Some(adj) = sw.get_vadjustment() {
+					let alloc = row.get_allocation();
+					adj.clamp_page(alloc.y as f64, (alloc.y + alloc.height) as f64);
+				}
+				break
+			}
+			w = parent;
+		}
+		glib::Continue(false)
+	});
+}
+
 impl ChatLog {
 	fn generate_log_widgets<'a>(&'a self, state: &'a VgmmsState) -> impl Iterator<Item=VNode<Self>> + 'a {
-		state.messages.iter().filter_map(move |(msg_id, msg)| {
-			if msg.chat != self.chat.numbers {
-				return None
-			}
+		state.chat_messages(&self.chat).map(move |(msg_id, msg)| {
 			let (align, halign) = match msg.status {
 				MessageStatus::Received => (0.0, gtk::Align::Start),
 				_ => (1.0, gtk::Align::End),
 			};
 			use chrono::offset::TimeZone;
-			let text = if let chrono::offset::LocalResult::Single(time) = chrono::Local.timestamp_opt(msg.time as i64, 0) {
+			let mut text = if let chrono::offset::LocalResult::Single(time) = chrono::Local.timestamp_millis_opt(msg.time as i64) {
 				format!("[{}] {}", time.format("%k:%M"), msg.sender.to_string())
 			} else {
 				format!("[@{}] {}", msg.time, msg.sender.to_string())
 			};
+			let queued = state.outbox.contains_key(msg_id);
+			match msg.status {
+				MessageStatus::Received | MessageStatus::Sent => (),
+				MessageStatus::Failed if queued => match state.send_errors.get(msg_id) {
+					Some(crate::backend::Error::NoMmsService) => text.push_str(" (no MMS service, will retry)"),
+					_ => text.push_str(" (failed, will retry)"),
+				},
+				status => text.push_str(&format!(" ({})", status.name())),
+			}
 			let name_time = gtk! { <Label label=text selectable=true line_wrap=true line_wrap_mode=pango::WrapMode::WordChar xalign=align /> };
 			let message_content = msg.contents.iter().map(move |item| {
 				match item {
@@ -175,14 +206,34 @@ impl ChatLog {
 					},
 				}
 			});
-			Some(gtk! {
-				<ListBoxRow selectable=false>
+			/* failed messages can be sent again now, or no longer retried */
+			let send_buttons = if msg.status == MessageStatus::Failed {
+				let (retry_id, cancel_id) = (*msg_id, *msg_id);
+				let cancel = if queued {
+					Some(gtk! { <Button label="_Cancel" use_underline=true on clicked=|_| UiMessage::Cancel(cancel_id) /> })
+				} else {
+					None
+				};
+				Some(gtk! {
+					<GtkBox::new(Orientation::Horizontal, 6) halign=halign>
+						<Button label="_Retry" use_underline=true on clicked=|_| UiMessage::Retry(retry_id) />
+						{cancel}
+					</GtkBox>
+				})
+			} else {
+				None
+			};
+			let is_target = self.scroll_to == Some(*msg_id);
+			gtk! {
+				<ListBoxRow selectable=false
+					on realize=|row| { if is_target { scroll_into_view(row); } UiMessage::Nop }>
 					<GtkBox::new(Orientation::Vertical, 0)>
 						{name_time}
 						{message_content}
+						{send_buttons}
 					</GtkBox>
 				</ListBoxRow>
-			})
+			}
 		})
 	}
 }
@@ -203,8 +254,8 @@ impl Component for ChatLog {
 	fn update(&mut self, msg: Self::Message) -> UpdateAction<Self> {
 		use UiMessage::*;
 		match msg {
-			Send(draft_items) => {
-				self.on_send.send((self.chat.clone(), draft_items));
+			Send(from, draft_items) => {
+				self.on_send.send((self.chat.clone(), from, draft_items));
 				UpdateAction::Render
 			}
 			AskDelete(_msg_id) => {
@@ -213,6 +264,21 @@ impl Component for ChatLog {
 			Delete(_msg_id) => {
 				UpdateAction::Render
 			},
+			LoadOlder => {
+				if self.state.write().unwrap().load_older(&self.chat) {
+					UpdateAction::Render
+				} else {
+					UpdateAction::None
+				}
+			},
+			Retry(msg_id) => {
+				self.state.write().unwrap().retry_message(&msg_id);
+				UpdateAction::Render
+			},
+			Cancel(msg_id) => {
+				self.state.write().unwrap().cancel_message(&msg_id);
+				UpdateAction::Render
+			},
 			Nop => {
 				UpdateAction::None
 			},
@@ -221,23 +287,44 @@ impl Component for ChatLog {
 
 	fn view(&self) -> VNode<ChatLog> {
 		let state = self.state.read().unwrap();
+		let identities = state.sending_identities();
+		let own_number = state.own_number_of(&self.chat);
+		/* the chat's own number, unless its modem is absent */
+		let default_from = if identities.contains(&own_number) { own_number } else { identities.first().cloned().unwrap_or(own_number) };
+		let can_send = !identities.is_empty();
 		fn keep_scrolled_to_bottom(sw: &ScrolledWindow) {
+			use std::{cell::Cell, rc::Rc};
 			if let Some(adj) = sw.get_vadjustment() {
-				adj.connect_property_upper_notify(|adj| {
-					adj.set_value(adj.get_upper());
+				let last_upper = Rc::new(Cell::new(adj.get_upper()));
+				adj.connect_property_upper_notify(move |adj| {
+					let old_upper = last_upper.replace(adj.get_upper());
+					if adj.get_value() + adj.get_page_size() >= old_upper - 1.0 {
+						/* follow new messages if we were at the bottom */
+						adj.set_value(adj.get_upper());
+					} else if adj.get_value() == 0.0 {
+						/* older messages were loaded above; keep the same ones in view */
+						adj.set_value(adj.get_upper() - old_upper);
+					}
 				});
 			}
 		}
 		gtk! {
 			<GtkBox::new(Orientation::Vertical, 0)>
-				<ScrolledWindow GtkBox::expand=true on map=|sw| { keep_scrolled_to_bottom(sw); UiMessage::Nop} >
+				<ScrolledWindow GtkBox::expand=true on map=|sw| { keep_scrolled_to_bottom(sw); UiMessage::Nop}
+					on edge_reached=|_sw, pos| if pos == PositionType::Top { UiMessage::LoadOlder } else { UiMessage::Nop } >
 					<ListBox> //TODO: TreeView
 					{self.generate_log_widgets(&*state)}
 					</ListBox>
 				</ScrolledWindow>
-				<@InputBox
-					on send=|draft| UiMessage::Send(draft)
-				/>
+				{
+					if can_send { Some(gtk! {
+						<@InputBox
+							identities=identities.clone()
+							default_from=default_from
+							on send=|(from, draft)| UiMessage::Send(from, draft)
+						/>
+					}) } else { None }
+				}
 			</GtkBox>
 		}
 	}
@@ -1,21 +1,156 @@
 use rusqlite::{params, Connection};
-use byteorder::WriteBytesExt;
+use std::collections::{BTreeMap, HashMap};
 
 use crate::types::*;
 use self::get::*;
 
-pub fn connect() -> rusqlite::Result<Connection> {
+use std::path::{Path, PathBuf};
+
+/* whether to keep data apart from the real history, as the simulator does */
+static SIMULATED: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/* keep the database and attachments in a directory of their own, for the simulator */
+pub fn use_simulator_data() {
+	SIMULATED.store(true, std::sync::atomic::Ordering::Relaxed);
+}
+
+fn data_dir() -> PathBuf {
 	let mut path = xdg_basedir::get_data_home()
 		.expect("could not find XDG data directory");
-	path.push("vgmms");
+	path.push(if SIMULATED.load(std::sync::atomic::Ordering::Relaxed) { "vgmms-simulator" } else { "vgmms" });
+	path
+}
+
+/* directory holding attachment files owned by vgmms. files elsewhere (e.g. mmsd's spool) are never deleted by us. */
+pub fn attachment_dir() -> PathBuf {
+	let mut path = data_dir();
+	path.push("attachments");
+	path
+}
+
+fn db_path() -> PathBuf {
+	let mut path = data_dir();
 	std::fs::create_dir_all(&path)
 		.expect(&format!("could not create {}", path.display()));
 	path.push("vgmms.db");
-	let conn = Connection::open(path)?;
+	path
+}
+
+pub fn connect() -> rusqlite::Result<Connection> {
+	let conn = Connection::open(db_path())?;
+	Ok(conn)
+}
+
+/* for browsing history without a modem; the database must already be migrated */
+pub fn connect_read_only() -> rusqlite::Result<Connection> {
+	let conn = Connection::open_with_flags(db_path(), rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)?;
 	Ok(conn)
 }
 
-pub fn create_tables(conn: &mut Connection) -> rusqlite::Result<usize> {
+/* schema migrations, in order. MIGRATIONS[n] upgrades a database from version n to n+1. */
+const MIGRATIONS: &[fn(&Connection) -> rusqlite::Result<()>] = &[
+	create_tables,
+	create_message_parts,
+	create_chat_members,
+	store_attachments,
+	create_message_search,
+	order_messages,
+	add_remote_paths,
+	create_settings,
+	create_outbox,
+	add_own_numbers,
+];
+
+/* the schema version this binary reads and writes */
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+#[derive(Debug)]
+pub enum MigrationError {
+	/* the database was written by a newer vgmms */
+	TooNew { found: u32, supported: u32 },
+	/* a migration step failed; the database was left at version `from` */
+	Failed { from: u32, error: rusqlite::Error },
+	Sql(rusqlite::Error),
+}
+
+impl std::fmt::Display for MigrationError {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		use MigrationError::*;
+		match self {
+			TooNew { found, supported } =>
+				write!(f, "database schema version {} is newer than supported version {}", found, supported),
+			Failed { from, error } =>
+				write!(f, "migration from schema version {} failed: {}", from, error),
+			Sql(e) => write!(f, "could not read schema version: {}", e),
+		}
+	}
+}
+
+impl From<rusqlite::Error> for MigrationError {
+	fn from(e: rusqlite::Error) -> Self {
+		MigrationError::Sql(e)
+	}
+}
+
+fn table_exists(conn: &Connection, name: &str) -> rusqlite::Result<bool> {
+	let mut stmt = conn.prepare("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1")?;
+	stmt.exists(params![name])
+}
+
+/* get the schema version of the db. databases from before schema_version existed are version 1 if they have tables at all. */
+pub fn get_schema_version(conn: &Connection) -> rusqlite::Result<u32> {
+	if table_exists(conn, "schema_version")? {
+		conn.query_row("SELECT version FROM schema_version", params![], |row| row.get::<_, u32>(0))
+	} else if table_exists(conn, "messages")? {
+		Ok(1)
+	} else {
+		Ok(0)
+	}
+}
+
+fn set_schema_version(conn: &Connection, version: u32) -> rusqlite::Result<()> {
+	conn.execute("CREATE TABLE IF NOT EXISTS schema_version (version INTEGER NOT NULL)", params![])?;
+	conn.execute("DELETE FROM schema_version", params![])?;
+	conn.execute("INSERT INTO schema_version (version) VALUES (?1)", params![version])?;
+	Ok(())
+}
+
+/* bring the db up to SCHEMA_VERSION, running each migration in its own transaction.
+returns the version the db was at before migrating. */
+pub fn migrate(conn: &mut Connection) -> Result<u32, MigrationError> {
+	let initial = get_schema_version(conn)?;
+	if initial > SCHEMA_VERSION {
+		return Err(MigrationError::TooNew { found: initial, supported: SCHEMA_VERSION })
+	}
+	/* migrations may rebuild tables, which foreign key enforcement would get in the way of.
+	this pragma has no effect inside a transaction, so it is set around the whole run. */
+	let foreign_keys: bool = conn.query_row("PRAGMA foreign_keys", params![], |row| row.get(0))?;
+	conn.execute_batch("PRAGMA foreign_keys = OFF")?;
+	let res = run_migrations(conn, initial);
+	if foreign_keys {
+		conn.execute_batch("PRAGMA foreign_keys = ON")?;
+	}
+	res.map(|()| initial)
+}
+
+fn run_migrations(conn: &mut Connection, initial: u32) -> Result<(), MigrationError> {
+	for from in initial..SCHEMA_VERSION {
+		let migration = MIGRATIONS[from as usize];
+		let res = (|| {
+			let tx = conn.transaction()?;
+			migration(&tx)?;
+			set_schema_version(&tx, from + 1)?;
+			tx.commit()
+		})();
+		if let Err(error) = res {
+			return Err(MigrationError::Failed { from, error })
+		}
+	}
+	Ok(())
+}
+
+/* version 1: the original schema */
+fn create_tables(conn: &Connection) -> rusqlite::Result<()> {
 	conn.execute(
 		"CREATE TABLE chats (
 			numbers BLOB PRIMARY KEY,
@@ -40,7 +175,8 @@ pub fn create_tables(conn: &mut Connection) -> rusqlite::Result<usize> {
 			path BLOB,
 			start INTEGER,
 			len INTEGER
-		)", params![])
+		)", params![])?;
+	Ok(())
 /* red.png:
 89504e470d0a1a0a0000000d4948445200000064
 0000006401030000004a2c071700000003504c54
@@ -62,101 +198,707 @@ pub fn create_tables(conn: &mut Connection) -> rusqlite::Result<usize> {
 		;", params![])*/
 }
 
-fn chat_to_bytes(chat: &[Number]) -> &[u8] {
-	unsafe {
-		std::slice::from_raw_parts(
-			chat.as_ptr() as *const _,
-			chat.len() * std::mem::size_of::<Number>())
+/* version 2: message contents move from a NUL-delimited blob in messages.contents to message_parts */
+fn create_message_parts(conn: &Connection) -> rusqlite::Result<()> {
+	conn.execute(
+		"CREATE TABLE message_parts (
+			message_id BLOB NOT NULL,
+			idx INTEGER NOT NULL,
+			kind TEXT NOT NULL,
+			text TEXT,
+			attachment_id INTEGER,
+			PRIMARY KEY(message_id, idx),
+			FOREIGN KEY(message_id) REFERENCES messages(id),
+			FOREIGN KEY(attachment_id) REFERENCES attachments(id)
+		)", params![])?;
+	{
+		let mut select = conn.prepare("SELECT id, contents FROM messages")?;
+		let mut insert = conn.prepare("INSERT INTO message_parts (message_id, idx, kind, text, attachment_id) \
+			VALUES (?1, ?2, ?3, ?4, ?5)")?;
+		let mut rows = select.query(params![])?;
+		while let Some(row) = rows.next()? {
+			let id: Vec<u8> = row.get(0)?;
+			let contents = row.get::<_, Option<Vec<u8>>>(1)?.unwrap_or_default();
+			let items = legacy::decode_contents(&contents)
+				.map_err(|e| rusqlite::Error::FromSqlConversionFailure(1, rusqlite::types::Type::Blob,
+					format!("message {}: {}", hex::encode(&id), e).into()))?;
+			for (idx, item) in items.into_iter().enumerate() {
+				match item {
+					MessageItem::Text(t) =>
+						insert.execute(params![id, idx as i64, PART_TEXT, t, None::<i64>])?,
+					MessageItem::Attachment(att_id) =>
+						insert.execute(params![id, idx as i64, PART_ATTACHMENT, None::<String>, att_id as i64])?,
+				};
+			}
+		}
+	}
+	/* sqlite cannot drop columns, so rebuild messages without contents */
+	conn.execute_batch(
+		"CREATE TABLE messages_new (
+			id BLOB PRIMARY KEY,
+			sender INTEGER,
+			chat BLOB,
+			time INTEGER,
+			status INTEGER,
+			FOREIGN KEY(chat) REFERENCES chats(numbers)
+		);
+		INSERT INTO messages_new (id, sender, chat, time, status)
+			SELECT id, sender, chat, time, status FROM messages;
+		DROP TABLE messages;
+		ALTER TABLE messages_new RENAME TO messages;")
+}
+
+/* version 3: chats get an integer id and their members move from a native-endian blob key to chat_members */
+fn create_chat_members(conn: &Connection) -> rusqlite::Result<()> {
+	conn.execute_batch(
+		"CREATE TABLE chats_new (
+			id INTEGER PRIMARY KEY,
+			tab_id INTEGER,
+			last_msg_id BLOB
+		);
+		CREATE TABLE chat_members (
+			chat_id INTEGER NOT NULL,
+			number INTEGER NOT NULL,
+			PRIMARY KEY(chat_id, number),
+			FOREIGN KEY(chat_id) REFERENCES chats(id)
+		);
+		CREATE INDEX chat_members_number ON chat_members(number);
+		CREATE TABLE messages_new (
+			id BLOB PRIMARY KEY,
+			sender INTEGER,
+			chat INTEGER,
+			time INTEGER,
+			status INTEGER,
+			FOREIGN KEY(chat) REFERENCES chats(id)
+		);")?;
+
+	let mut chat_ids = HashMap::<Vec<u8>, i64>::new();
+	{
+		let mut insert_chat = conn.prepare("INSERT INTO chats_new (tab_id, last_msg_id) VALUES (?1, ?2)")?;
+		let mut insert_member = conn.prepare("INSERT OR IGNORE INTO chat_members (chat_id, number) VALUES (?1, ?2)")?;
+		let mut add_chat = |key: Vec<u8>, tab_id: Option<i64>, last_msg_id: Option<Vec<u8>>| -> rusqlite::Result<()> {
+			let numbers = legacy::decode_numbers(&key)
+				.map_err(|e| rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Blob, e.into()))?;
+			insert_chat.execute(params![tab_id, last_msg_id])?;
+			let id = conn.last_insert_rowid();
+			for n in numbers {
+				insert_member.execute(params![id, n.num as i64])?;
+			}
+			chat_ids.insert(key, id);
+			Ok(())
+		};
+
+		let mut select = conn.prepare("SELECT numbers, tab_id, last_msg_id FROM chats")?;
+		let mut rows = select.query(params![])?;
+		while let Some(row) = rows.next()? {
+			add_chat(row.get(0)?, row.get(1)?, row.get(2)?)?;
+		}
+		/* messages should never refer to a chat missing from the chats table, but keep them if they do */
+		let mut select = conn.prepare("SELECT DISTINCT chat FROM messages \
+			WHERE chat NOT IN (SELECT numbers FROM chats)")?;
+		let mut rows = select.query(params![])?;
+		while let Some(row) = rows.next()? {
+			add_chat(row.get(0)?, None, None)?;
+		}
+	}
+	{
+		let mut select = conn.prepare("SELECT id, sender, chat, time, status FROM messages")?;
+		let mut insert = conn.prepare("INSERT INTO messages_new (id, sender, chat, time, status) \
+			VALUES (?1, ?2, ?3, ?4, ?5)")?;
+		let mut rows = select.query(params![])?;
+		while let Some(row) = rows.next()? {
+			let chat: Vec<u8> = row.get(2)?;
+			insert.execute(params![row.get::<_, Vec<u8>>(0)?, row.get::<_, Option<i64>>(1)?,
+				chat_ids[&chat], row.get::<_, Option<i64>>(3)?, row.get::<_, Option<i64>>(4)?])?;
+		}
+	}
+	conn.execute_batch(
+		"DROP TABLE messages;
+		ALTER TABLE messages_new RENAME TO messages;
+		DROP TABLE chats;
+		ALTER TABLE chats_new RENAME TO chats;")
+}
+
+/* version 4: copy attachment data into attachment_dir() so it no longer depends on mmsd's spool
+or the user's files. rows whose files are gone are left as they are. */
+fn store_attachments(conn: &Connection) -> rusqlite::Result<()> {
+	let dir = attachment_dir();
+	let mut select = conn.prepare("SELECT id, path, start, len FROM attachments WHERE path IS NOT NULL")?;
+	let mut update = conn.prepare("UPDATE attachments SET path = ?2, start = 0 WHERE id = ?1")?;
+	let mut rows = select.query(params![])?;
+	while let Some(row) = rows.next()? {
+		let id: i64 = row.get(0)?;
+		let path = get_path(row, 1)?;
+		if path.starts_with(&dir) || !path.exists() {
+			continue
+		}
+		let (start, len) = (get_u64(row, 2)?, get_u64(row, 3)?);
+		match crate::store::import(&dir, &path, start, len) {
+			Ok(stored) => {
+				use std::os::unix::ffi::OsStrExt;
+				update.execute(params![id, stored.as_os_str().as_bytes()])?;
+			},
+			Err(e) => eprintln!("could not store attachment {} from {}: {}", id, path.display(), e),
+		}
 	}
+	Ok(())
+}
+
+/* version 5: full-text index over the text of each message */
+fn create_message_search(conn: &Connection) -> rusqlite::Result<()> {
+	conn.execute_batch(
+		"CREATE VIRTUAL TABLE message_search USING fts5(text, message_id UNINDEXED);
+		INSERT INTO message_search (text, message_id)
+			SELECT group_concat(text, '
+'), message_id FROM
+				(SELECT text, message_id FROM message_parts WHERE kind = 't' ORDER BY message_id, idx)
+			GROUP BY message_id;")
 }
 
-unsafe fn bytes_to_chat(data: &[u8]) -> &[Number] {
-	std::slice::from_raw_parts(
-		data.as_ptr() as *const _,
-		data.len() / std::mem::size_of::<Number>())
+/* version 6: times are in milliseconds, and seq records the order messages were saved in.
+existing messages get their rowid, which is insertion order since messages was last rebuilt. */
+fn order_messages(conn: &Connection) -> rusqlite::Result<()> {
+	conn.execute_batch(
+		"ALTER TABLE messages ADD COLUMN seq INTEGER NOT NULL DEFAULT 0;
+		UPDATE messages SET time = time * 1000, seq = rowid;
+		CREATE INDEX messages_chat_order ON messages(chat, time, seq);
+		CREATE INDEX messages_seq ON messages(seq);")
 }
 
-/* delete a chat and all associated messages from the db (but leaks their attachments).
-must call close_chat first! fails if the chat does not exist. */
-pub fn delete_chat(conn: &mut Connection, chat: &Chat) -> rusqlite::Result<usize> {
+/* version 7: the D-Bus object (e.g. of mmsd) a sent message is tracked through */
+fn add_remote_paths(conn: &Connection) -> rusqlite::Result<()> {
+	conn.execute_batch(
+		"ALTER TABLE messages ADD COLUMN remote_path TEXT;
+		CREATE INDEX messages_remote_path ON messages(remote_path);")
+}
+
+/* version 8: user settings */
+fn create_settings(conn: &Connection) -> rusqlite::Result<()> {
 	conn.execute(
-		"DELETE FROM chats where numbers = ?1;",
-		params![chat_to_bytes(&*chat.numbers)],
-	)
+		"CREATE TABLE settings (
+			key TEXT PRIMARY KEY,
+			value TEXT NOT NULL
+		)", params![])?;
+	Ok(())
+}
+
+/* version 9: messages not yet accepted by oFono or mmsd */
+fn create_outbox(conn: &Connection) -> rusqlite::Result<()> {
+	conn.execute(
+		"CREATE TABLE outbox (
+			message_id BLOB PRIMARY KEY,
+			attempts INTEGER NOT NULL,
+			next_attempt INTEGER NOT NULL,
+			FOREIGN KEY(message_id) REFERENCES messages(id)
+		)", params![])?;
+	Ok(())
+}
+
+/* version 10: which of our own numbers a chat or message belongs to, for when there are several */
+fn add_own_numbers(conn: &Connection) -> rusqlite::Result<()> {
+	conn.execute_batch(
+		"ALTER TABLE chats ADD COLUMN own_number INTEGER;
+		ALTER TABLE messages ADD COLUMN own_number INTEGER;")
+}
+
+/* keys of the settings table */
+pub const SETTING_DELIVERY_REPORTS: &str = "delivery_reports";
+/* which telephony stack to use: "ofono", "modemmanager" or "auto" (the default) */
+pub const SETTING_BACKEND: &str = "backend";
+/* the own number and home country (e.g. "US") the user entered, for SIMs that don't report a number */
+pub const SETTING_OWN_NUMBER: &str = "own_number";
+pub const SETTING_HOME_COUNTRY: &str = "home_country";
+/* the subscriber number of the last modem we used, for when none is present.
+per-modem, under modem_setting(SETTING_MY_NUMBER, path), for modems that don't report it. */
+pub const SETTING_MY_NUMBER: &str = "my_number";
+
+/* key of a setting that is kept separately for each modem */
+pub fn modem_setting(key: &str, modem_path: &str) -> String {
+	format!("{}:{}", key, modem_path)
+}
+
+pub fn get_setting(conn: &Connection, key: &str) -> rusqlite::Result<Option<String>> {
+	let mut stmt = conn.prepare_cached("SELECT value FROM settings WHERE key = ?1")?;
+	let mut rows = stmt.query(params![key])?;
+	match rows.next()? {
+		Some(row) => Ok(Some(row.get(0)?)),
+		None => Ok(None),
+	}
+}
+
+pub fn set_setting(conn: &Connection, key: &str, value: &str) -> rusqlite::Result<()> {
+	conn.execute("INSERT OR REPLACE INTO settings (key, value) VALUES (?1, ?2);", params![key, value])?;
+	Ok(())
+}
+
+/* values of message_parts.kind */
+const PART_TEXT: &str = "t";
+const PART_ATTACHMENT: &str = "a";
+
+mod legacy {
+	use byteorder::ByteOrder;
+
+	use crate::types::*;
+
+	/* decode the pre-message_parts contents blob:
+		't' [^\0]* '\0'
+		'a' .{8}
+	*/
+	pub fn decode_contents(data: &[u8]) -> Result<Vec<MessageItem>, String> {
+		let mut contents = vec![];
+		let mut rest = data;
+		while let Some((&kind, tail)) = rest.split_first() {
+			match kind {
+				b't' => {
+					let end = tail.iter().position(|&b| b == 0)
+						.ok_or("unterminated text item")?;
+					let s = std::str::from_utf8(&tail[..end])
+						.map_err(|e| e.to_string())?;
+					contents.push(MessageItem::Text(s.to_owned()));
+					rest = &tail[end+1..];
+				},
+				b'a' => {
+					let len = std::mem::size_of::<u64>();
+					if tail.len() < len {
+						return Err("truncated attachment item".into())
+					}
+					let att_id = byteorder::LittleEndian::read_u64(&tail[..len]);
+					contents.push(MessageItem::Attachment(att_id));
+					rest = &tail[len..];
+				},
+				x => return Err(format!("invalid item kind {:#x}", x)),
+			}
+		}
+		Ok(contents)
+	}
+
+	/* decode a pre-chat_members chat key, which was the in-memory representation of its
+	numbers. this is only right on a host with the endianness of the one that wrote it. */
+	pub fn decode_numbers(data: &[u8]) -> Result<Vec<Number>, String> {
+		use std::convert::TryInto;
+		let len = std::mem::size_of::<u64>();
+		if data.len() % len != 0 {
+			return Err(format!("chat key of invalid length {}", data.len()))
+		}
+		let mut numbers: Vec<_> = data.chunks(len)
+			.map(|n| Number::new(u64::from_ne_bytes(n.try_into().unwrap())))
+			.collect();
+		numbers.sort();
+		Ok(numbers)
+	}
+}
+
+/* find the id of the chat with exactly the given members */
+pub fn find_chat(conn: &Connection, numbers: &[Number]) -> rusqlite::Result<Option<i64>> {
+	let first = match numbers.first() {
+		Some(n) => n,
+		None => return Ok(None),
+	};
+	let mut stmt = conn.prepare_cached("SELECT chat_id, number FROM chat_members \
+		WHERE chat_id IN (SELECT chat_id FROM chat_members WHERE number = ?1) \
+		ORDER BY chat_id, number")?;
+	let mut candidates = BTreeMap::<i64, Vec<Number>>::new();
+	let mut rows = stmt.query(params![first.num as i64])?;
+	while let Some(row) = rows.next()? {
+		candidates.entry(row.get(0)?).or_default().push(get_number(row, 1)?);
+	}
+	let mut sorted = numbers.to_vec();
+	sorted.sort();
+	Ok(candidates.into_iter().find(|(_id, members)| members == &sorted).map(|(id, _)| id))
+}
+
+/* set the status of a message. returns whether the message exists. */
+pub fn update_message_status(conn: &Connection, id: &MessageId, status: MessageStatus) -> rusqlite::Result<bool> {
+	let n = conn.execute("UPDATE messages SET status = ?1 WHERE id = ?2;", params![status as u8, &id[..]])?;
+	Ok(n > 0)
+}
+
+/* mark messages that were still being sent when we last stopped as Unknown. returns how many there were.
+messages in the outbox were never handed over, so they are left to be sent again. */
+pub fn mark_interrupted_sends(conn: &Connection) -> rusqlite::Result<usize> {
+	conn.execute("UPDATE messages SET status = ?1 WHERE status = ?2 AND id NOT IN (SELECT message_id FROM outbox);",
+		params![MessageStatus::Unknown as u8, MessageStatus::Sending as u8])
+}
+
+/* add a message to the outbox, or update its entry */
+pub fn queue_message(conn: &Connection, id: &MessageId, entry: &OutboxEntry) -> rusqlite::Result<()> {
+	conn.execute("INSERT OR REPLACE INTO outbox (message_id, attempts, next_attempt) VALUES (?1, ?2, ?3);",
+		params![&id[..], entry.attempts, entry.next_attempt as i64])?;
+	Ok(())
+}
+
+pub fn dequeue_message(conn: &Connection, id: &MessageId) -> rusqlite::Result<()> {
+	conn.execute("DELETE FROM outbox WHERE message_id = ?1;", params![&id[..]])?;
+	Ok(())
+}
+
+pub fn get_outbox(conn: &Connection) -> rusqlite::Result<BTreeMap<MessageId, OutboxEntry>> {
+	let mut stmt = conn.prepare("SELECT message_id, attempts, next_attempt FROM outbox")?;
+	let entries = stmt.query_map(params![], |row| {
+		Ok((get_id(row, 0)?, OutboxEntry { attempts: row.get(1)?, next_attempt: get_u64(row, 2)? }))
+	})?;
+	entries.collect()
+}
+
+/* link a message to the D-Bus object tracking it, or unlink it with None */
+pub fn set_remote_path(conn: &Connection, id: &MessageId, path: Option<&str>) -> rusqlite::Result<()> {
+	conn.execute("UPDATE messages SET remote_path = ?1 WHERE id = ?2;", params![path, &id[..]])?;
+	Ok(())
+}
+
+pub fn find_message_by_remote_path(conn: &Connection, path: &str) -> rusqlite::Result<Option<MessageId>> {
+	let mut stmt = conn.prepare_cached("SELECT id FROM messages WHERE remote_path = ?1")?;
+	let mut rows = stmt.query(params![path])?;
+	match rows.next()? {
+		Some(row) => Ok(Some(get_id(row, 0)?)),
+		None => Ok(None),
+	}
+}
+
+pub fn has_message(conn: &Connection, id: &MessageId) -> rusqlite::Result<bool> {
+	let mut stmt = conn.prepare_cached("SELECT 1 FROM messages WHERE id = ?1")?;
+	stmt.exists(params![&id[..]])
+}
+
+/* whether a message from sender at time, whose text parts joined by newlines are text, is
+already in the chat with the given members */
+pub fn message_exists(conn: &Connection, numbers: &[Number], sender: Number, time: u64, text: &str) -> rusqlite::Result<bool> {
+	let chat_id = match find_chat(conn, numbers)? {
+		Some(id) => id,
+		None => return Ok(false),
+	};
+	let mut stmt = conn.prepare_cached("SELECT 1 FROM messages WHERE chat = ?1 AND sender = ?2 AND time = ?3 \
+		AND coalesce((SELECT group_concat(text, char(10)) FROM \
+			(SELECT text FROM message_parts WHERE message_id = messages.id AND kind = 't' ORDER BY idx)), '') = ?4")?;
+	stmt.exists(params![chat_id, sender.num as i64, time as i64, text])
+}
+
+/* find the id of the chat with exactly the given members, failing if there is none */
+fn chat_id(conn: &Connection, numbers: &[Number]) -> rusqlite::Result<i64> {
+	find_chat(conn, numbers)?.ok_or(rusqlite::Error::QueryReturnedNoRows)
+}
+
+/* delete a chat and all associated messages from the db, along with any attachments no other message uses.
+must call close_chat first! fails if the chat does not exist. returns the ids of deleted attachments. */
+pub fn delete_chat(conn: &mut Connection, chat: &Chat) -> rusqlite::Result<Vec<AttachmentId>> {
+	let tx = conn.transaction()?;
+	let id = chat_id(&tx, &chat.numbers)?;
+	let candidates = referenced_attachments(&tx,
+		"SELECT attachment_id FROM message_parts WHERE attachment_id IS NOT NULL \
+			AND message_id IN (SELECT id FROM messages WHERE chat = ?1)",
+		params![id])?;
+	tx.execute(
+		"DELETE FROM message_search WHERE message_id IN (SELECT id FROM messages WHERE chat = ?1);",
+		params![id],
+	)?;
+	tx.execute(
+		"DELETE FROM message_parts WHERE message_id IN (SELECT id FROM messages WHERE chat = ?1);",
+		params![id],
+	)?;
+	tx.execute(
+		"DELETE FROM outbox WHERE message_id IN (SELECT id FROM messages WHERE chat = ?1);",
+		params![id],
+	)?;
+	tx.execute(
+		"DELETE FROM messages where chat = ?1;",
+		params![id],
+	)?;
+	tx.execute(
+		"DELETE FROM chat_members where chat_id = ?1;",
+		params![id],
+	)?;
+	tx.execute(
+		"DELETE FROM chats where id = ?1;",
+		params![id],
+	)?;
+	let removed = delete_unreferenced_attachments(&tx, &candidates)?;
+	tx.commit()?;
+	Ok(remove_owned_files(conn, removed))
 }
 
 /* insert a chat into the db. fails if the chat already exists. */
 pub fn insert_chat(conn: &mut Connection, chat: &Chat, tab_id: i32, last_msg_id: Option<&MessageId>) -> rusqlite::Result<()> {
-	let chat_bytes = chat_to_bytes(&*chat.numbers);
 	let last_msg_id = last_msg_id.unwrap_or(&[0u8; 20]);
 	let tab_id = if tab_id < 0 { None } else { Some(tab_id) };
 
-	let tx = conn.transaction()?;
+	/* a savepoint, so this can be part of a bigger transaction such as an import */
+	let tx = conn.savepoint()?;
+	if find_chat(&tx, &chat.numbers)?.is_some() {
+		return Err(rusqlite::Error::SqliteFailure(
+			rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_CONSTRAINT),
+			Some("chat already exists".into())))
+	}
 	tx.execute(
 		"UPDATE chats SET tab_id = tab_id + 1 WHERE tab_id >= ?1;",
 		params![tab_id],
 	)?;
 	tx.execute(
-		"INSERT INTO chats (numbers, tab_id, last_msg_id) VALUES (?1, ?2, ?3);",
-		params![chat_bytes, tab_id, &last_msg_id[..]],
+		"INSERT INTO chats (tab_id, last_msg_id) VALUES (?1, ?2);",
+		params![tab_id, &last_msg_id[..]],
 	)?;
+	let id = tx.last_insert_rowid();
+	for n in &chat.numbers {
+		tx.execute(
+			"INSERT INTO chat_members (chat_id, number) VALUES (?1, ?2);",
+			params![id, n.num as i64],
+		)?;
+	}
 	tx.commit()
 }
 
 /* set the open tab of an existing chat */
 pub fn set_chat_tab(conn: &mut Connection, chat: &Chat, tab_id: i32) -> rusqlite::Result<usize> {
+	let id = chat_id(conn, &chat.numbers)?;
 	conn.execute(
-		"UPDATE chats SET tab_id = CASE WHEN numbers = ?2 THEN ?1 ELSE tab_id + 1 END WHERE tab_id >= ?1 or numbers = ?2;",
-		params![tab_id, chat_to_bytes(&*chat.numbers)],
+		"UPDATE chats SET tab_id = CASE WHEN id = ?2 THEN ?1 ELSE tab_id + 1 END WHERE tab_id >= ?1 or id = ?2;",
+		params![tab_id, id],
 	)
 }
 
 /* close an existing chat */
 pub fn close_chat(conn: &mut Connection, chat: &Chat) -> rusqlite::Result<usize> {
+	let id = chat_id(conn, &chat.numbers)?;
 	conn.execute(
-		"UPDATE chats SET tab_id = CASE WHEN numbers = ?1 THEN NULL ELSE tab_id - 1 END WHERE tab_id >= (SELECT tab_id FROM chats WHERE numbers = ?1);",
-		params![chat_to_bytes(&*chat.numbers)],
+		"UPDATE chats SET tab_id = CASE WHEN id = ?1 THEN NULL ELSE tab_id - 1 END WHERE tab_id >= (SELECT tab_id FROM chats WHERE id = ?1);",
+		params![id],
 	)
 }
 
-/* delete a single message from the db. leaks any attachments the message might have. */
-pub fn delete_message(conn: &mut Connection, id: &MessageId) -> rusqlite::Result<usize> {
-	conn.execute(
+
+/* delete a single message from the db, along with any of its attachments no other message uses.
+returns the ids of deleted attachments. */
+pub fn delete_message(conn: &mut Connection, id: &MessageId) -> rusqlite::Result<Vec<AttachmentId>> {
+	let tx = conn.transaction()?;
+	let candidates = referenced_attachments(&tx,
+		"SELECT attachment_id FROM message_parts WHERE attachment_id IS NOT NULL AND message_id = ?1",
+		params![&id[..]])?;
+	tx.execute(
+		"DELETE FROM message_search where message_id = ?1;",
+		params![&id[..]],
+	)?;
+	tx.execute(
+		"DELETE FROM message_parts where message_id = ?1;",
+		params![&id[..]],
+	)?;
+	tx.execute(
+		"DELETE FROM outbox where message_id = ?1;",
+		params![&id[..]],
+	)?;
+	tx.execute(
 		"DELETE FROM messages where id = ?1;",
 		params![&id[..]],
-	)
+	)?;
+	let removed = delete_unreferenced_attachments(&tx, &candidates)?;
+	tx.commit()?;
+	Ok(remove_owned_files(conn, removed))
+}
+
+fn referenced_attachments(conn: &Connection, query: &str, params: &[&dyn rusqlite::ToSql]) -> rusqlite::Result<Vec<AttachmentId>> {
+	let mut stmt = conn.prepare(query)?;
+	let ids = stmt.query_map(params, |row| get_u64(row, 0))?;
+	ids.collect()
 }
 
-/* insert a message to the db. the message's chat must already be present in the db. */
-pub fn insert_message(conn: &mut Connection, id: &MessageId, msg: &MessageInfo) -> rusqlite::Result<()> {
-	let chat_bytes: &[u8] = chat_to_bytes(&*msg.chat);
+/* delete those of the given attachment rows that no message part refers to, returning their ids and paths */
+fn delete_unreferenced_attachments(conn: &Connection, ids: &[AttachmentId]) -> rusqlite::Result<Vec<(AttachmentId, PathBuf)>> {
+	let mut removed = vec![];
+	for id in ids {
+		let referenced = conn.prepare_cached("SELECT 1 FROM message_parts WHERE attachment_id = ?1")?
+			.exists(params![*id as i64])?;
+		if referenced {
+			continue
+		}
+		let path = conn.query_row("SELECT path FROM attachments WHERE id = ?1",
+			params![*id as i64], |row| get_path(row, 0));
+		let path = match path {
+			Ok(path) => path,
+			Err(rusqlite::Error::QueryReturnedNoRows) => continue,
+			Err(e) => return Err(e),
+		};
+		conn.execute("DELETE FROM attachments WHERE id = ?1", params![*id as i64])?;
+		removed.push((*id, path));
+	}
+	Ok(removed)
+}
 
-	let mut contents_bytes = vec![];
-	for m in &msg.contents {
-		use std::io::Write;
-		match m {
-			MessageItem::Text(t) => {
-				contents_bytes.push(b't');
-				let _ = contents_bytes.write_all(t.as_bytes());
-				contents_bytes.push(0);
+/* unlink the files of deleted attachments if vgmms owns them and no remaining attachment uses them.
+returns the ids of the deleted attachments. */
+fn remove_owned_files(conn: &Connection, removed: Vec<(AttachmentId, PathBuf)>) -> Vec<AttachmentId> {
+	let dir = attachment_dir();
+	removed.into_iter().map(|(id, path)| {
+		if path.starts_with(&dir) {
+			use std::os::unix::ffi::OsStrExt;
+			let in_use = conn.prepare_cached("SELECT 1 FROM attachments WHERE path = ?1")
+				.and_then(|mut stmt| stmt.exists(params![path.as_os_str().as_bytes()]));
+			match in_use {
+				Ok(false) => if let Err(e) = std::fs::remove_file(&path) {
+					eprintln!("error removing attachment file {}: {}", path.display(), e);
+				},
+				Ok(true) => (),
+				Err(e) => eprintln!("error checking use of attachment file {}: {}", path.display(), e),
 			}
-			MessageItem::Attachment(att_id) => {
-				contents_bytes.push(b'a');
-				let _ = contents_bytes.write_u64::<byteorder::LittleEndian>(*att_id);
+		}
+		id
+	}).collect()
+}
+
+/* counts of things removed by collect_garbage */
+#[derive(Debug, Default)]
+pub struct GcStats {
+	pub rows: usize,
+	pub files: usize,
+}
+
+/* remove attachment rows no message refers to, and files in dir (normally attachment_dir()) no attachment refers to.
+these are left behind by versions of vgmms that leaked attachments when deleting messages. */
+pub fn collect_garbage(conn: &mut Connection, dir: &Path) -> rusqlite::Result<GcStats> {
+	let mut stats = GcStats::default();
+
+	let tx = conn.transaction()?;
+	let orphans = referenced_attachments(&tx,
+		"SELECT id FROM attachments WHERE id NOT IN \
+			(SELECT attachment_id FROM message_parts WHERE attachment_id IS NOT NULL)",
+		params![])?;
+	stats.rows = delete_unreferenced_attachments(&tx, &orphans)?.len();
+	tx.commit()?;
+
+	let mut in_use = std::collections::HashSet::new();
+	{
+		let mut stmt = conn.prepare("SELECT path FROM attachments")?;
+		let paths = stmt.query_map(params![], |row| get_path(row, 0))?;
+		for path in paths {
+			in_use.insert(path?);
+		}
+	}
+	stats.files = remove_unused_files(dir, &in_use);
+	Ok(stats)
+}
+
+/* how long a file is left alone after it was last written: a running vgmms stores attachments
+(through .tmp- files) before the messages that refer to them are saved */
+const GC_GRACE: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+fn remove_unused_files(dir: &Path, in_use: &std::collections::HashSet<PathBuf>) -> usize {
+	let entries = match std::fs::read_dir(dir) {
+		Ok(entries) => entries,
+		Err(e) => {
+			if e.kind() != std::io::ErrorKind::NotFound {
+				eprintln!("error reading {}: {}", dir.display(), e);
 			}
+			return 0
+		},
+	};
+	let mut removed = 0;
+	for entry in entries.filter_map(Result::ok) {
+		let path = entry.path();
+		let recent = entry.metadata().and_then(|m| m.modified())
+			.map(|modified| modified.elapsed().map_or(true, |age| age < GC_GRACE))
+			.unwrap_or(true);
+		let temporary = entry.file_name().to_string_lossy().starts_with(".tmp-");
+		match entry.file_type() {
+			Ok(t) if t.is_dir() => removed += remove_unused_files(&path, in_use),
+			Ok(_) if recent || temporary => (),
+			Ok(_) if !in_use.contains(&path) => match std::fs::remove_file(&path) {
+				Ok(()) => removed += 1,
+				Err(e) => eprintln!("error removing {}: {}", path.display(), e),
+			},
+			_ => (),
 		}
 	}
+	removed
+}
 
-	let tx = conn.transaction()?;
+/* insert a message to the db. the message's chat must already be present in the db.
+returns the message's seq, which orders it after every message saved before it. */
+pub fn insert_message(conn: &mut Connection, id: &MessageId, msg: &MessageInfo) -> rusqlite::Result<u64> {
+	let tx = conn.savepoint()?;
+	let chat_id = chat_id(&tx, &msg.chat)?;
+	let seq = tx.query_row("SELECT coalesce(max(seq), 0) + 1 FROM messages", params![], |row| get_u64(row, 0))?;
 	tx.execute(
-		"INSERT INTO messages (id, sender, chat, time, contents, status) VALUES (?1, ?2, ?3, ?4, ?5, ?6);",
-		params![&id[..], msg.sender.num as i64, chat_bytes, msg.time as i64, contents_bytes, msg.status as u8],
+		"INSERT INTO messages (id, sender, chat, time, status, seq, own_number) \
+			VALUES (?1, ?2, ?3, ?4, ?5, ?6, (SELECT own_number FROM chats WHERE id = ?3));",
+		params![&id[..], msg.sender.num as i64, chat_id, msg.time as i64, msg.status as u8, seq as i64],
 	)?;
+	{
+		let mut insert = tx.prepare("INSERT INTO message_parts (message_id, idx, kind, text, attachment_id) \
+			VALUES (?1, ?2, ?3, ?4, ?5);")?;
+		for (idx, item) in msg.contents.iter().enumerate() {
+			match item {
+				MessageItem::Text(t) =>
+					insert.execute(params![&id[..], idx as i64, PART_TEXT, t, None::<i64>])?,
+				MessageItem::Attachment(att_id) =>
+					insert.execute(params![&id[..], idx as i64, PART_ATTACHMENT, None::<String>, *att_id as i64])?,
+			};
+		}
+	}
+	let text: Vec<&str> = msg.contents.iter().filter_map(|item| match item {
+		MessageItem::Text(t) => Some(&**t),
+		_ => None,
+	}).collect();
+	if !text.is_empty() {
+		tx.execute(
+			"INSERT INTO message_search (text, message_id) VALUES (?1, ?2);",
+			params![text.join("\n"), &id[..]],
+		)?;
+	}
 	tx.execute(
-		"UPDATE chats SET last_msg_id = ?1 where numbers = ?2;",
-		params![&id[..], chat_bytes],
+		"UPDATE chats SET last_msg_id = ?1 where id = ?2;",
+		params![&id[..], chat_id],
 	)?;
-	tx.commit()
+	tx.commit()?;
+	Ok(seq)
+}
+
+#[derive(Clone, Debug)]
+pub struct SearchHit {
+	pub id: MessageId,
+	pub chat: Vec<Number>,
+	pub time: u64,
+	/* the matching part of the message's text */
+	pub snippet: String,
+}
+
+/* turn user input into an fts5 query matching messages containing every word, the last as a prefix */
+fn fts_query(query: &str) -> String {
+	let words: Vec<_> = query.split_whitespace()
+		.map(|w| format!("\"{}\"", w.replace('"', "\"\"")))
+		.collect();
+	match words.split_last() {
+		Some((last, rest)) if !rest.is_empty() => format!("{} {}*", rest.join(" "), last),
+		Some((last, _)) => format!("{}*", last),
+		None => String::new(),
+	}
+}
+
+/* find messages whose text matches query, newest first, optionally only within one chat */
+pub fn search(conn: &Connection, query: &str, chat_filter: Option<&Chat>, limit: u32) -> rusqlite::Result<Vec<SearchHit>> {
+	let query = fts_query(query);
+	if query.is_empty() {
+		return Ok(vec![])
+	}
+	let chat_filter = match chat_filter {
+		Some(chat) => match find_chat(conn, &chat.numbers)? {
+			Some(id) => Some(id),
+			None => return Ok(vec![]),
+		},
+		None => None,
+	};
+	let members = get_chat_members(&mut conn.prepare(CHAT_MEMBERS_QUERY)?)?;
+	let mut stmt = conn.prepare("SELECT messages.id, messages.chat, messages.time, \
+			snippet(message_search, 0, '', '', '…', 8) FROM message_search \
+		JOIN messages ON messages.id = message_search.message_id \
+		WHERE message_search MATCH ?1 AND (?2 IS NULL OR messages.chat = ?2) \
+		ORDER BY messages.time DESC, messages.seq DESC LIMIT ?3")?;
+	let hits = stmt.query_map(params![query, chat_filter, limit], |row| {
+		let chat_id: i64 = row.get(1)?;
+		Ok(SearchHit {
+			id: get_id(row, 0)?,
+			chat: members.get(&chat_id).cloned().unwrap_or_default(),
+			time: get_u64(row, 2)?,
+			snippet: row.get(3)?,
+		})
+	})?;
+	hits.collect()
 }
 
 pub fn insert_attachment(conn: &mut Connection, id: &AttachmentId, att: &Attachment) -> rusqlite::Result<usize> {
@@ -168,11 +910,83 @@ pub fn insert_attachment(conn: &mut Connection, id: &AttachmentId, att: &Attachm
 	)
 }
 
-pub struct Query<'a>(rusqlite::Statement<'a>);
+/* prepared statements for loading messages a page at a time */
+pub struct Query<'a> {
+	conn: &'a Connection,
+	page: rusqlite::Statement<'a>,
+	message: rusqlite::Statement<'a>,
+	parts: rusqlite::Statement<'a>,
+	members: rusqlite::Statement<'a>,
+}
 
 impl<'a> Query<'a> {
-	pub fn new(conn: &'a mut Connection) -> rusqlite::Result<Query<'a>> {
-		Ok(Query(conn.prepare("SELECT id, sender, chat, time, contents, status FROM messages ORDER BY time")?))
+	pub fn new(conn: &'a Connection) -> rusqlite::Result<Query<'a>> {
+		Ok(Query {
+			conn,
+			page: conn.prepare("SELECT id, sender, time, status, seq FROM messages \
+				WHERE chat = ?1 AND (?2 IS NULL OR time < ?2 OR (time = ?2 AND seq < ?3)) \
+				ORDER BY time DESC, seq DESC LIMIT ?4")?,
+			message: conn.prepare("SELECT id, sender, time, status, chat, seq FROM messages WHERE id = ?1")?,
+			parts: conn.prepare("SELECT kind, text, attachment_id FROM message_parts \
+				WHERE message_id = ?1 ORDER BY idx")?,
+			members: conn.prepare("SELECT number FROM chat_members WHERE chat_id = ?1 ORDER BY number")?,
+		})
+	}
+
+	fn contents(&mut self, id: &MessageId) -> rusqlite::Result<Vec<MessageItem>> {
+		let items = self.parts.query_map(params![&id[..]], |row| get_message_item(row, 0))?;
+		items.collect()
+	}
+
+	/* load up to limit messages of a chat from before the given (time, seq), or the newest if None. oldest first. */
+	pub fn chat_page(&mut self, chat: &Chat, before: Option<MessageOrder>, limit: u32) -> rusqlite::Result<Vec<(MessageId, MessageInfo)>> {
+		let chat_id = match find_chat(self.conn, &chat.numbers)? {
+			Some(id) => id,
+			None => return Ok(vec![]),
+		};
+		let (before_time, before_seq) = match before {
+			Some((time, seq)) => (Some(time as i64), Some(seq as i64)),
+			None => (None, None),
+		};
+		let rows = self.page.query_map(params![chat_id, before_time, before_seq, limit], |row| {
+			Ok((get_id(row, 0)?, get_number(row, 1)?, get_u64(row, 2)?, get_status(row, 3)?, get_u64(row, 4)?))
+		})?.collect::<rusqlite::Result<Vec<_>>>()?;
+
+		let mut page = vec![];
+		for (id, sender, time, status, seq) in rows.into_iter().rev() {
+			let message = MessageInfo {
+				sender,
+				chat: chat.numbers.clone(),
+				time,
+				seq,
+				contents: self.contents(&id)?,
+				status,
+			};
+			page.push((id, message));
+		}
+		Ok(page)
+	}
+
+	/* load a single message */
+	pub fn message(&mut self, id: &MessageId) -> rusqlite::Result<Option<MessageInfo>> {
+		let row = self.message.query_row(params![&id[..]], |row| {
+			Ok((get_number(row, 1)?, get_u64(row, 2)?, get_status(row, 3)?, row.get::<_, i64>(4)?, get_u64(row, 5)?))
+		});
+		let (sender, time, status, chat_id, seq) = match row {
+			Ok(row) => row,
+			Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(None),
+			Err(e) => return Err(e),
+		};
+		let chat = self.members.query_map(params![chat_id], |row| get_number(row, 0))?
+			.collect::<rusqlite::Result<Vec<_>>>()?;
+		Ok(Some(MessageInfo {
+			sender,
+			chat,
+			time,
+			seq,
+			contents: self.contents(id)?,
+			status,
+		}))
 	}
 }
 
@@ -203,14 +1017,15 @@ pub fn get_next_attachment_id(conn: &mut Connection) -> rusqlite::Result<Attachm
 /*
 	return chats along with their open tab index (-1 if closed) and last message (if any) timestamp + id
 */
-pub fn get_all_chats(conn: &mut Connection) -> rusqlite::Result<Vec<(Chat, i32, Option<(u64, MessageId)>)>> {
-	let mut q = conn.prepare("SELECT numbers, tab_id, last_msg_id, time FROM chats \
+pub fn get_all_chats(conn: &Connection) -> rusqlite::Result<Vec<(Chat, i32, Option<(u64, MessageId)>)>> {
+	let mut members = get_chat_members(&mut conn.prepare(CHAT_MEMBERS_QUERY)?)?;
+	let mut q = conn.prepare("SELECT chats.id, tab_id, last_msg_id, time FROM chats \
 		LEFT JOIN messages ON chats.last_msg_id = messages.id \
 		ORDER BY tab_id")?;
 
 	let chat_iter = q.query_map(params![], |row| {
 		let chat = Chat {
-			numbers: get_numbers(row, 0)?,
+			numbers: members.remove(&row.get::<_, i64>(0)?).unwrap_or_default(),
 		};
 		let tab_id: i32 = row.get(1).unwrap_or(-1);
 		let last_msg_info = match (get_id(row, 2), get_u64(row, 3)) {
@@ -227,53 +1042,134 @@ pub fn get_all_chats(conn: &mut Connection) -> rusqlite::Result<Vec<(Chat, i32,
 		.filter_map(Result::ok).collect())
 }
 
-pub fn get_all_messages<'a>(stmt: &'a mut Query) -> rusqlite::Result<Result<impl Iterator<Item=rusqlite::Result<(MessageId, MessageInfo)>> + 'a, String>> {
-	let message_iter = stmt.0.query_map(params![], |row| {
-		let id: MessageId = get_id(row, 0)?;
-		let message = MessageInfo {
-			sender: get_number(row, 1)?,
-			chat: get_numbers(row, 2)?,
-			time: get_u64(row, 3)?,
-			contents: get_message_items(row, 4)?,
-			status: MessageStatus::from_u8(get_u8(row, 5)?).expect("invalid message status"),
+/* assign chats that have own as a member and no own number yet, and their messages, to own.
+returns how many chats were claimed. */
+pub fn claim_own_number(conn: &mut Connection, own: Number) -> rusqlite::Result<usize> {
+	let tx = conn.transaction()?;
+	let n = tx.execute(
+		"UPDATE chats SET own_number = ?1 WHERE own_number IS NULL \
+			AND id IN (SELECT chat_id FROM chat_members WHERE number = ?1);",
+		params![own.num as i64],
+	)?;
+	tx.execute(
+		"UPDATE messages SET own_number = ?1 WHERE own_number IS NULL \
+			AND chat IN (SELECT id FROM chats WHERE own_number = ?1);",
+		params![own.num as i64],
+	)?;
+	tx.commit()?;
+	Ok(n)
+}
+
+/* replace our own number old by new as chat member, sender and own number. a chat that then has the
+same members as an existing one is merged into it. returns how many chats were changed. */
+pub fn rekey_own_number(conn: &mut Connection, old: Number, new: Number) -> rusqlite::Result<usize> {
+	let tx = conn.transaction()?;
+	let chat_ids = {
+		let mut stmt = tx.prepare("SELECT chat_id FROM chat_members WHERE number = ?1")?;
+		let ids = stmt.query_map(params![old.num as i64], |row| row.get::<_, i64>(0))?;
+		ids.collect::<rusqlite::Result<Vec<i64>>>()?
+	};
+	for &id in &chat_ids {
+		let mut members = {
+			let mut stmt = tx.prepare("SELECT number FROM chat_members WHERE chat_id = ?1")?;
+			let members = stmt.query_map(params![id], |row| get_number(row, 0))?;
+			members.collect::<rusqlite::Result<Vec<Number>>>()?
 		};
-		Ok((id, message))
-	})?;
+		for member in members.iter_mut().filter(|m| **m == old) {
+			*member = new;
+		}
+		members.sort();
+		members.dedup();
+		match find_chat(&tx, &members)? {
+			Some(existing) if existing != id => {
+				tx.execute("UPDATE messages SET chat = ?1 WHERE chat = ?2;", params![existing, id])?;
+				tx.execute(
+					"UPDATE chats SET last_msg_id = (SELECT id FROM messages WHERE chat = ?1 ORDER BY time DESC, seq DESC LIMIT 1) \
+						WHERE id = ?1;",
+					params![existing],
+				)?;
+				/* the merged chat takes over the tab, or the tab closes if it has its own */
+				let moved = tx.execute(
+					"UPDATE chats SET tab_id = (SELECT tab_id FROM chats WHERE id = ?2) WHERE id = ?1 AND tab_id IS NULL;",
+					params![existing, id],
+				)?;
+				if moved == 0 {
+					tx.execute(
+						"UPDATE chats SET tab_id = CASE WHEN id = ?1 THEN NULL ELSE tab_id - 1 END WHERE tab_id >= (SELECT tab_id FROM chats WHERE id = ?1);",
+						params![id],
+					)?;
+				}
+				tx.execute("DELETE FROM chat_members WHERE chat_id = ?1;", params![id])?;
+				tx.execute("DELETE FROM chats WHERE id = ?1;", params![id])?;
+			},
+			_ => {
+				tx.execute("DELETE FROM chat_members WHERE chat_id = ?1 AND number = ?2;", params![id, old.num as i64])?;
+				tx.execute("INSERT OR IGNORE INTO chat_members (chat_id, number) VALUES (?1, ?2);", params![id, new.num as i64])?;
+			},
+		}
+	}
+	tx.execute("UPDATE messages SET sender = ?2 WHERE sender = ?1;", params![old.num as i64, new.num as i64])?;
+	tx.execute("UPDATE messages SET own_number = ?2 WHERE own_number = ?1;", params![old.num as i64, new.num as i64])?;
+	tx.execute("UPDATE chats SET own_number = ?2 WHERE own_number = ?1;", params![old.num as i64, new.num as i64])?;
+	tx.commit()?;
+	Ok(chat_ids.len())
+}
 
-	Ok(Ok(message_iter))
+/* the own number of each chat that has one */
+pub fn get_chat_owners(conn: &Connection) -> rusqlite::Result<BTreeMap<Chat, Number>> {
+	let mut members = get_chat_members(&mut conn.prepare(CHAT_MEMBERS_QUERY)?)?;
+	let mut q = conn.prepare("SELECT id, own_number FROM chats WHERE own_number IS NOT NULL")?;
+	let mut rows = q.query(params![])?;
+	let mut owners = BTreeMap::new();
+	while let Some(row) = rows.next()? {
+		if let Some(numbers) = members.remove(&row.get::<_, i64>(0)?) {
+			owners.insert(Chat { numbers }, get_number(row, 1)?);
+		}
+	}
+	Ok(owners)
 }
 
-use std::collections::HashMap;
+/* every own number chats have been assigned to */
+pub fn get_own_numbers(conn: &Connection) -> rusqlite::Result<Vec<Number>> {
+	let mut q = conn.prepare("SELECT DISTINCT own_number FROM chats WHERE own_number IS NOT NULL ORDER BY own_number")?;
+	let numbers = q.query_map(params![], |row| get_number(row, 0))?;
+	numbers.collect()
+}
 
-pub fn get_all_attachments(conn: &mut Connection) -> rusqlite::Result<HashMap<AttachmentId, Attachment>> {
-	let mut q = conn.prepare("SELECT id, name, mime_type, path, start, len FROM attachments")?;
+const CHAT_MEMBERS_QUERY: &str = "SELECT chat_id, number FROM chat_members ORDER BY chat_id, number";
+
+/* gather the sorted members of every chat, using a statement prepared from CHAT_MEMBERS_QUERY */
+fn get_chat_members(stmt: &mut rusqlite::Statement) -> rusqlite::Result<HashMap<i64, Vec<Number>>> {
+	let mut members = HashMap::<i64, Vec<Number>>::new();
+	let mut rows = stmt.query(params![])?;
+	while let Some(row) = rows.next()? {
+		members.entry(row.get(0)?).or_default().push(get_number(row, 1)?);
+	}
+	Ok(members)
+}
 
-	let att_iter = q.query_map(params![], |row| {
-		let id: AttachmentId = get_u64(row, 0)?;
+pub fn get_attachment(conn: &Connection, id: AttachmentId) -> rusqlite::Result<Option<Attachment>> {
+	let mut q = conn.prepare_cached("SELECT name, mime_type, path, start, len FROM attachments WHERE id = ?1")?;
+	let res = q.query_row(params![id as i64], |row| {
 		use std::os::unix::ffi::OsStringExt;
-		let path: std::ffi::OsString = OsStringExt::from_vec(row.get(3)?);
-		let att = Attachment {
-			name: OsStringExt::from_vec(row.get(1)?),
-			mime_type: row.get::<_, String>(2)?,
+		Ok(Attachment {
+			name: OsStringExt::from_vec(row.get(0)?),
+			mime_type: row.get::<_, String>(1)?,
 			data: (
-				path.into(),
+				get_path(row, 2)?,
+				get_u64(row, 3)?,
 				get_u64(row, 4)?,
-				get_u64(row, 5)?,
 			),
-		};
-		Ok((id, att))
-	})?;
-
-	Ok(att_iter
-		.inspect(|x| if let Err(e) = x {
-			eprintln!("error loading attachment: {}", e)
 		})
-		.filter_map(Result::ok).collect())
+	});
+	match res {
+		Ok(att) => Ok(Some(att)),
+		Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+		Err(e) => Err(e),
+	}
 }
 
 mod get {
-	use byteorder::ByteOrder;
-
 	use crate::types::*;
 
 	pub fn get_u8(row: &rusqlite::Row, idx: usize) -> rusqlite::Result<u8> {
@@ -290,69 +1186,298 @@ mod get {
 
 	pub fn get_id(row: &rusqlite::Row, idx: usize) -> rusqlite::Result<MessageId> {
 		use std::convert::TryInto;
-		let id: MessageId = if let rusqlite::types::ValueRef::Blob(data) = row.get_raw(idx) {
-			data.try_into().expect("invalid message ID")
+		if let rusqlite::types::ValueRef::Blob(data) = row.get_raw(idx) {
+			data.try_into().map_err(|_|
+				rusqlite::Error::InvalidColumnType(idx, "id".into(), rusqlite::types::Type::Blob))
 		} else {
-			return Err(rusqlite::Error::InvalidColumnType(idx, "id".into(), rusqlite::types::Type::Blob))
-		};
-		Ok(id)
+			Err(rusqlite::Error::InvalidColumnType(idx, "id".into(), rusqlite::types::Type::Blob))
+		}
 	}
 
-	pub fn get_numbers(row: &rusqlite::Row, idx: usize) -> rusqlite::Result<Vec<Number>> {
-		if let rusqlite::types::ValueRef::Blob(data) = row.get_raw(idx) {
-			let chat_nums = unsafe { crate::db::bytes_to_chat(data) };
-			Ok(chat_nums.to_vec())
-		} else {
-			/* value was not a blob! */
-			Err(rusqlite::Error::InvalidColumnType(idx, "Vec<Number>".into(), rusqlite::types::Type::Blob))
+	pub fn get_path(row: &rusqlite::Row, idx: usize) -> rusqlite::Result<std::path::PathBuf> {
+		use std::os::unix::ffi::OsStringExt;
+		let path: std::ffi::OsString = OsStringExt::from_vec(row.get(idx)?);
+		Ok(path.into())
+	}
+
+	pub fn get_status(row: &rusqlite::Row, idx: usize) -> rusqlite::Result<MessageStatus> {
+		MessageStatus::from_u8(get_u8(row, idx)?).ok_or_else(||
+			rusqlite::Error::InvalidColumnType(idx, "status".into(), rusqlite::types::Type::Integer))
+	}
+
+	/* read a message part from columns (kind, text, attachment_id) starting at idx */
+	pub fn get_message_item(row: &rusqlite::Row, idx: usize) -> rusqlite::Result<MessageItem> {
+		let kind: String = row.get(idx)?;
+		match &*kind {
+			super::PART_TEXT => Ok(MessageItem::Text(row.get(idx + 1)?)),
+			super::PART_ATTACHMENT => Ok(MessageItem::Attachment(get_u64(row, idx + 2)?)),
+			_ => Err(rusqlite::Error::InvalidColumnType(idx, "message part kind".into(), rusqlite::types::Type::Text)),
 		}
 	}
+}
 
-	//TODO: this is an awful serialization scheme but works for message text not containing NUL bytes
-	pub fn get_message_items(row: &rusqlite::Row, idx: usize) -> rusqlite::Result<Vec<MessageItem>> {
-		/*
-		serialization format: 
-			't' [^\0]+ '\0'
-			'a' .{8}
-		*/
+#[test]
+fn test_migrate() {
+	let mut conn = Connection::open_in_memory().unwrap();
+	assert_eq!(migrate(&mut conn).unwrap(), 0);
+	assert_eq!(get_schema_version(&conn).unwrap(), SCHEMA_VERSION);
+	assert_eq!(migrate(&mut conn).unwrap(), SCHEMA_VERSION);
 
-		enum Kind { Unknown, Text, Attachment, };
-		use Kind::*;
+	/* databases from before schema_version was added start at version 1 */
+	let mut legacy = Connection::open_in_memory().unwrap();
+	create_tables(&legacy).unwrap();
+	assert_eq!(migrate(&mut legacy).unwrap(), 1);
 
-		let mut contents = vec![];
+	set_schema_version(&conn, SCHEMA_VERSION + 1).unwrap();
+	assert!(matches!(migrate(&mut conn), Err(MigrationError::TooNew { .. })));
+}
 
-		let vec = row.get::<_, Vec<u8>>(idx)?;
-		let mut kind = Unknown;
-		let mut text_start = 0;
+#[test]
+fn test_message_parts_migration() {
+	let mut conn = Connection::open_in_memory().unwrap();
+	create_tables(&conn).unwrap();
+	let chat: Vec<u8> = [1u64, 2].iter().flat_map(|n| n.to_ne_bytes().to_vec()).collect();
+	let id = [7u8; 20];
+	conn.execute("INSERT INTO chats (numbers) VALUES (?1)", params![chat]).unwrap();
+	conn.execute("INSERT INTO attachments (id) VALUES (4)", params![]).unwrap();
+	conn.execute("INSERT INTO messages (id, sender, chat, time, contents, status) VALUES (?1, 1, ?2, 0, ?3, 0)",
+		params![&id[..], chat, b"thello\0a\x04\0\0\0\0\0\0\0".to_vec()]).unwrap();
+	migrate(&mut conn).unwrap();
 
-		let mut i = 0;
-		while i < vec.len() {
-			let n = vec[i];
-			match kind {
-				Unknown => match n {
-					b't' => { kind = Text; text_start = i + 1; },
-					b'a' => { kind = Attachment; },
-					_ => panic!("invalid message content in db"),
-				},
-				Text => if n == 0 {
-					let s = match std::str::from_utf8(&vec[text_start..i]) {
-						Ok(s) => s,
-						Err(e) => return Err(rusqlite::Error::Utf8Error(e)),
-					};
-					contents.push(MessageItem::Text(s.to_owned()));
-					kind = Unknown;
-				},
-				Attachment => {
-					let len = std::mem::size_of::<u64>();
-					let buf = &vec[i..i+len];
-					let att_id = byteorder::LittleEndian::read_u64(buf);
-					contents.push(MessageItem::Attachment(att_id));
-					i += len - 1;
-					kind = Unknown;
-				},
-			}
-			i += 1;
-		}
-		Ok(contents)
+	let msg = Query::new(&conn).unwrap().message(&id).unwrap().unwrap();
+	assert_eq!(msg.chat, vec![Number::new(1), Number::new(2)]);
+	match &*msg.contents {
+		[MessageItem::Text(t), MessageItem::Attachment(4)] => assert_eq!(t, "hello"),
+		x => panic!("unexpected contents {:?}", x),
+	}
+}
+
+#[test]
+fn test_message_parts_keep_text() {
+	let mut conn = Connection::open_in_memory().unwrap();
+	create_tables(&conn).unwrap();
+	let chat: Vec<u8> = [1u64, 2].iter().flat_map(|n| n.to_ne_bytes().to_vec()).collect();
+	conn.execute("INSERT INTO chats (numbers) VALUES (?1)", params![chat]).unwrap();
+	let texts = ["007", "1.50", "+5", "3.0", "1e3", " 12"];
+	for (i, t) in texts.iter().enumerate() {
+		let contents: Vec<u8> = [&b"t"[..], t.as_bytes(), &b"\0"[..]].concat();
+		conn.execute("INSERT INTO messages (id, sender, chat, time, contents, status) VALUES (?1, 1, ?2, 0, ?3, 0)",
+			params![&[i as u8; 20][..], chat, contents]).unwrap();
+	}
+	migrate(&mut conn).unwrap();
+
+	/* number-like texts must not be turned into numbers by the column's affinity */
+	let mut select = conn.prepare("SELECT text FROM message_parts WHERE message_id = ?1 AND idx = 0").unwrap();
+	for (i, t) in texts.iter().enumerate() {
+		let text: String = select.query_row(params![&[i as u8; 20][..]], |row| row.get(0)).unwrap();
+		assert_eq!(text.as_bytes(), t.as_bytes());
+	}
+}
+
+#[test]
+fn test_chat_members() {
+	let mut conn = Connection::open_in_memory().unwrap();
+	migrate(&mut conn).unwrap();
+	let small = Chat { numbers: vec![Number::new(1), Number::new(2)] };
+	let big = Chat { numbers: vec![Number::new(1), Number::new(2), Number::new(3)] };
+	insert_chat(&mut conn, &big, -1, None).unwrap();
+	insert_chat(&mut conn, &small, 0, None).unwrap();
+	assert!(insert_chat(&mut conn, &small, -1, None).is_err());
+
+	assert_eq!(find_chat(&conn, &[Number::new(2), Number::new(1)]).unwrap(), Some(2));
+	assert_eq!(find_chat(&conn, &[Number::new(1)]).unwrap(), None);
+	let chats = get_all_chats(&mut conn).unwrap();
+	assert!(chats.iter().any(|(c, tab, _)| c == &small && *tab == 0));
+	assert!(chats.iter().any(|(c, tab, _)| c == &big && *tab == -1));
+}
+
+#[test]
+fn test_delete_message_attachments() {
+	let mut conn = Connection::open_in_memory().unwrap();
+	migrate(&mut conn).unwrap();
+	let chat = Chat { numbers: vec![Number::new(1), Number::new(2)] };
+	insert_chat(&mut conn, &chat, -1, None).unwrap();
+	let att = Attachment { name: "a.png".into(), mime_type: "image/png".into(), data: ("/nonexistent".into(), 0, 0) };
+	insert_attachment(&mut conn, &1, &att).unwrap();
+	insert_attachment(&mut conn, &2, &att).unwrap();
+	insert_attachment(&mut conn, &3, &att).unwrap();
+	let msg = |contents| MessageInfo {
+		sender: Number::new(1), chat: chat.numbers.clone(), time: 0, seq: 0, contents, status: MessageStatus::Received,
+	};
+	insert_message(&mut conn, &[1u8; 20], &msg(vec![MessageItem::Attachment(1), MessageItem::Attachment(2)])).unwrap();
+	insert_message(&mut conn, &[2u8; 20], &msg(vec![MessageItem::Attachment(2)])).unwrap();
+
+	/* attachment 2 is still used by the second message */
+	assert_eq!(delete_message(&mut conn, &[1u8; 20]).unwrap(), vec![1]);
+	/* attachment 3 was never used by any message */
+	assert_eq!(collect_garbage(&mut conn, Path::new("/nonexistent")).unwrap().rows, 1);
+	assert_eq!(delete_chat(&mut conn, &chat).unwrap(), vec![2]);
+	assert!(get_attachment(&conn, 2).unwrap().is_none());
+
+	/* files just stored, or still being written, may be about to be referenced */
+	let dir = std::env::temp_dir().join(format!("vgmms-test-gc-{}", std::process::id()));
+	std::fs::create_dir_all(&dir).unwrap();
+	std::fs::write(dir.join("stored"), b"").unwrap();
+	std::fs::write(dir.join(".tmp-1234"), b"").unwrap();
+	let files = collect_garbage(&mut conn, &dir).unwrap().files;
+	let kept = std::fs::read_dir(&dir).unwrap().count();
+	std::fs::remove_dir_all(&dir).unwrap();
+	assert_eq!((files, kept), (0, 2));
+}
+
+#[test]
+fn test_search() {
+	let mut conn = Connection::open_in_memory().unwrap();
+	migrate(&mut conn).unwrap();
+	let chat = Chat { numbers: vec![Number::new(1), Number::new(2)] };
+	let other = Chat { numbers: vec![Number::new(1), Number::new(3)] };
+	insert_chat(&mut conn, &chat, -1, None).unwrap();
+	insert_chat(&mut conn, &other, -1, None).unwrap();
+	let msg = |chat: &Chat, time, text: &str| MessageInfo {
+		sender: Number::new(1), chat: chat.numbers.clone(), time, seq: 0,
+		contents: vec![MessageItem::Text(text.into())], status: MessageStatus::Received,
+	};
+	insert_message(&mut conn, &[1u8; 20], &msg(&chat, 1, "see you at the station")).unwrap();
+	insert_message(&mut conn, &[2u8; 20], &msg(&other, 2, "which station?")).unwrap();
+	insert_message(&mut conn, &[3u8; 20], &msg(&chat, 3, "running \"late\"")).unwrap();
+
+	let ids = |hits: Vec<SearchHit>| hits.into_iter().map(|h| h.id[0]).collect::<Vec<_>>();
+	assert_eq!(ids(search(&mut conn, "stat", None, 10).unwrap()), vec![2, 1]);
+	assert_eq!(ids(search(&mut conn, "stat", Some(&chat), 10).unwrap()), vec![1]);
+	assert_eq!(ids(search(&mut conn, "\"late", None, 10).unwrap()), vec![3]);
+
+	delete_message(&mut conn, &[1u8; 20]).unwrap();
+	assert_eq!(ids(search(&mut conn, "station", None, 10).unwrap()), vec![2]);
+}
+
+#[test]
+fn test_chat_page() {
+	let mut conn = Connection::open_in_memory().unwrap();
+	migrate(&mut conn).unwrap();
+	let chat = Chat { numbers: vec![Number::new(1), Number::new(2)] };
+	insert_chat(&mut conn, &chat, -1, None).unwrap();
+	/* ids run against arrival order, which must win between messages with the same time */
+	for i in 0..5u8 {
+		let msg = MessageInfo {
+			sender: Number::new(1), chat: chat.numbers.clone(), time: (i / 2) as u64, seq: 0,
+			contents: vec![MessageItem::Text(i.to_string())], status: MessageStatus::Received,
+		};
+		assert_eq!(insert_message(&mut conn, &[4 - i; 20], &msg).unwrap(), i as u64 + 1);
 	}
+
+	let mut q = Query::new(&conn).unwrap();
+	let ids = |page: Vec<(MessageId, MessageInfo)>| page.into_iter().map(|(id, _)| id[0]).collect::<Vec<_>>();
+	let newest = q.chat_page(&chat, None, 2).unwrap();
+	assert_eq!(newest[1].1.contents.len(), 1);
+	assert_eq!(newest[1].1.seq, 5);
+	assert_eq!(ids(newest), vec![1, 0]);
+	/* messages with the same timestamp are not skipped between pages */
+	assert_eq!(ids(q.chat_page(&chat, Some((1, 4)), 2).unwrap()), vec![3, 2]);
+	assert_eq!(ids(q.chat_page(&chat, Some((0, 2)), 2).unwrap()), vec![4]);
+}
+
+#[test]
+fn test_message_status() {
+	let mut conn = Connection::open_in_memory().unwrap();
+	migrate(&mut conn).unwrap();
+	let chat = Chat { numbers: vec![Number::new(1), Number::new(2)] };
+	insert_chat(&mut conn, &chat, -1, None).unwrap();
+	let msg = MessageInfo {
+		sender: Number::new(1), chat: chat.numbers.clone(), time: 0, seq: 0,
+		contents: vec![], status: MessageStatus::Sending,
+	};
+	insert_message(&mut conn, &[1; 20], &msg).unwrap();
+	insert_message(&mut conn, &[2; 20], &msg).unwrap();
+
+	assert!(update_message_status(&conn, &[1; 20], MessageStatus::Sent).unwrap());
+	assert!(!update_message_status(&conn, &[3; 20], MessageStatus::Sent).unwrap());
+	assert_eq!(mark_interrupted_sends(&conn).unwrap(), 1);
+	let mut q = Query::new(&conn).unwrap();
+	assert_eq!(q.message(&[1; 20]).unwrap().unwrap().status, MessageStatus::Sent);
+	assert_eq!(q.message(&[2; 20]).unwrap().unwrap().status, MessageStatus::Unknown);
+}
+
+#[test]
+fn test_outbox() {
+	let mut conn = Connection::open_in_memory().unwrap();
+	migrate(&mut conn).unwrap();
+	let chat = Chat { numbers: vec![Number::new(1), Number::new(2)] };
+	insert_chat(&mut conn, &chat, -1, None).unwrap();
+	let msg = MessageInfo {
+		sender: Number::new(1), chat: chat.numbers.clone(), time: 0, seq: 0,
+		contents: vec![MessageItem::Text("hi".into())], status: MessageStatus::Sending,
+	};
+	insert_message(&mut conn, &[1; 20], &msg).unwrap();
+	insert_message(&mut conn, &[2; 20], &msg).unwrap();
+
+	let entry = OutboxEntry { attempts: 2, next_attempt: 1_600_000_000_000 };
+	queue_message(&conn, &[1; 20], &OutboxEntry { attempts: 0, next_attempt: 0 }).unwrap();
+	queue_message(&conn, &[1; 20], &entry).unwrap();
+	/* only the message that had been handed over is interrupted */
+	assert_eq!(mark_interrupted_sends(&conn).unwrap(), 1);
+	assert_eq!(get_outbox(&conn).unwrap().into_iter().collect::<Vec<_>>(), vec![([1; 20], entry)]);
+
+	delete_message(&mut conn, &[1; 20]).unwrap();
+	assert!(get_outbox(&conn).unwrap().is_empty());
+}
+
+#[test]
+fn test_own_numbers() {
+	let mut conn = Connection::open_in_memory().unwrap();
+	migrate(&mut conn).unwrap();
+	let (me, other_me) = (Number::new(1), Number::new(5));
+	let chat = Chat { numbers: vec![me, Number::new(2)] };
+	let other = Chat { numbers: vec![Number::new(3), other_me] };
+	insert_chat(&mut conn, &chat, -1, None).unwrap();
+	insert_chat(&mut conn, &other, -1, None).unwrap();
+	let msg = |chat: &Chat| MessageInfo {
+		sender: chat.numbers[1], chat: chat.numbers.clone(), time: 0, seq: 0,
+		contents: vec![], status: MessageStatus::Received,
+	};
+	insert_message(&mut conn, &[1; 20], &msg(&chat)).unwrap();
+
+	assert_eq!(claim_own_number(&mut conn, me).unwrap(), 1);
+	assert_eq!(claim_own_number(&mut conn, me).unwrap(), 0);
+	/* messages of a claimed chat belong to it from the start */
+	insert_message(&mut conn, &[2; 20], &msg(&chat)).unwrap();
+	let owned = conn.query_row("SELECT count(*) FROM messages WHERE own_number = ?1", params![me.num as i64],
+		|row| row.get::<_, i64>(0)).unwrap();
+	assert_eq!(owned, 2);
+
+	claim_own_number(&mut conn, other_me).unwrap();
+	assert_eq!(get_own_numbers(&conn).unwrap(), vec![me, other_me]);
+	let owners = get_chat_owners(&conn).unwrap();
+	assert_eq!(owners.get(&chat), Some(&me));
+	assert_eq!(owners.get(&other), Some(&other_me));
+}
+
+#[test]
+fn test_rekey_own_number() {
+	let mut conn = Connection::open_in_memory().unwrap();
+	migrate(&mut conn).unwrap();
+	let (old, new) = (Number::new(1), Number::new(9));
+	let with_old = Chat { numbers: vec![old, Number::new(2)] };
+	let with_new = Chat { numbers: vec![Number::new(2), new] };
+	let group = Chat { numbers: vec![old, Number::new(3), Number::new(4)] };
+	insert_chat(&mut conn, &with_old, 0, None).unwrap();
+	insert_chat(&mut conn, &with_new, -1, None).unwrap();
+	insert_chat(&mut conn, &group, 1, None).unwrap();
+	let msg = |chat: &Chat, sender, time| MessageInfo {
+		sender, chat: chat.numbers.clone(), time, seq: 0,
+		contents: vec![], status: MessageStatus::Received,
+	};
+	insert_message(&mut conn, &[1; 20], &msg(&with_old, old, 1)).unwrap();
+	insert_message(&mut conn, &[2; 20], &msg(&with_new, Number::new(2), 2)).unwrap();
+	insert_message(&mut conn, &[3; 20], &msg(&group, Number::new(3), 3)).unwrap();
+
+	assert_eq!(rekey_own_number(&mut conn, old, new).unwrap(), 2);
+	let chats = get_all_chats(&conn).unwrap();
+	/* the chat with 2 was merged into the one already with the new number, keeping its tab */
+	assert_eq!(chats.len(), 2);
+	assert_eq!(chats[0].0, with_new);
+	assert_eq!((chats[0].1, chats[0].2.map(|(_t, id)| id)), (0, Some([2; 20])));
+	assert_eq!(chats[1].0, Chat { numbers: vec![Number::new(3), Number::new(4), new] });
+	let mut q = Query::new(&conn).unwrap();
+	let moved = q.message(&[1; 20]).unwrap().unwrap();
+	assert_eq!((moved.sender, moved.chat), (new, with_new.numbers.clone()));
 }
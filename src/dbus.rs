@@ -1,41 +1,10 @@
 use dbus::blocking::Connection;
 use dbus::message::MatchRule;
-use std::path::PathBuf;
 use std::time::Duration;
 
+use crate::backend::{Error as BackendError, NotifSink, Notification, ReceivedAttachment, StoredMms, notify};
 use crate::types::MessageStatus;
 
-#[derive(Debug, Clone)]
-pub struct Attachment {
-	pub name: String,
-	pub mime_type: String,
-	pub disk_path: PathBuf,
-	pub start: u64,
-	pub len: u64,
-}
-
-#[derive(Debug, Clone)]
-pub enum DbusNotification {
-	MmsStatusUpdate {
-		id: [u8; 20],
-		status: MessageStatus,
-	},
-	MmsReceived {
-		id: [u8; 20],
-		date: String,
-		subject: Option<String>,
-		sender: String,
-		recipients: Vec<String>,
-		attachments: Vec<Attachment>,
-		smil: Option<String>,
-	},
-	SmsReceived {
-		message: String,
-		date: String,
-		sender: String,
-	}
-}
-
 #[derive(Debug)]
 pub enum ParseError {
 	BadMmsPath,
@@ -48,9 +17,9 @@ pub enum ParseError {
 	MissingRecipients,
 }
 
-use DbusNotification::*;
+use Notification::*;
 
-fn parse_sms_message(msg: &dbus::Message) -> Result<DbusNotification, ParseError> {
+fn parse_sms_message(msg: &dbus::Message) -> Result<Notification, ParseError> {
 	use dbus::arg::*;
 	if let (Some(text), Some(dict)) = msg.get2::<String, Dict<&str, Variant<String>, _>>() {
 		let (mut sender, mut date) = (None, None);
@@ -63,20 +32,24 @@ fn parse_sms_message(msg: &dbus::Message) -> Result<DbusNotification, ParseError
 		}
 		let sender = sender.ok_or(ParseError::MissingSender)?;
 		let date = date.ok_or(ParseError::MissingSentTime)?;
+		let modem = msg.path().ok_or(ParseError::BadArgs)?.to_string();
 
 		//println!("{} @ {}: {}", sender, date, text)
 		Ok(SmsReceived {
 			message: text,
 			date: date,
 			sender: sender,
+			modem: modem,
+			/* oFono hands SMS over without keeping them */
+			stored: None,
 		})
 	} else {
 		Err(ParseError::BadArgs)
 	}
 }
 
-fn parse_attachments<'a>(v: &'a(dyn dbus::arg::RefArg + 'static)) -> Result<Vec<Attachment>, ParseError> {
-	match || -> Option<Vec<Attachment>> {
+fn parse_attachments<'a>(v: &'a(dyn dbus::arg::RefArg + 'static)) -> Result<Vec<ReceivedAttachment>, ParseError> {
+	match || -> Option<Vec<ReceivedAttachment>> {
 		let mut atts = vec![];
 		//descend into variant
 		let mut v = v.as_iter()?;
@@ -92,7 +65,7 @@ fn parse_attachments<'a>(v: &'a(dyn dbus::arg::RefArg + 'static)) -> Result<Vec<
 			let disk_path = att_fields.next()?.as_str()?.to_owned().into();
 			let start = att_fields.next()?.as_u64()?;
 			let len = att_fields.next()?.as_u64()?;
-			atts.push(Attachment {
+			atts.push(ReceivedAttachment {
 				name, mime_type, disk_path, start, len,
 			})
 		}
@@ -120,48 +93,187 @@ fn parse_numbers<'a>(v: &'a(dyn dbus::arg::RefArg + 'static)) -> Result<Vec<Stri
 	}
 }
 
-fn parse_mms_message(msg: &dbus::Message) -> Result<DbusNotification, ParseError> {
+/* the message id vgmms uses for the MMS mmsd stores at path */
+fn mms_id(path: &str) -> Result<[u8; 20], ParseError> {
+	if path.len() < 40 {
+		return Err(ParseError::BadMmsPath);
+	}
+	let mut mms_id = [0u8; 20];
+	if let Err(_) = hex::decode_to_slice(&path[path.len()-40..], &mut mms_id) {
+		return Err(ParseError::BadMmsPath);
+	}
+	Ok(mms_id)
+}
+
+/* build an MmsReceived from the properties of an mmsd message, as in MessageAdded or GetMessages */
+fn parse_mms_properties(path: &str, props: &[(&str, &(dyn dbus::arg::RefArg + 'static))]) -> Result<Notification, ParseError> {
+	let mms_id = mms_id(path)?;
+	let (mut sender, mut date, mut subject, mut recipients, mut attachments, mut smil) =
+		(None, None, None, None, None, None);
+	for &(k, v) in props {
+		//println!("{}: {:?}", k, v);
+		match k {
+			//"Status" => status = Some(v.0),
+			"Sender" => sender = v.as_str().map(|x| x.to_owned()),
+			"Date" => date = v.as_str().map(|x| x.to_owned()),
+			"Subject" => subject = v.as_str().map(|x| x.to_owned()),
+			"Recipients" => recipients = Some(parse_numbers(v)?),
+			"Attachments" => attachments = Some(parse_attachments(v)?),
+			"Smil" => smil = v.as_str().map(|x| x.to_owned()),
+			_ => (),
+		}
+	}
+	let sender = sender.ok_or(ParseError::MissingSender)?;
+	let date = date.ok_or(ParseError::MissingSentTime)?;
+	let recipients = recipients.ok_or(ParseError::MissingRecipients)?;
+	let attachments = attachments.ok_or(ParseError::MissingAttachments)?;
+
+	Ok(MmsReceived {
+		id: mms_id,
+		date: date,
+		subject: subject,
+		sender: sender,
+		recipients: recipients,
+		attachments: attachments,
+		smil: smil,
+	})
+}
+
+fn parse_mms_message(msg: &dbus::Message) -> Result<Notification, ParseError> {
 	use dbus::arg::*;
 	if let (Some(path), Some(dict)) = msg.get2::<dbus::Path, Dict<&str, Variant<Box<dyn RefArg>>, _>>() {
-		if path.len() < 40 {
-			return Err(ParseError::BadMmsPath);
+		let dict: Vec<_> = dict.collect();
+		let props: Vec<(&str, &(dyn RefArg + 'static))> = dict.iter().map(|(k, v)| (*k, v as &(dyn RefArg + 'static))).collect();
+		parse_mms_properties(&path, &props)
+	} else {
+		Err(ParseError::BadArgs)
+	}
+}
+
+/* the status of a sent message for an mmsd Status value */
+fn mms_status(status: &str) -> Option<MessageStatus> {
+	match status {
+		"sent" => Some(MessageStatus::Sent),
+		"delivered" => Some(MessageStatus::Delivered),
+		"read" => Some(MessageStatus::Read),
+		"failed" => Some(MessageStatus::Failed),
+		/* e.g. "received"; not a state of a message we sent */
+		_ => None,
+	}
+}
+
+fn parse_mms_status(msg: &dbus::Message) -> Result<Option<Notification>, ParseError> {
+	use dbus::arg::*;
+	let path = msg.path().ok_or(ParseError::BadMmsPath)?;
+	match msg.get2::<&str, Variant<Box<dyn RefArg>>>() {
+		(Some("Status"), Some(v)) => Ok(v.0.as_str().and_then(mms_status)
+			.map(|status| StatusUpdate { path: path.to_string(), status })),
+		(Some(_), Some(_)) => Ok(None),
+		_ => Err(ParseError::BadArgs),
+	}
+}
+
+/* the status of a sent SMS for an oFono message State value */
+fn sms_status(state: &str) -> Option<MessageStatus> {
+	match state {
+		"pending" => Some(MessageStatus::Sending),
+		"sent" => Some(MessageStatus::Sent),
+		"failed" => Some(MessageStatus::Failed),
+		_ => None,
+	}
+}
+
+fn parse_sms_state(msg: &dbus::Message) -> Result<Option<Notification>, ParseError> {
+	use dbus::arg::*;
+	let path = msg.path().ok_or(ParseError::BadArgs)?;
+	match msg.get2::<&str, Variant<Box<dyn RefArg>>>() {
+		(Some("State"), Some(v)) => Ok(v.0.as_str().and_then(sms_status)
+			.map(|status| StatusUpdate { path: path.to_string(), status })),
+		(Some(_), Some(_)) => Ok(None),
+		_ => Err(ParseError::BadArgs),
+	}
+}
+
+/* whether an oFono modem property change means sending may work again */
+fn modem_became_available(msg: &dbus::Message) -> Result<bool, ParseError> {
+	use dbus::arg::*;
+	let v = match msg.get2::<&str, Variant<Box<dyn RefArg>>>() {
+		(Some(name), Some(v)) => (name, v),
+		_ => return Err(ParseError::BadArgs),
+	};
+	Ok(match (msg.interface().as_deref(), v) {
+		(Some("org.ofono.Modem"), ("Online", v)) => v.0.as_i64() == Some(1),
+		(Some("org.ofono.NetworkRegistration"), ("Status", v)) => match v.0.as_str() {
+			Some("registered") | Some("roaming") => true,
+			_ => false,
+		},
+		(Some("org.ofono.ConnectionContext"), ("Active", v)) => v.0.as_i64() == Some(1),
+		_ => false,
+	})
+}
+
+type MmsProperties = HashMap<String, dbus::arg::Variant<Box<dyn dbus::arg::RefArg + 'static>>>;
+
+/* the MMS messages the mmsd on mms_conn's bus has stored, whether or not we saw them arrive.
+this can take many calls; the connection is let go of between them so the send worker isn't
+held up meanwhile. */
+pub fn get_stored_mms(mms_conn: &BusConn, chat_numbers: &[String]) -> Result<Vec<StoredMms>, dbus::Error> {
+	use dbus::arg::RefArg;
+	use crate::mmsd_manager::OrgOfonoMmsManager;
+	use crate::mmsd_service::OrgOfonoMmsService;
+
+	let services = mms_conn.with(|conn| {
+		conn.with_proxy("org.ofono.mms", "/org/ofono/mms", Duration::from_millis(500)).get_services()
+	})?;
+	let mut stored: Vec<(dbus::Path<'static>, MmsProperties)> = vec![];
+	for (service_path, _props) in services {
+		let messages = mms_conn.with(|conn| {
+			conn.with_proxy("org.ofono.mms", &service_path, Duration::from_millis(5000)).get_messages()
+		});
+		match messages {
+			Ok(messages) => stored.extend(messages),
+			/* services without GetMessages can still be asked for each conversation we know of */
+			Err(ref e) if e.name() == Some("org.freedesktop.DBus.Error.UnknownMethod") => {
+				for number in chat_numbers {
+					let messages = mms_conn.with(|conn| {
+						conn.with_proxy("org.ofono.mms", &service_path, Duration::from_millis(5000))
+							.get_conversation(number, "0")
+					});
+					match messages {
+						Ok(messages) => stored.extend(messages),
+						Err(e) => eprintln!("error getting stored MMS with {} from {}: {}", number, service_path, e),
+					}
+				}
+			},
+			Err(e) => return Err(e),
 		}
-		let mut mms_id = [0u8; 20];
-		if let Err(_) = hex::decode_to_slice(&path[path.len()-40..], &mut mms_id) {
-			return Err(ParseError::BadMmsPath);
+	}
+
+	let mut messages = vec![];
+	let mut seen = std::collections::HashSet::new();
+	for (path, props) in stored {
+		if !seen.insert(path.clone()) {
+			continue
 		}
-		let (mut sender, mut date, mut subject, mut recipients, mut attachments, mut smil) =
-			(None, None, None, None, None, None);
-		for (k, v) in dict {
-			//println!("{}: {:?}", k, v);
-			match k {
-				//"Status" => status = Some(v.0),
-				"Sender" => sender = v.as_str().map(|x| x.to_owned()),
-				"Date" => date = v.as_str().map(|x| x.to_owned()),
-				"Subject" => subject = v.as_str().map(|x| x.to_owned()),
-				"Recipients" => recipients = Some(parse_numbers(&v)?),
-				"Attachments" => attachments = Some(parse_attachments(&v)?),
-				"Smil" => smil = v.as_str().map(|x| x.to_owned()),
-				_ => (),
-			}
+		let status = props.get("Status").and_then(|s| s.0.as_str());
+		if status == Some("draft") {
+			continue
 		}
-		let sender = sender.ok_or(ParseError::MissingSender)?;
-		let date = date.ok_or(ParseError::MissingSentTime)?;
-		let recipients = recipients.ok_or(ParseError::MissingRecipients)?;
-		let attachments = attachments.ok_or(ParseError::MissingAttachments)?;
-
-		Ok(MmsReceived {
-			id: mms_id,
-			date: date,
-			subject: subject,
-			sender: sender,
-			recipients: recipients,
-			attachments: attachments,
-			smil: smil,
-		})
-	} else {
-		Err(ParseError::BadArgs)
+		let sent_status = status.and_then(mms_status);
+		let props: Vec<(&str, &(dyn RefArg + 'static))> = props.iter().map(|(k, v)| (&**k, v as &(dyn RefArg + 'static))).collect();
+		let received = match parse_mms_properties(&path, &props) {
+			Ok(notif) => Some(notif),
+			/* messages we sent may lack a sender */
+			Err(e) => {
+				if sent_status.is_none() {
+					eprintln!("stored MMS {} parse error: {:?}", path, e);
+				}
+				None
+			},
+		};
+		messages.push(StoredMms { path: path.to_string(), sent_status, received });
 	}
+	Ok(messages)
 }
 
 use std::collections::HashMap;
@@ -170,10 +282,10 @@ use crate::types::{MessageItem, MessageInfo};
 
 pub fn get_my_number(/*sys_conn: &mut Connection, */
 	modem_path: &dbus::strings::Path) -> Result<Option<String>, dbus::Error> {
-	let conn = SYS_CONN.lock().unwrap();
-	let sim_proxy = conn.with_proxy("org.ofono", modem_path, Duration::from_millis(500));
 	use crate::ofono_simmanager::OrgOfonoSimManager;
-	let dict = sim_proxy.get_properties()?;
+	let dict = SYS_CONN.with(|conn| {
+		conn.with_proxy("org.ofono", modem_path, Duration::from_millis(500)).get_properties()
+	})?;
 	let mut nums = None;
 	for (k, v) in dict {
 		if let "SubscriberNumbers" = &*k {
@@ -196,99 +308,263 @@ pub fn get_my_number(/*sys_conn: &mut Connection, */
 	})
 }
 
+/* retries left for each kind of PIN, from a SimManager Retries property */
+fn parse_retries(v: &(dyn dbus::arg::RefArg + 'static)) -> HashMap<String, u8> {
+	let mut retries = HashMap::new();
+	/* a dict iterates as alternating keys and values */
+	if let Some(mut items) = v.as_iter() {
+		while let (Some(k), Some(n)) = (items.next(), items.next()) {
+			if let (Some(k), Some(n)) = (k.as_str(), n.as_u64()) {
+				retries.insert(k.to_owned(), n as u8);
+			}
+		}
+	}
+	retries
+}
+
+/* which PIN or PUK the SIM of a modem is waiting for ("none" if it isn't locked), and how many
+attempts at it are left if oFono knows */
+pub fn get_pin_required(modem_path: &dbus::strings::Path) -> Result<(String, Option<u8>), dbus::Error> {
+	use dbus::arg::RefArg;
+	use crate::ofono_simmanager::OrgOfonoSimManager;
+	let dict = SYS_CONN.with(|conn| {
+		conn.with_proxy("org.ofono", modem_path, Duration::from_millis(500)).get_properties()
+	})?;
+	let pin_type = dict.get("PinRequired").and_then(|v| v.0.as_str()).unwrap_or("none").to_owned();
+	let retries = dict.get("Retries").map(|v| parse_retries(&*v.0)).unwrap_or_default();
+	let left = retries.get(&pin_type).cloned();
+	Ok((pin_type, left))
+}
+
+/* unlock the SIM of a modem with its PIN, or with its PUK if a new PIN to set is given */
+pub fn enter_pin(modem_path: &dbus::strings::Path, pin_type: &str, pin: &str, new_pin: Option<&str>) -> Result<(), dbus::Error> {
+	use crate::ofono_simmanager::OrgOfonoSimManager;
+	SYS_CONN.with(|conn| {
+		let sim_proxy = conn.with_proxy("org.ofono", modem_path, Duration::from_millis(5000));
+		match new_pin {
+			Some(new_pin) => sim_proxy.reset_pin(pin_type, pin, new_pin),
+			None => sim_proxy.enter_pin(pin_type, pin),
+		}
+	})
+}
+
+/* ask the SMSC for delivery reports of SMS we send */
+pub fn set_delivery_reports(modem_path: &dbus::strings::Path, enabled: bool) -> Result<(), dbus::Error> {
+	SYS_CONN.with(|conn| {
+		let sms_proxy = conn.with_proxy("org.ofono", modem_path, Duration::from_millis(500));
+		sms_proxy.method_call("org.ofono.MessageManager", "SetProperty",
+			("UseDeliveryReports", dbus::arg::Variant(enabled)))
+	})
+}
+
 pub fn get_modem_paths(/*sys_conn: &mut Connection*/) -> Result<Vec<dbus::strings::Path<'static>>, dbus::Error> {
-	let conn = SYS_CONN.lock().unwrap();
-	let man_proxy = conn.with_proxy("org.ofono", "/", Duration::from_millis(500));
 	use crate::ofono_manager::OrgOfonoManager;
-	let modems = man_proxy.get_modems()?;
+	let modems = SYS_CONN.with(|conn| {
+		conn.with_proxy("org.ofono", "/", Duration::from_millis(500)).get_modems()
+	})?;
 	let paths = modems.iter().map(|m| m.0.to_owned()).collect();
 	Ok(paths)
 }
 
-pub fn send_message(/*sys_conn: &mut Connection, sess_conn: &mut Connection,*/
-	modem_path: &dbus::strings::Path,
-	msg: &MessageInfo,
-	atts: &HashMap<crate::types::AttachmentId, crate::types::Attachment>) -> Result<Option<dbus::strings::Path<'static>>, dbus::Error> {
+/* errors oFono, ModemManager and mmsd give for requests that can never succeed as they are */
+const REJECTED_ERRORS: &[&str] = &[
+	"org.freedesktop.DBus.Error.InvalidArgs",
+	"org.ofono.Error.InvalidArguments",
+	"org.ofono.Error.InvalidFormat",
+	"org.freedesktop.ModemManager1.Error.Core.InvalidArgs",
+];
+
+impl From<dbus::Error> for BackendError {
+	fn from(e: dbus::Error) -> Self {
+		let why = e.message().unwrap_or("unknown D-Bus error").to_owned();
+		match e.name() {
+			Some(name) if REJECTED_ERRORS.contains(&name) => BackendError::Rejected(why),
+			_ => BackendError::Failed(why),
+		}
+	}
+}
 
-	/* prepare recipients */
-	let recip_strings: Vec<_> = msg.chat.iter().filter_map(|n|
+/* the object path a backend names a modem by */
+pub fn object_path(modem: &str) -> Result<dbus::Path<'static>, dbus::Error> {
+	dbus::Path::new(modem.to_owned())
+		.map_err(|e| dbus::Error::new_custom("org.freedesktop.DBus.Error.InvalidArgs", &e))
+}
+
+/* the IMSI of the SIM in a modem, which mmsd names its service for that modem after */
+fn get_imsi(modem_path: &dbus::strings::Path) -> Result<Option<String>, dbus::Error> {
+	use dbus::arg::RefArg;
+	use crate::ofono_simmanager::OrgOfonoSimManager;
+	let dict = SYS_CONN.with(|conn| {
+		conn.with_proxy("org.ofono", modem_path, Duration::from_millis(500)).get_properties()
+	})?;
+	Ok(dict.get("SubscriberIdentity").and_then(|v| v.0.as_str()).map(|s| s.to_owned()))
+}
+
+/* which of mmsd's services is the modem's: the one named after its SIM's IMSI, or after the
+modem itself. a lone service not named after some other SIM is taken to serve whatever modem
+there is, as with mmsd-tng. */
+fn match_mms_service<'a>(services: &'a [String], modem_path: &str, imsi: Option<&str>) -> Option<&'a str> {
+	fn last(path: &str) -> &str {
+		path.rsplit('/').next().unwrap_or(path)
+	}
+	let modem_name = last(modem_path);
+	if let Some(imsi) = imsi {
+		if let Some(service) = services.iter().find(|s| last(s) == imsi) {
+			return Some(service)
+		}
+	}
+	if let Some(service) = services.iter().find(|s| last(s) == modem_name) {
+		return Some(service)
+	}
+	match services {
+		[service] if !last(service).chars().all(|c| c.is_ascii_digit()) => Some(service),
+		_ => None,
+	}
+}
+
+/* the path of the mmsd service on mms_conn's bus to send MMS through the modem at modem_path,
+whose SIM has imsi */
+fn mms_service_for(mms_conn: &BusConn, modem_path: &dbus::strings::Path, imsi: Option<&str>) -> Result<dbus::strings::Path<'static>, BackendError> {
+	use crate::mmsd_manager::OrgOfonoMmsManager;
+	let services = mms_conn.with(|conn| {
+		conn.with_proxy("org.ofono.mms", "/org/ofono/mms", Duration::from_millis(500)).get_services()
+	})?;
+	let services = services.into_iter().map(|(path, _props)| path.to_string()).collect::<Vec<_>>();
+	match match_mms_service(&services, modem_path, imsi) {
+		Some(service) => dbus::strings::Path::new(service.to_owned()).map_err(|_| BackendError::NoMmsService),
+		None => Err(BackendError::NoMmsService),
+	}
+}
+
+/* the numbers a message goes to */
+fn recipients(msg: &MessageInfo) -> Vec<String> {
+	msg.chat.iter().filter_map(|n|
 		if n != &msg.sender {
 			Some(n.to_string())
 		} else {
 			None
-		}).collect();
+		}).collect()
+}
+
+/* the recipient and text of a message that can go as an SMS, or None if it needs MMS */
+pub fn as_sms(msg: &MessageInfo) -> Option<(String, &str)> {
+	match (&*recipients(msg), &*msg.contents) {
+		([recip], [MessageItem::Text(t)]) => Some((recip.clone(), t)),
+		_ => None,
+	}
+}
+
+pub fn send_message(/*sys_conn: &mut Connection, sess_conn: &mut Connection,*/
+	modem_path: &dbus::strings::Path,
+	msg: &MessageInfo,
+	atts: &HashMap<crate::types::AttachmentId, crate::types::Attachment>) -> Result<Option<String>, BackendError> {
 
 	/* choose sms or mms */
-	if let ([recip], [MessageItem::Text(t)]) = (&*recip_strings, &*msg.contents) { /* sms */
-		let conn = SYS_CONN.lock().unwrap();
-		let sms_proxy = conn.with_proxy("org.ofono", modem_path, Duration::from_millis(500));
-		let () = sms_proxy.method_call("org.ofono.MessageManager", "SendMessage", (recip, t))?;
-		Ok(None)
-	} else { /* mms */
-		let recip_strs: Vec<_> = recip_strings.iter().map(|s| &s[..]).collect();
-	
-		/* prepare attachments */
-		let mut attachments = Vec::<(&str, &str, &str)>::new(); /* name, mime type, disk path */
-
-		let mut text_files = vec![];
-		for item in &msg.contents {
-			match item {
-				MessageItem::Text(t) => {
-					use rand::Rng;
-					use std::io::Write;
-					let filename = format!("{}.txt", text_files.len());
-					let mut dir = format!("/tmp/vgmms/{:x}/", rand::thread_rng().gen::<u32>());
-					std::fs::create_dir_all(&dir).unwrap();
-					let path = { dir.push_str(&*filename); dir };
-					let mut f = std::fs::File::create(&path).unwrap();
-					f.write_all(t.as_bytes()).unwrap();
-					text_files.push((filename, path));
-				},
-				_ => (),
-			}
+	if let Some((recip, t)) = as_sms(msg) {
+		let (path,): (dbus::Path<'static>,) = SYS_CONN.with(|conn| {
+			let sms_proxy = conn.with_proxy("org.ofono", modem_path, Duration::from_millis(500));
+			sms_proxy.method_call("org.ofono.MessageManager", "SendMessage", (recip, t))
+		})?;
+		Ok(Some(path.to_string()))
+	} else {
+		let imsi = get_imsi(modem_path).unwrap_or_else(|e| {
+			eprintln!("error getting IMSI of {}: {}", modem_path, e);
+			None
+		});
+		send_mms(&SESS_CONN, modem_path, imsi.as_deref(), msg, atts)
+	}
+}
+
+/* a directory of text parts for mmsd to read, which has done so once SendMessage returns */
+struct TextDir(String);
+
+impl Drop for TextDir {
+	fn drop(&mut self) {
+		match std::fs::remove_dir_all(&self.0) {
+			Err(ref e) if e.kind() != std::io::ErrorKind::NotFound => eprintln!("could not remove {}: {}", self.0, e),
+			_ => (),
 		}
+	}
+}
 
-		let mut n_text_files_used = 0;
-
-		for item in &msg.contents {
-			attachments.push(
-				match item {
-					MessageItem::Attachment(ref att_id) => {
-						if let Some(att) = atts.get(att_id) {
-							if att.data.1 != 0 {
-								eprintln!("cannot send partial attachment!");
-								continue
-							}
-							(att.name.to_str().unwrap(), &att.mime_type, att.data.0.to_str().unwrap())
-						} else {
-							eprintln!("could not find attachment {} when sending MMS", att_id);
-							continue
-						}
-					},
-					MessageItem::Text(_) => {
-						let (ref filename, ref path) = &text_files[n_text_files_used];
-						n_text_files_used += 1;
-						(filename, "text/plain", path)
-					},
+/* send a message through the mmsd service on mms_conn's bus of the modem at modem_path, whose SIM
+has imsi */
+pub fn send_mms(mms_conn: &BusConn,
+	modem_path: &dbus::strings::Path,
+	imsi: Option<&str>,
+	msg: &MessageInfo,
+	atts: &HashMap<crate::types::AttachmentId, crate::types::Attachment>) -> Result<Option<String>, BackendError> {
+	let recip_strings = recipients(msg);
+	let recip_strs: Vec<_> = recip_strings.iter().map(|s| &s[..]).collect();
+	let service_path = mms_service_for(mms_conn, modem_path, imsi)?;
+
+	/* prepare attachments */
+	let mut attachments = Vec::<(&str, &str, &str)>::new(); /* name, mime type, disk path */
+
+	/* text parts go to mmsd as files in a directory of this message's own, removed on return */
+	let text_dir = {
+		use rand::Rng;
+		TextDir(format!("/tmp/vgmms/{:x}/", rand::thread_rng().gen::<u32>()))
+	};
+	let mut text_files = vec![];
+	for item in &msg.contents {
+		match item {
+			MessageItem::Text(t) => {
+				use std::io::Write;
+				if text_files.is_empty() {
+					std::fs::create_dir_all(&text_dir.0)
+						.map_err(|e| BackendError::Failed(format!("could not create {}: {}", text_dir.0, e)))?;
 				}
-			);
+				let filename = format!("{}.txt", text_files.len());
+				let path = format!("{}{}", text_dir.0, filename);
+				std::fs::File::create(&path)
+					.and_then(|mut f| f.write_all(t.as_bytes()))
+					.map_err(|e| BackendError::Failed(format!("could not write {}: {}", path, e)))?;
+				text_files.push((filename, path));
+			},
+			_ => (),
 		}
+	}
 
-		let smil = crate::smil::generate_smil(&attachments);
-
-		let conn = SESS_CONN.lock().unwrap();
+	let mut n_text_files_used = 0;
 
-		let mms_proxy = conn.with_proxy("org.ofono.mms", "/org/ofono/mms", Duration::from_millis(500));
-		use crate::mmsd_manager::OrgOfonoMmsManager;
-		let services = mms_proxy.get_services()?;
-		let path: &dbus::strings::Path = &services[0].0;
+	for item in &msg.contents {
+		attachments.push(
+			match item {
+				MessageItem::Attachment(ref att_id) => {
+					if let Some(att) = atts.get(att_id) {
+						if att.data.1 != 0 {
+							eprintln!("cannot send partial attachment!");
+							continue
+						}
+						/* mmsd takes them as D-Bus strings, which must be UTF-8 */
+						let name = att.name.to_str().ok_or_else(||
+							BackendError::Rejected(format!("attachment name {:?} is not UTF-8", att.name)))?;
+						let path = att.data.0.to_str().ok_or_else(||
+							BackendError::Rejected(format!("attachment path {} is not UTF-8", att.data.0.display())))?;
+						(name, &att.mime_type, path)
+					} else {
+						eprintln!("could not find attachment {} when sending MMS", att_id);
+						continue
+					}
+				},
+				MessageItem::Text(_) => {
+					let (ref filename, ref path) = &text_files[n_text_files_used];
+					n_text_files_used += 1;
+					(filename, "text/plain", path)
+				},
+			}
+		);
+	}
 
-		let service_proxy = conn.with_proxy("org.ofono.mms", path, Duration::from_millis(500));
+	let smil = crate::smil::generate_smil(&attachments);
 
-		use crate::mmsd_service::OrgOfonoMmsService;
-		let path = service_proxy.send_message(recip_strs, &smil, attachments)?;
-		Ok(Some(path.to_owned()))
-	}
+	use crate::mmsd_service::OrgOfonoMmsService;
+	let path = mms_conn.with(|conn| {
+		let service_proxy = conn.with_proxy("org.ofono.mms", service_path, Duration::from_millis(500));
+		service_proxy.send_message(recip_strs, &smil, attachments)
+	})?;
+	Ok(Some(path.to_string()))
 }
 
 /*pub fn start_send() -> impl futures::Sink<(MessageInfo,
@@ -311,44 +587,372 @@ pub fn send_message(/*sys_conn: &mut Connection, sess_conn: &mut Connection,*/
 	sink
 }*/
 
-use std::sync::{Arc, Mutex};
+use std::sync::Mutex;
+
+/* a bus to connect to */
+#[derive(Clone, Debug)]
+pub enum Bus {
+	System,
+	Session,
+	/* a bus at some address, e.g. a private one a test runs */
+	Address(String),
+}
+
+impl Bus {
+	pub fn connect(&self) -> Result<Connection, dbus::Error> {
+		match self {
+			Bus::System => Connection::new_system(),
+			Bus::Session => Connection::new_session(),
+			Bus::Address(address) => {
+				let mut channel = dbus::channel::Channel::open_private(address)?;
+				channel.register()?;
+				Ok(Connection::from(channel))
+			},
+		}
+	}
+
+	fn name(&self) -> &str {
+		match self {
+			Bus::System => "system",
+			Bus::Session => "session",
+			Bus::Address(address) => address,
+		}
+	}
+}
+
+/* a shared connection to a bus for method calls, made when first needed and remade if it breaks */
+pub struct BusConn {
+	conn: Mutex<Option<Connection>>,
+	bus: Bus,
+}
+
+impl BusConn {
+	pub fn new(bus: Bus) -> Self {
+		BusConn { conn: Mutex::new(None), bus }
+	}
+
+	pub fn with<T, F: FnOnce(&Connection) -> Result<T, dbus::Error>>(&self, f: F) -> Result<T, dbus::Error> {
+		let mut conn = self.conn.lock().unwrap();
+		if let Some(ref c) = *conn {
+			if !c.channel().is_connected() {
+				*conn = None;
+			}
+		}
+		if conn.is_none() {
+			*conn = Some(self.bus.connect()?);
+		}
+		let result = f(conn.as_ref().unwrap());
+		if let Err(ref e) = result {
+			if e.name() == Some("org.freedesktop.DBus.Error.Disconnected") {
+				*conn = None;
+			}
+		}
+		result
+	}
+}
 
 lazy_static! {
-	pub static ref SYS_CONN: Arc<Mutex<Connection>> = Arc::new(Mutex::new(Connection::new_system().expect("DBus connection failed")));
-	pub static ref SESS_CONN: Arc<Mutex<Connection>> = Arc::new(Mutex::new(Connection::new_session().expect("DBus connection failed")));
+	pub static ref SYS_CONN: BusConn = BusConn::new(Bus::System);
+	pub static ref SESS_CONN: BusConn = BusConn::new(Bus::Session);
 }
 
-pub fn start_recv() -> impl futures::Stream<Item=DbusNotification> {
-	let (mut sink, stream) = futures::channel::mpsc::channel(0);
+/* whether a service is running on the system bus */
+pub fn name_has_owner(name: &str) -> Result<bool, dbus::Error> {
+	SYS_CONN.with(|conn| {
+		let (has_owner,): (bool,) = conn.with_proxy("org.freedesktop.DBus", "/org/freedesktop/DBus", Duration::from_millis(500))
+			.method_call("org.freedesktop.DBus", "NameHasOwner", (name,))?;
+		Ok(has_owner)
+	})
+}
 
-	// open buses
-	let sys_conn = Connection::new_system().expect("DBus connection failed");
-	let sess_conn = Connection::new_session().expect("DBus connection failed");
+/* call `f` with the new owner of `name` (empty if it has none) whenever it changes. MatchRule has
+no field for arg0, so the rule is added by hand to have the bus send us only changes of `name`. */
+pub fn watch_name_owner<F>(conn: &Connection, name: &str, mut f: F) -> Result<(), dbus::Error>
+	where F: FnMut(&str) + Send + 'static {
+	let rule = MatchRule::new_signal("org.freedesktop.DBus", "NameOwnerChanged");
+	conn.add_match_no_cb(&format!("{},arg0='{}'", rule.match_str(), name))?;
+	let name = name.to_owned();
+	conn.start_receive(rule, Box::new(move |msg, _| {
+		if let (Some(changed), Some(_old_owner), Some(new_owner)) = msg.get3::<&str, &str, &str>() {
+			if changed == name {
+				f(new_owner);
+			}
+		}
+		true
+	}));
+	Ok(())
+}
 
-	let sms_recv_rule = MatchRule::new_signal("org.ofono.MessageManager", "IncomingMessage");
-	let mut mms_recv_rule = MatchRule::new_signal("org.ofono.mms.Service", "MessageAdded");
-	mms_recv_rule.eavesdrop = true;
+/* how long to wait before connecting to a bus again */
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
 
-	let mut sms_sink = sink.clone();
+/* subscribe to oFono's signals on the system bus */
+fn subscribe_system(conn: &Connection, sink: &NotifSink) -> Result<(), dbus::Error> {
+	use crate::ofono_manager::{OrgOfonoManagerModemAdded, OrgOfonoManagerModemRemoved};
+	use crate::ofono_simmanager::OrgOfonoSimManagerPropertyChanged;
+	use dbus::message::SignalArgs;
 
-	sys_conn.add_match(sms_recv_rule, move |_: (), _, msg| {
+	let sms_recv_rule = MatchRule::new_signal("org.ofono.MessageManager", "IncomingMessage");
+	/* oFono's message objects report the State of SMS we send, then go away once it is final */
+	let sms_state_rule = MatchRule::new_signal("org.ofono.Message", "PropertyChanged");
+	let sms_removed_rule = MatchRule::new_signal("org.ofono.MessageManager", "MessageRemoved");
+	let modem_added_rule = OrgOfonoManagerModemAdded::match_rule(None, None).static_clone();
+	let modem_removed_rule = OrgOfonoManagerModemRemoved::match_rule(None, None).static_clone();
+	let pin_rule = OrgOfonoSimManagerPropertyChanged::match_rule(None, None).static_clone();
+	/* any of these can be what sending was waiting for */
+	let modem_rules = ["org.ofono.Modem", "org.ofono.NetworkRegistration", "org.ofono.ConnectionContext"]
+		.iter().map(|iface| MatchRule::new_signal(*iface, "PropertyChanged")).collect::<Vec<_>>();
+
+	let sms_sink = sink.clone();
+	conn.add_match(sms_recv_rule, move |_: (), _, msg| {
 		match parse_sms_message(&msg) {
-			Ok(notif) => sms_sink.try_send(notif).unwrap(),
+			Ok(notif) => notify(&sms_sink, notif),
 			Err(e) => eprintln!("SMS notification parse error: {:?}", e),
 		};
 		true
-	}).expect("add_match failed");
+	})?;
+
+	let sms_state_sink = sink.clone();
+	conn.add_match(sms_state_rule, move |_: (), _, msg| {
+		match parse_sms_state(&msg) {
+			Ok(Some(notif)) => notify(&sms_state_sink, notif),
+			Ok(None) => (),
+			Err(e) => eprintln!("SMS state notification parse error: {:?}", e),
+		};
+		true
+	})?;
+
+	let sms_removed_sink = sink.clone();
+	conn.add_match(sms_removed_rule, move |_: (), _, msg| {
+		match msg.get1::<dbus::Path>() {
+			Some(path) => notify(&sms_removed_sink, MessageRemoved { path: path.to_string() }),
+			None => eprintln!("SMS removal notification parse error: {:?}", ParseError::BadArgs),
+		};
+		true
+	})?;
+
+	let modem_added_sink = sink.clone();
+	conn.add_match(modem_added_rule, move |added: OrgOfonoManagerModemAdded, _, _| {
+		notify(&modem_added_sink, ModemAdded { modem: added.path.to_string() });
+		true
+	})?;
+
+	let modem_removed_sink = sink.clone();
+	conn.add_match(modem_removed_rule, move |removed: OrgOfonoManagerModemRemoved, _, _| {
+		notify(&modem_removed_sink, ModemRemoved { modem: removed.path.to_string() });
+		true
+	})?;
+
+	let pin_sink = sink.clone();
+	conn.add_match(pin_rule, move |changed: OrgOfonoSimManagerPropertyChanged, _, msg| {
+		use dbus::arg::RefArg;
+		if changed.name == "PinRequired" {
+			match (msg.path(), changed.value.0.as_str()) {
+				(Some(modem), Some(pin_type)) => notify(&pin_sink, PinRequired {
+					modem: modem.to_string(),
+					pin_type: pin_type.to_owned(),
+				}),
+				_ => eprintln!("SIM property notification parse error: {:?}", ParseError::BadArgs),
+			}
+		} else if changed.name == "SubscriberNumbers" {
+			if let Some(modem) = msg.path() {
+				notify(&pin_sink, OwnNumberChanged { modem: modem.to_string() });
+			}
+		}
+		true
+	})?;
+
+	let ofono_owner_sink = sink.clone();
+	watch_name_owner(conn, "org.ofono", move |new_owner| {
+		notify(&ofono_owner_sink, if new_owner != "" { ModemServiceAppeared } else { ModemServiceVanished });
+	})?;
+
+	for rule in modem_rules {
+		let modem_sink = sink.clone();
+		conn.add_match(rule, move |_: (), _, msg| {
+			match modem_became_available(&msg) {
+				Ok(true) => notify(&modem_sink, ModemAvailable),
+				Ok(false) => (),
+				Err(e) => eprintln!("modem property notification parse error: {:?}", e),
+			};
+			true
+		})?;
+	}
+	Ok(())
+}
 
-	sess_conn.add_match(mms_recv_rule, move |_: (), _, msg| {
+/* subscribe to the signals of mmsd, or mmsd-tng, on the session bus */
+pub fn subscribe_session(conn: &Connection, sink: &NotifSink) -> Result<(), dbus::Error> {
+	use crate::mmsd_manager::{OrgOfonoMmsManagerServiceAdded, OrgOfonoMmsManagerServiceRemoved};
+	use dbus::message::SignalArgs;
+
+	let mut mms_recv_rule = MatchRule::new_signal("org.ofono.mms.Service", "MessageAdded");
+	mms_recv_rule.eavesdrop = true;
+	/* these fire for every mmsd message object; state ignores paths it isn't tracking */
+	let mut mms_status_rule = MatchRule::new_signal("org.ofono.mms.Message", "PropertyChanged");
+	mms_status_rule.eavesdrop = true;
+	let mut mms_removed_rule = MatchRule::new_signal("org.ofono.mms.Service", "MessageRemoved");
+	mms_removed_rule.eavesdrop = true;
+	let service_added_rule = OrgOfonoMmsManagerServiceAdded::match_rule(None, None).static_clone();
+	let service_removed_rule = OrgOfonoMmsManagerServiceRemoved::match_rule(None, None).static_clone();
+
+	let mms_sink = sink.clone();
+	conn.add_match(mms_recv_rule, move |_: (), _, msg| {
 		match parse_mms_message(&msg) {
-			Ok(notif) => sink.try_send(notif).unwrap(),
+			Ok(notif) => notify(&mms_sink, notif),
 			Err(e) => eprintln!("MMS notification parse error: {:?}", e),
 		};
 		true
-	}).expect("add_match failed");
+	})?;
+
+	let mms_status_sink = sink.clone();
+	conn.add_match(mms_status_rule, move |_: (), _, msg| {
+		match parse_mms_status(&msg) {
+			Ok(Some(notif)) => notify(&mms_status_sink, notif),
+			Ok(None) => (),
+			Err(e) => eprintln!("MMS status notification parse error: {:?}", e),
+		};
+		true
+	})?;
 
-	// loop and print messages as they come
-	std::thread::spawn(move || loop { sys_conn.process(Duration::from_millis(1000)).unwrap(); });
-	std::thread::spawn(move || loop { sess_conn.process(Duration::from_millis(1000)).unwrap(); });
+	let mms_removed_sink = sink.clone();
+	conn.add_match(mms_removed_rule, move |_: (), _, msg| {
+		match msg.get1::<dbus::Path>() {
+			Some(path) => notify(&mms_removed_sink, MessageRemoved { path: path.to_string() }),
+			None => eprintln!("MMS removal notification parse error: {:?}", ParseError::BadArgs),
+		};
+		true
+	})?;
+
+	let service_added_sink = sink.clone();
+	conn.add_match(service_added_rule, move |_: OrgOfonoMmsManagerServiceAdded, _, _| {
+		notify(&service_added_sink, MmsServiceAppeared);
+		true
+	})?;
+
+	let service_removed_sink = sink.clone();
+	conn.add_match(service_removed_rule, move |_: OrgOfonoMmsManagerServiceRemoved, _, _| {
+		notify(&service_removed_sink, MmsServiceVanished);
+		true
+	})?;
+
+	let owner_sink = sink.clone();
+	watch_name_owner(conn, "org.ofono.mms", move |new_owner| {
+		/* a new mmsd has no services until it announces them with ServiceAdded, which is when
+		there is something to fetch */
+		if new_owner == "" {
+			notify(&owner_sink, MmsServiceVanished);
+		}
+	})?;
+	Ok(())
+}
+
+/* keep a connection to a bus with our signals subscribed, connecting again whenever it is lost.
+anything missed while disconnected is caught up on by sending `resync` */
+pub fn supervise<F>(bus: Bus, subscribe: F, resync: Vec<Notification>, sink: NotifSink)
+	where F: Fn(&Connection, &NotifSink) -> Result<(), dbus::Error> + Send + 'static {
+	std::thread::spawn(move || {
+		let mut reconnecting = false;
+		loop {
+			if reconnecting {
+				std::thread::sleep(RECONNECT_DELAY);
+			}
+			let conn = match bus.connect().and_then(|conn| subscribe(&conn, &sink).map(|_| conn)) {
+				Ok(conn) => conn,
+				Err(e) => {
+					eprintln!("could not subscribe to the {} bus: {}", bus.name(), e);
+					reconnecting = true;
+					continue
+				},
+			};
+			if reconnecting {
+				println!("reconnected to the {} bus", bus.name());
+				for notif in &resync {
+					notify(&sink, notif.clone());
+				}
+			}
+			reconnecting = true;
+			while conn.channel().is_connected() {
+				if let Err(e) = conn.process(Duration::from_millis(1000)) {
+					eprintln!("error on the {} bus: {}", bus.name(), e);
+					break
+				}
+			}
+			eprintln!("lost connection to the {} bus", bus.name());
+		}
+	});
+}
+
+pub fn start_recv() -> impl futures::Stream<Item=Notification> {
+	/* unbounded, so that signals arriving faster than the UI takes them aren't dropped */
+	let (sink, stream) = futures::channel::mpsc::unbounded();
+	supervise(Bus::System, subscribe_system, vec![ModemServiceAppeared], sink.clone());
+	supervise(Bus::Session, subscribe_session, vec![MmsServiceAppeared], sink);
 	stream
 }
+
+/* oFono for SMS and modems, with mmsd for MMS */
+pub struct OfonoBackend;
+
+impl crate::backend::MessagingBackend for OfonoBackend {
+	fn start_recv(&self) -> crate::backend::NotifStream {
+		use futures::stream::StreamExt;
+		start_recv().boxed()
+	}
+
+	fn modems(&self) -> Result<Vec<String>, BackendError> {
+		Ok(get_modem_paths()?.iter().map(|path| path.to_string()).collect())
+	}
+
+	fn own_number(&self, modem: &str) -> Result<Option<String>, BackendError> {
+		Ok(get_my_number(&object_path(modem)?)?)
+	}
+
+	fn pin_required(&self, modem: &str) -> Result<(String, Option<u8>), BackendError> {
+		Ok(get_pin_required(&object_path(modem)?)?)
+	}
+
+	fn enter_pin(&self, modem: &str, pin_type: &str, pin: &str, new_pin: Option<&str>) -> Result<(), BackendError> {
+		Ok(enter_pin(&object_path(modem)?, pin_type, pin, new_pin)?)
+	}
+
+	/* oFono hands the delivery reports it receives to its history plugins rather than over
+	D-Bus, so messages sent through it stay Sent */
+	fn set_delivery_reports(&self, modem: &str, enabled: bool) -> Result<(), BackendError> {
+		Ok(set_delivery_reports(&object_path(modem)?, enabled)?)
+	}
+
+	fn send_message(&self, modem: &str, msg: &MessageInfo,
+		atts: &HashMap<crate::types::AttachmentId, crate::types::Attachment>) -> Result<Option<String>, BackendError> {
+		send_message(&object_path(modem)?, msg, atts)
+	}
+
+	fn stored_mms(&self, chat_numbers: &[String]) -> Result<Vec<StoredMms>, BackendError> {
+		Ok(get_stored_mms(&SESS_CONN, chat_numbers)?)
+	}
+}
+
+#[test]
+fn test_parse_retries() {
+	use dbus::arg::RefArg;
+	let mut dict: HashMap<String, u8> = HashMap::new();
+	dict.insert("pin".into(), 3);
+	dict.insert("puk".into(), 10);
+	let v = dbus::arg::Variant(Box::new(dict) as Box<dyn RefArg>);
+	let retries = parse_retries(&*v.0);
+	assert_eq!(retries.get("pin"), Some(&3));
+	assert_eq!(retries.get("puk"), Some(&10));
+	assert_eq!(retries.len(), 2);
+}
+
+#[test]
+fn test_match_mms_service() {
+	let services = vec!["/org/ofono/mms/310260000000001".to_owned(), "/org/ofono/mms/310260000000002".to_owned()];
+	assert_eq!(match_mms_service(&services, "/quectelqmi_0", Some("310260000000002")), Some(&*services[1]));
+	assert_eq!(match_mms_service(&services, "/quectelqmi_0", None), None);
+	assert_eq!(match_mms_service(&services[..1], "/quectelqmi_0", Some("310260000000009")), None);
+
+	let services = vec!["/org/ofono/mms/modemmanager".to_owned()];
+	assert_eq!(match_mms_service(&services, "/quectelqmi_0", Some("310260000000001")), Some(&*services[0]));
+	assert_eq!(match_mms_service(&[], "/quectelqmi_0", None), None);
+}
@@ -0,0 +1,323 @@
+use rusqlite::Connection;
+
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::db;
+use crate::types::*;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+	/* a folder holding index.html and copies of the attachments */
+	Html,
+	Text,
+	Json,
+}
+
+impl Format {
+	pub fn from_name(name: &str) -> Option<Format> {
+		match name {
+			"html" => Some(Format::Html),
+			"text" => Some(Format::Text),
+			"json" => Some(Format::Json),
+			_ => None,
+		}
+	}
+
+	pub fn name(&self) -> &'static str {
+		match self {
+			Format::Html => "html",
+			Format::Text => "text",
+			Format::Json => "json",
+		}
+	}
+
+	/* suffix for the default file name; html exports are folders and have none */
+	pub fn extension(&self) -> &'static str {
+		match self {
+			Format::Html => "",
+			Format::Text => ".txt",
+			Format::Json => ".json",
+		}
+	}
+}
+
+#[derive(Debug)]
+pub enum ExportError {
+	Db(rusqlite::Error),
+	Io(io::Error),
+}
+
+impl std::fmt::Display for ExportError {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self {
+			ExportError::Db(e) => write!(f, "could not read messages: {}", e),
+			ExportError::Io(e) => write!(f, "could not write export: {}", e),
+		}
+	}
+}
+
+impl From<rusqlite::Error> for ExportError {
+	fn from(e: rusqlite::Error) -> Self {
+		ExportError::Db(e)
+	}
+}
+
+impl From<io::Error> for ExportError {
+	fn from(e: io::Error) -> Self {
+		ExportError::Io(e)
+	}
+}
+
+/* a chat's whole history, oldest first, with the attachments it uses */
+pub struct History {
+	pub chat: Chat,
+	pub my_number: Number,
+	pub messages: Vec<(MessageId, MessageInfo)>,
+	pub attachments: HashMap<AttachmentId, Attachment>,
+}
+
+impl History {
+	pub fn load(conn: &Connection, chat: &Chat, my_number: Number) -> rusqlite::Result<History> {
+		let mut q = db::Query::new(conn)?;
+		let mut messages = vec![];
+		let mut before = None;
+		loop {
+			let mut page = q.chat_page(chat, before, 500)?;
+			match page.first() {
+				Some((_id, msg)) => before = Some(msg.order()),
+				None => break,
+			}
+			page.append(&mut messages);
+			messages = page;
+		}
+
+		let mut attachments = HashMap::new();
+		for (_id, msg) in &messages {
+			for item in &msg.contents {
+				if let MessageItem::Attachment(att_id) = item {
+					if let Some(att) = db::get_attachment(conn, *att_id)? {
+						attachments.insert(*att_id, att);
+					}
+				}
+			}
+		}
+		Ok(History { chat: chat.clone(), my_number, messages, attachments })
+	}
+}
+
+/* write the history to dest, which is a directory for Format::Html and a file otherwise */
+pub fn export(history: &History, format: Format, dest: &Path) -> Result<(), ExportError> {
+	match format {
+		Format::Html => write_html_folder(history, dest)?,
+		Format::Text => write_text(history, &mut io::BufWriter::new(std::fs::File::create(dest)?))?,
+		Format::Json => write_json(history, &mut io::BufWriter::new(std::fs::File::create(dest)?))?,
+	}
+	Ok(())
+}
+
+fn format_time(time: u64) -> String {
+	use chrono::offset::TimeZone;
+	match chrono::Local.timestamp_millis_opt(time as i64) {
+		chrono::offset::LocalResult::Single(time) => time.format("%Y-%m-%d %H:%M:%S").to_string(),
+		_ => format!("@{}", time),
+	}
+}
+
+/* name an attachment's copy is saved under, unique within the export */
+fn attachment_file_name(id: AttachmentId, att: &Attachment) -> String {
+	let name = att.name.to_string_lossy().replace('/', "_");
+	format!("{}-{}", id, name)
+}
+
+pub fn write_text<W: Write>(history: &History, w: &mut W) -> io::Result<()> {
+	writeln!(w, "Conversation with {}", history.chat.get_name(&history.my_number))?;
+	for (_id, msg) in &history.messages {
+		write!(w, "\n[{}] {}:", format_time(msg.time), msg.sender.to_string())?;
+		for item in &msg.contents {
+			match item {
+				MessageItem::Text(t) => {
+					/* indent continuation lines so multi-line messages stay grouped */
+					for line in t.lines() {
+						write!(w, "\n\t{}", line)?;
+					}
+				},
+				MessageItem::Attachment(att_id) => match history.attachments.get(att_id) {
+					Some(att) => write!(w, "\n\t[attachment {} ({})]", att.name.to_string_lossy(), att.mime_type)?,
+					None => write!(w, "\n\t[attachment {} not found]", att_id)?,
+				},
+			}
+		}
+		writeln!(w)?;
+	}
+	w.flush()
+}
+
+fn json_string(s: &str) -> String {
+	let mut out = String::with_capacity(s.len() + 2);
+	out.push('"');
+	for c in s.chars() {
+		match c {
+			'"' => out.push_str("\\\""),
+			'\\' => out.push_str("\\\\"),
+			'\n' => out.push_str("\\n"),
+			'\r' => out.push_str("\\r"),
+			'\t' => out.push_str("\\t"),
+			c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+			c => out.push(c),
+		}
+	}
+	out.push('"');
+	out
+}
+
+pub fn write_json<W: Write>(history: &History, w: &mut W) -> io::Result<()> {
+	let numbers = history.chat.numbers.iter()
+		.map(|n| json_string(&n.to_string()))
+		.collect::<Vec<_>>().join(", ");
+	writeln!(w, "{{")?;
+	writeln!(w, "\t\"chat\": [{}],", numbers)?;
+	writeln!(w, "\t\"my_number\": {},", json_string(&history.my_number.to_string()))?;
+	write!(w, "\t\"messages\": [")?;
+	for (i, (id, msg)) in history.messages.iter().enumerate() {
+		let contents = msg.contents.iter().map(|item| match item {
+			MessageItem::Text(t) => format!("{{\"text\": {}}}", json_string(t)),
+			MessageItem::Attachment(att_id) => match history.attachments.get(att_id) {
+				Some(att) => format!("{{\"attachment\": {{\"name\": {}, \"mime_type\": {}, \"size\": {}}}}}",
+					json_string(&att.name.to_string_lossy()), json_string(&att.mime_type), att.data.2),
+				None => "{\"attachment\": null}".into(),
+			},
+		}).collect::<Vec<_>>().join(", ");
+		write!(w, "{}\n\t\t{{\"id\": {}, \"sender\": {}, \"time\": {}, \"status\": {}, \"contents\": [{}]}}",
+			if i == 0 { "" } else { "," },
+			json_string(&hex::encode(&id[..])), json_string(&msg.sender.to_string()),
+			msg.time, json_string(msg.status.name()), contents)?;
+	}
+	writeln!(w, "\n\t]")?;
+	writeln!(w, "}}")?;
+	w.flush()
+}
+
+fn html_escape(s: &str) -> String {
+	s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn url_escape(s: &str) -> String {
+	let mut out = String::new();
+	for b in s.bytes() {
+		match b {
+			b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => out.push(b as char),
+			b => out.push_str(&format!("%{:02X}", b)),
+		}
+	}
+	out
+}
+
+/* write index.html into dir and copy attachments into dir/attachments, so the folder stands alone.
+attachments that cannot be read are left out rather than failing the export. */
+fn write_html_folder(history: &History, dir: &Path) -> io::Result<()> {
+	let att_dir: PathBuf = dir.join("attachments");
+	std::fs::create_dir_all(&att_dir)?;
+	let mut unavailable = HashSet::new();
+	for (id, att) in &history.attachments {
+		match att.with_data(|data| std::fs::write(att_dir.join(attachment_file_name(*id, att)), data)) {
+			Ok(written) => written?,
+			Err(e) => {
+				eprintln!("cannot read attachment {} ({}): {}", id, att.data.0.display(), e);
+				unavailable.insert(*id);
+			},
+		}
+	}
+	let mut w = io::BufWriter::new(std::fs::File::create(dir.join("index.html"))?);
+	write_html(history, &unavailable, &mut w)
+}
+
+/* attachments are linked from attachments/, except those in unavailable */
+pub fn write_html<W: Write>(history: &History, unavailable: &HashSet<AttachmentId>, w: &mut W) -> io::Result<()> {
+	let title = html_escape(&format!("Conversation with {}", history.chat.get_name(&history.my_number)));
+	writeln!(w, "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>", title)?;
+	writeln!(w, "<style>\n\
+		.msg {{ margin: 0.5em 0; padding: 0.3em 0.6em; border-radius: 0.5em; max-width: 70%; }}\n\
+		.received {{ background: #eee; }}\n\
+		.sent {{ background: #cdf; margin-left: auto; }}\n\
+		.meta {{ font-size: small; color: #666; }}\n\
+		.msg p {{ white-space: pre-wrap; margin: 0.2em 0; }}\n\
+		img {{ max-width: 100%; }}\n\
+		</style>\n</head>\n<body>\n<h1>{}</h1>", title)?;
+	for (_id, msg) in &history.messages {
+		let class = match msg.status {
+			MessageStatus::Received => "received",
+			_ => "sent",
+		};
+		writeln!(w, "<div class=\"msg {}\">", class)?;
+		writeln!(w, "<div class=\"meta\">{} {}</div>",
+			html_escape(&format_time(msg.time)), html_escape(&msg.sender.to_string()))?;
+		for item in &msg.contents {
+			match item {
+				MessageItem::Text(t) => writeln!(w, "<p>{}</p>", html_escape(t))?,
+				MessageItem::Attachment(att_id) => match history.attachments.get(att_id) {
+					Some(att) if unavailable.contains(att_id) => writeln!(w, "<p>[{} ({}) unavailable]</p>",
+						html_escape(&att.name.to_string_lossy()), html_escape(&att.mime_type))?,
+					Some(att) => {
+						let href = format!("attachments/{}", url_escape(&attachment_file_name(*att_id, att)));
+						let name = html_escape(&att.name.to_string_lossy());
+						if att.mime_type.starts_with("image/") {
+							writeln!(w, "<p><img src=\"{}\" alt=\"{}\"></p>", href, name)?
+						} else {
+							writeln!(w, "<p><a href=\"{}\">{}</a> ({})</p>", href, name, html_escape(&att.mime_type))?
+						}
+					},
+					None => writeln!(w, "<p>[attachment {} not found]</p>", att_id)?,
+				},
+			}
+		}
+		writeln!(w, "</div>")?;
+	}
+	writeln!(w, "</body>\n</html>")?;
+	w.flush()
+}
+
+#[test]
+fn test_export_formats() {
+	let chat = Chat { numbers: vec![Number::new(1), Number::new(2)] };
+	let mut attachments = HashMap::new();
+	attachments.insert(7, Attachment { name: "a b.png".into(), mime_type: "image/png".into(), data: ("/nonexistent".into(), 0, 3) });
+	let msg = |contents| MessageInfo {
+		sender: Number::new(2), chat: chat.numbers.clone(), time: 0, seq: 0, contents, status: MessageStatus::Received,
+	};
+	let history = History {
+		chat: chat.clone(),
+		my_number: Number::new(1),
+		messages: vec![
+			([1; 20], msg(vec![MessageItem::Text("<hi> \"there\"\nbye".into())])),
+			([2; 20], msg(vec![MessageItem::Attachment(7)])),
+		],
+		attachments,
+	};
+
+	let mut text = vec![];
+	write_text(&history, &mut text).unwrap();
+	let text = String::from_utf8(text).unwrap();
+	assert!(text.contains("\n\t<hi> \"there\"\n\tbye\n"));
+	assert!(text.contains("[attachment a b.png (image/png)]"));
+
+	let mut json = vec![];
+	write_json(&history, &mut json).unwrap();
+	let json = String::from_utf8(json).unwrap();
+	assert!(json.contains(r#"{"text": "<hi> \"there\"\nbye"}"#));
+	assert!(json.contains(r#""status": "received""#));
+
+	let mut html = vec![];
+	write_html(&history, &HashSet::new(), &mut html).unwrap();
+	let html = String::from_utf8(html).unwrap();
+	assert!(html.contains("<p>&lt;hi&gt; &quot;there&quot;\nbye</p>"));
+	assert!(html.contains("<img src=\"attachments/7-a%20b.png\" alt=\"a b.png\">"));
+
+	/* the attachment's file is gone, which the folder notes rather than failing */
+	let dir = std::env::temp_dir().join(format!("vgmms-export-test-{}", std::process::id()));
+	write_html_folder(&history, &dir).unwrap();
+	let index = std::fs::read_to_string(dir.join("index.html")).unwrap();
+	std::fs::remove_dir_all(&dir).unwrap();
+	assert!(index.contains("<p>[a b.png (image/png) unavailable]</p>"));
+}
@@ -14,8 +14,14 @@ use crate::types::*;
 pub struct InputBox {
 	pub file_paths: Vec<PathBuf>,
 	pub message: String,
-	pub on_send: Callback<Vec<DraftItem>>,
+	/* sends the own number to send from along with the message */
+	pub on_send: Callback<(Number, Vec<DraftItem>)>,
 	pub message_typed: bool,
+	/* own numbers that can be sent from; a selector is shown if there are several */
+	pub identities: Vec<Number>,
+	/* the number of the chat, which is sent from unless another is chosen */
+	pub default_from: Number,
+	pub from: Number,
 }
 
 #[derive(Clone, Debug)]
@@ -25,6 +31,7 @@ pub enum UiMessage {
 	ToggleFile,
 	AskForFile,
 	SetFiles(Vec<PathBuf>),
+	SetFrom(Number),
 	Clear,
 	Nop,
 }
@@ -33,11 +40,14 @@ impl Component for InputBox {
 	type Message = UiMessage;
 	type Properties = Self;
 
-	fn create(props: Self) -> Self {
+	fn create(mut props: Self) -> Self {
+		props.from = props.default_from;
 		props
 	}
 
 	fn change(&mut self, mut props: Self) -> UpdateAction<Self> {
+		/* keep the chosen number while showing the same chat */
+		props.from = if props.default_from == self.default_from { self.from } else { props.default_from };
 		/* preserve message/attachments if self.message_typed */
 		if self.message_typed {
 			std::mem::swap(&mut props.file_paths, &mut self.file_paths);
@@ -77,7 +87,7 @@ impl Component for InputBox {
 					};
 					items.push(DraftItem::Attachment(att));
 				}
-				self.on_send.send(items);
+				self.on_send.send((self.from, items));
 				self.message = String::new();
 				UpdateAction::Render
 			},
@@ -116,6 +126,10 @@ impl Component for InputBox {
 				self.file_paths = paths;
 				UpdateAction::Render
 			},
+			SetFrom(from) => {
+				self.from = from;
+				UpdateAction::None
+			},
 			Clear => {
 				self.file_paths.clear();
 				self.message.clear();
@@ -129,8 +143,29 @@ impl Component for InputBox {
 
 	fn view(&self) -> VNode<Self> {
 		let files_empty = self.file_paths.len() == 0;
+		let from_selector = if self.identities.len() > 1 {
+			let (identities, from) = (self.identities.clone(), self.from);
+			Some(gtk! {
+				<ComboBoxText tooltip_text="Send from"
+					on realize=|c| {
+						for n in &identities {
+							c.append(Some(&n.to_string()), &n.to_string());
+						}
+						c.set_active_id(Some(&from.to_string()));
+						UiMessage::Nop
+					}
+					on changed=|c| match c.get_active_id().and_then(|id| id.parse().ok()) {
+						Some(n) => UiMessage::SetFrom(Number::new(n)),
+						None => UiMessage::Nop,
+					}
+				/>
+			})
+		} else {
+			None
+		};
 		gtk! {
 			<GtkBox::new(Orientation::Horizontal, 0)>
+				{from_selector}
 				/*<Button label="" image="mail-attachment" always_show_image=true
 					on clicked=|_entry| UiMessage::AskForFile
 				/>*/
@@ -12,28 +12,74 @@ mod chat_log;
 mod file_chooser;
 mod input_box;
 mod new_chat;
+mod own_number;
+mod search;
 mod select_chat;
+mod sim_pin;
 mod window;
 
 /* logic */
+mod backend;
 mod new_custom;
 mod once;
+mod simulator;
 mod types;
 mod smil;
 mod state;
 
 /* persistence */
+mod android_backup;
 mod db;
+mod export;
+mod store;
 
 /* dbus interfaces */
 mod dbus;
 mod mmsd_manager;
 mod mmsd_service;
+mod modemmanager;
 mod ofono_manager;
 mod ofono_simmanager;
 
 use window::*;
 
+fn collect_garbage() -> i32 {
+	let mut conn = db::connect().expect("could not open database");
+	if let Err(e) = db::migrate(&mut conn) {
+		eprintln!("could not open database: {}", e);
+		return 1
+	}
+	match db::collect_garbage(&mut conn, &db::attachment_dir()) {
+		Ok(stats) => {
+			println!("removed {} unused attachments and {} unused files", stats.rows, stats.files);
+			0
+		},
+		Err(e) => {
+			eprintln!("error collecting garbage: {}", e);
+			1
+		},
+	}
+}
+
+/* the backend setting, first saving the one given by --backend=NAME if any */
+fn choose_backend(args: &[String]) -> String {
+	let chosen = args.iter().find_map(|a| a.strip_prefix("--backend="));
+	let result = db::connect().map_err(db::MigrationError::from).and_then(|mut conn| {
+		db::migrate(&mut conn)?;
+		if let Some(name) = chosen {
+			db::set_setting(&conn, db::SETTING_BACKEND, name)?;
+		}
+		Ok(db::get_setting(&conn, db::SETTING_BACKEND)?)
+	});
+	match result {
+		Ok(setting) => setting.unwrap_or_else(|| "auto".into()),
+		Err(e) => {
+			eprintln!("error reading backend setting: {}", e);
+			chosen.unwrap_or("auto").into()
+		},
+	}
+}
+
 fn main() {
 	use gio::prelude::ApplicationExtManual;
 	use gio::ApplicationExt;
@@ -41,10 +87,38 @@ fn main() {
 
 	let args = &*std::env::args().collect::<Vec<_>>();
 
-	/* receive notifications of new/updated SMS and MMS messages from DBus */
-	let notif_stream = dbus::start_recv();
+	/* development: pretend to be a phone, keeping data apart from the real history */
+	let simulate = args.iter().find_map(|a| match &**a {
+		"--simulate" => Some(None),
+		a => a.strip_prefix("--simulate=").map(Some),
+	});
+	if simulate.is_some() {
+		db::use_simulator_data();
+	}
+
+	/* maintenance: remove attachments nothing refers to any more, then exit */
+	if args.iter().any(|a| a == "--gc") {
+		std::process::exit(collect_garbage());
+	}
+
+	/* receive notifications of new/updated SMS and MMS messages from the backend */
+	use backend::MessagingBackend;
+	let backend: Box<dyn MessagingBackend> = match simulate {
+		Some(fixture_dir) => match simulator::SimulatedBackend::load(fixture_dir.map(std::path::Path::new)) {
+			Ok(simulator) => Box::new(simulator),
+			Err(e) => {
+				eprintln!("cannot start simulator: {}", e);
+				std::process::exit(1);
+			},
+		},
+		None => backend::choose(&choose_backend(args)),
+	};
+	/* the worker sending messages reports along with the backend */
+	let (results, results_stream) = futures::channel::mpsc::unbounded();
+	let notif_stream = futures::stream::select(backend.start_recv(), results_stream);
 	pretty_env_logger::init();
 	let (app, scope) = vgtk::start::<WindowModel>();
+	let _ = scope.try_send(window::UiMessage::Start(window::Handover::new(backend, results)));
 	let scope_ = scope.clone();
 	std::thread::spawn(
 		move || futures::executor::block_on(
@@ -55,6 +129,18 @@ fn main() {
 		}))
 	);
 
+	/* ask to unlock the SIM if it is locked, and for our number if it doesn't report it */
+	let _ = scope.try_send(window::UiMessage::CheckModems);
+
+	/* retry queued messages once their backoff has passed */
+	let scope_ = scope.clone();
+	std::thread::spawn(move || loop {
+		std::thread::sleep(std::time::Duration::from_secs(15));
+		if scope_.try_send(window::UiMessage::FlushOutbox).is_err() {
+			break
+		}
+	});
+
 	/* add options */
 	app.add_main_option("daemon", glib::Char::new('d').unwrap(), glib::OptionFlags::NONE, glib::OptionArg::None,
 		"run in the background without opening a window",
@@ -94,5 +180,10 @@ fn main() {
 	if !app.get_is_remote() {
 		app.hold();
 	}
-	std::process::exit(app.run(args));
+	/* GApplication rejects options it wasn't told of, so leave out those handled above */
+	let gtk_args = args.iter()
+		.filter(|a| !a.starts_with("--backend=") && !a.starts_with("--simulate"))
+		.cloned()
+		.collect::<Vec<_>>();
+	std::process::exit(app.run(&gtk_args));
 }
@@ -0,0 +1,623 @@
+use dbus::arg::{RefArg, Variant};
+use dbus::blocking::Connection;
+use dbus::message::MatchRule;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::backend::{Error as BackendError, NotifSink, Notification, StoredMms, notify};
+use crate::backend::Notification::*;
+use crate::dbus::{Bus, BusConn, ParseError, object_path, watch_name_owner};
+use crate::types::{Attachment, AttachmentId, MessageInfo, MessageStatus};
+
+const MM_SERVICE: &str = "org.freedesktop.ModemManager1";
+const MM_PATH: &str = "/org/freedesktop/ModemManager1";
+const MODEM_IFACE: &str = "org.freedesktop.ModemManager1.Modem";
+const MESSAGING_IFACE: &str = "org.freedesktop.ModemManager1.Modem.Messaging";
+const SIM_IFACE: &str = "org.freedesktop.ModemManager1.Sim";
+const SMS_IFACE: &str = "org.freedesktop.ModemManager1.Sms";
+
+const TIMEOUT: Duration = Duration::from_millis(5000);
+/* Sms.Send only returns once the SMS has gone out */
+const SEND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/* MMSmsState values */
+const SMS_STATE_RECEIVED: u64 = 3;
+const SMS_STATE_SENDING: u64 = 4;
+const SMS_STATE_SENT: u64 = 5;
+/* MMModemState values at which messages can go out */
+const MODEM_STATE_REGISTERED: i64 = 8;
+const MODEM_STATE_CONNECTED: i64 = 11;
+
+/* oFono's names for ModemManager's MMModemLock values, so the rest of vgmms knows only one set.
+an unknown lock is taken to be none. */
+const LOCK_NAMES: [&str; 17] = ["none", "none", "pin", "pin2", "puk", "puk2", "service", "servicepuk",
+	"network", "networkpuk", "phone", "corp", "corppuk", "firstphone", "firstphonepuk", "netsub", "netsubpuk"];
+
+fn lock_name(lock: u64) -> &'static str {
+	LOCK_NAMES.get(lock as usize).cloned().unwrap_or("none")
+}
+
+type Props = HashMap<String, Variant<Box<dyn RefArg + 'static>>>;
+
+/* the status of a message we sent, from properties of its Sms object */
+fn sms_status(props: &Props) -> Option<MessageStatus> {
+	/* MMSmsDeliveryState: completed below 0x20, permanent errors from 0x40 to 0x7f */
+	match props.get("DeliveryState").and_then(|v| v.0.as_u64()) {
+		Some(0x00..=0x1f) => return Some(MessageStatus::Delivered),
+		Some(0x40..=0x7f) => return Some(MessageStatus::Failed),
+		_ => (),
+	}
+	match props.get("State").and_then(|v| v.0.as_u64()) {
+		Some(SMS_STATE_SENDING) => Some(MessageStatus::Sending),
+		Some(SMS_STATE_SENT) => Some(MessageStatus::Sent),
+		_ => None,
+	}
+}
+
+/* ModemManager's timestamps may end in an hours-only UTC offset, which RFC 3339 lacks */
+fn rfc3339_timestamp(timestamp: &str) -> String {
+	let b = timestamp.as_bytes();
+	let n = b.len();
+	if timestamp.contains('T') && n >= 3 && (b[n-3] == b'+' || b[n-3] == b'-') && b[n-2..].iter().all(u8::is_ascii_digit) {
+		format!("{}:00", timestamp)
+	} else {
+		timestamp.to_owned()
+	}
+}
+
+fn parse_sms(props: &Props, modem: &str, sms_path: &str) -> Result<Notification, ParseError> {
+	let get_str = |name: &str| props.get(name).and_then(|v| v.0.as_str());
+	let sender = get_str("Number").ok_or(ParseError::MissingSender)?;
+	let date = get_str("Timestamp").ok_or(ParseError::MissingSentTime)?;
+	Ok(SmsReceived {
+		/* data SMS have no text */
+		message: get_str("Text").unwrap_or("").to_owned(),
+		date: rfc3339_timestamp(date),
+		sender: sender.to_owned(),
+		modem: modem.to_owned(),
+		/* deleted by taken_sms once saved */
+		stored: Some(sms_path.to_owned()),
+	})
+}
+
+fn get_property<T: for<'b> dbus::arg::Get<'b> + 'static>(conn: &BusConn, path: &str, iface: &str, name: &str) -> Result<T, dbus::Error> {
+	use dbus::blocking::stdintf::org_freedesktop_dbus::Properties;
+	let path = object_path(path)?;
+	conn.with(|c| c.with_proxy(MM_SERVICE, &path, TIMEOUT).get(iface, name))
+}
+
+/* read a received SMS. it stays on the modem until taken_sms, so it isn't lost if saving it fails. */
+fn read_sms(conn: &BusConn, modem: &str, sms_path: &str) -> Result<Notification, dbus::Error> {
+	use dbus::blocking::stdintf::org_freedesktop_dbus::Properties;
+	let props = conn.with(|c| c.with_proxy(MM_SERVICE, sms_path, TIMEOUT).get_all(SMS_IFACE))?;
+	parse_sms(&props, modem, sms_path).map_err(|e| {
+		dbus::Error::new_custom("org.freedesktop.DBus.Error.InvalidArgs", &format!("SMS {} parse error: {:?}", sms_path, e))
+	})
+}
+
+/* subscribe to ModemManager's signals. received SMS are read through conn; pending holds ones
+still arriving, with their modems. */
+fn subscribe(bus_conn: &Connection, sink: &NotifSink, conn: &Arc<BusConn>, pending: &Arc<Mutex<HashMap<String, String>>>) -> Result<(), dbus::Error> {
+	use dbus::blocking::stdintf::org_freedesktop_dbus::{ObjectManagerInterfacesAdded, ObjectManagerInterfacesRemoved,
+		PropertiesPropertiesChanged};
+	use dbus::message::SignalArgs;
+
+	let added_rule = ObjectManagerInterfacesAdded::match_rule(None, None).static_clone();
+	let removed_rule = ObjectManagerInterfacesRemoved::match_rule(None, None).static_clone();
+	let sms_added_rule = MatchRule::new_signal(MESSAGING_IFACE, "Added");
+	let sms_deleted_rule = MatchRule::new_signal(MESSAGING_IFACE, "Deleted");
+	let changed_rule = PropertiesPropertiesChanged::match_rule(None, None).static_clone();
+
+	let added_sink = sink.clone();
+	bus_conn.add_match(added_rule, move |added: ObjectManagerInterfacesAdded, _, _| {
+		if added.interfaces.contains_key(MODEM_IFACE) {
+			notify(&added_sink, ModemAdded { modem: added.object.to_string() });
+		}
+		true
+	})?;
+
+	let removed_sink = sink.clone();
+	bus_conn.add_match(removed_rule, move |removed: ObjectManagerInterfacesRemoved, _, _| {
+		if removed.interfaces.iter().any(|i| i == MODEM_IFACE) {
+			notify(&removed_sink, ModemRemoved { modem: removed.object.to_string() });
+		}
+		true
+	})?;
+
+	let owner_sink = sink.clone();
+	watch_name_owner(bus_conn, MM_SERVICE, move |new_owner| {
+		notify(&owner_sink, if new_owner != "" { ModemServiceAppeared } else { ModemServiceVanished });
+	})?;
+
+	let (sms_sink, sms_conn, sms_pending) = (sink.clone(), conn.clone(), pending.clone());
+	bus_conn.add_match(sms_added_rule, move |_: (), _, msg| {
+		let (modem, (sms_path, received)) = match (msg.path(), msg.get2::<dbus::Path, bool>()) {
+			(Some(modem), (Some(sms_path), Some(received))) => (modem.to_string(), (sms_path.to_string(), received)),
+			_ => {
+				eprintln!("SMS notification parse error: {:?}", ParseError::BadArgs);
+				return true
+			},
+		};
+		if !received {
+			return true
+		}
+		/* a multi-part SMS is added once its first part arrives, and is complete once it is received */
+		match get_property::<u32>(&sms_conn, &sms_path, SMS_IFACE, "State") {
+			Ok(state) if state as u64 == SMS_STATE_RECEIVED => match read_sms(&sms_conn, &modem, &sms_path) {
+				Ok(notif) => notify(&sms_sink, notif),
+				Err(e) => eprintln!("error reading SMS {}: {}", sms_path, e),
+			},
+			Ok(_) => {
+				sms_pending.lock().unwrap().insert(sms_path, modem);
+			},
+			Err(e) => eprintln!("error reading SMS {}: {}", sms_path, e),
+		}
+		true
+	})?;
+
+	let deleted_sink = sink.clone();
+	bus_conn.add_match(sms_deleted_rule, move |_: (), _, msg| {
+		match msg.get1::<dbus::Path>() {
+			Some(path) => notify(&deleted_sink, MessageRemoved { path: path.to_string() }),
+			None => eprintln!("SMS removal notification parse error: {:?}", ParseError::BadArgs),
+		};
+		true
+	})?;
+
+	let (changed_sink, changed_conn, changed_pending) = (sink.clone(), conn.clone(), pending.clone());
+	bus_conn.add_match(changed_rule, move |changed: PropertiesPropertiesChanged, _, msg| {
+		let path = match msg.path() {
+			Some(path) => path.to_string(),
+			None => return true,
+		};
+		let props = &changed.changed_properties;
+		match &*changed.interface_name {
+			MODEM_IFACE => {
+				match props.get("State").and_then(|v| v.0.as_i64()) {
+					Some(MODEM_STATE_REGISTERED) | Some(MODEM_STATE_CONNECTED) => notify(&changed_sink, ModemAvailable),
+					_ => (),
+				}
+				if props.contains_key("OwnNumbers") {
+					notify(&changed_sink, OwnNumberChanged { modem: path.clone() });
+				}
+				if let Some(lock) = props.get("UnlockRequired").and_then(|v| v.0.as_u64()) {
+					notify(&changed_sink, PinRequired { modem: path, pin_type: lock_name(lock).to_owned() });
+				}
+			},
+			SMS_IFACE => {
+				let state = props.get("State").and_then(|v| v.0.as_u64());
+				if state == Some(SMS_STATE_RECEIVED) {
+					let modem = changed_pending.lock().unwrap().remove(&path);
+					if let Some(modem) = modem {
+						match read_sms(&changed_conn, &modem, &path) {
+							Ok(notif) => notify(&changed_sink, notif),
+							Err(e) => eprintln!("error reading SMS {}: {}", path, e),
+						}
+					}
+				} else if let Some(status) = sms_status(props) {
+					notify(&changed_sink, StatusUpdate { path, status });
+				}
+			},
+			_ => (),
+		}
+		true
+	})?;
+	Ok(())
+}
+
+/* ModemManager for SMS and modems, with mmsd-tng for MMS */
+pub struct ModemManagerBackend {
+	/* the bus ModemManager is on: the system bus, except in tests */
+	bus: Bus,
+	/* the bus mmsd-tng is on: the session bus, except in tests */
+	mms_bus: Bus,
+	mms_conn: BusConn,
+	conn: Arc<BusConn>,
+	/* Sms.Send only returns once the SMS has gone out, so it is called over this connection
+	so that conn stays free for the window meanwhile */
+	send_conn: Arc<BusConn>,
+	delivery_reports: AtomicBool,
+	/* for reporting SMS as sent once Sms.Send returns */
+	sink: Mutex<Option<NotifSink>>,
+}
+
+impl ModemManagerBackend {
+	pub fn new(bus: Bus, mms_bus: Bus) -> Self {
+		ModemManagerBackend {
+			conn: Arc::new(BusConn::new(bus.clone())),
+			send_conn: Arc::new(BusConn::new(bus.clone())),
+			bus,
+			mms_conn: BusConn::new(mms_bus.clone()),
+			mms_bus,
+			delivery_reports: AtomicBool::new(false),
+			sink: Mutex::new(None),
+		}
+	}
+
+	fn sim_path(&self, modem_path: &str) -> Result<dbus::Path<'static>, dbus::Error> {
+		get_property(&self.conn, modem_path, MODEM_IFACE, "Sim")
+	}
+}
+
+impl crate::backend::MessagingBackend for ModemManagerBackend {
+	fn start_recv(&self) -> crate::backend::NotifStream {
+		use futures::stream::StreamExt;
+		let (sink, stream) = futures::channel::mpsc::unbounded();
+		*self.sink.lock().unwrap() = Some(sink.clone());
+		let conn = self.conn.clone();
+		let pending = Arc::new(Mutex::new(HashMap::new()));
+		crate::dbus::supervise(self.bus.clone(), move |bus_conn, sink| subscribe(bus_conn, sink, &conn, &pending),
+			vec![ModemServiceAppeared], sink.clone());
+		crate::dbus::supervise(self.mms_bus.clone(), crate::dbus::subscribe_session, vec![MmsServiceAppeared], sink);
+		stream.boxed()
+	}
+
+	fn modems(&self) -> Result<Vec<String>, BackendError> {
+		use dbus::blocking::stdintf::org_freedesktop_dbus::ObjectManager;
+		let objects = self.conn.with(|c| c.with_proxy(MM_SERVICE, MM_PATH, TIMEOUT).get_managed_objects())?;
+		let mut paths = objects.into_iter()
+			.filter(|(_path, ifaces)| ifaces.contains_key(MODEM_IFACE))
+			.map(|(path, _ifaces)| path.to_string())
+			.collect::<Vec<_>>();
+		paths.sort();
+		Ok(paths)
+	}
+
+	fn own_number(&self, modem_path: &str) -> Result<Option<String>, BackendError> {
+		let nums: Vec<String> = get_property(&self.conn, modem_path, MODEM_IFACE, "OwnNumbers")?;
+		Ok(if let [num] = &*nums {
+			Some(num.to_owned())
+		} else {
+			eprintln!("expected 1 own number, found {}", nums.len());
+			None
+		})
+	}
+
+	fn pin_required(&self, modem_path: &str) -> Result<(String, Option<u8>), BackendError> {
+		let lock: u32 = get_property(&self.conn, modem_path, MODEM_IFACE, "UnlockRequired")?;
+		let retries: HashMap<u32, u32> = get_property(&self.conn, modem_path, MODEM_IFACE, "UnlockRetries")?;
+		Ok((lock_name(lock as u64).to_owned(), retries.get(&lock).map(|&n| n as u8)))
+	}
+
+	fn enter_pin(&self, modem_path: &str, pin_type: &str, pin: &str, new_pin: Option<&str>) -> Result<(), BackendError> {
+		let sim_path = self.sim_path(modem_path)?;
+		Ok(self.conn.with(|c| {
+			let sim_proxy = c.with_proxy(MM_SERVICE, &sim_path, TIMEOUT);
+			match (pin_type, new_pin) {
+				("pin", None) => sim_proxy.method_call(SIM_IFACE, "SendPin", (pin,)),
+				("puk", Some(new_pin)) => sim_proxy.method_call(SIM_IFACE, "SendPuk", (pin, new_pin)),
+				_ => Err(dbus::Error::new_custom("org.freedesktop.ModemManager1.Error.Core.Unsupported",
+					&format!("cannot unlock {} with ModemManager", pin_type))),
+			}
+		})?)
+	}
+
+	fn set_delivery_reports(&self, _modem_path: &str, enabled: bool) -> Result<(), BackendError> {
+		/* ModemManager takes this with each SMS */
+		self.delivery_reports.store(enabled, Ordering::Relaxed);
+		Ok(())
+	}
+
+	fn send_message(&self, modem_path: &str, msg: &MessageInfo,
+		atts: &HashMap<AttachmentId, Attachment>) -> Result<Option<String>, BackendError> {
+		let modem_path = object_path(modem_path)?;
+		let (recip, text) = match crate::dbus::as_sms(msg) {
+			Some(sms) => sms,
+			None => {
+				let imsi = self.sim_path(&modem_path)
+					.and_then(|sim| get_property::<String>(&self.conn, &sim, SIM_IFACE, "Imsi"))
+					.map_err(|e| eprintln!("error getting IMSI of {}: {}", modem_path, e)).ok();
+				return crate::dbus::send_mms(&self.mms_conn, &modem_path, imsi.as_deref(), msg, atts)
+			},
+		};
+		let mut props: HashMap<&str, Variant<Box<dyn RefArg>>> = HashMap::new();
+		props.insert("number", Variant(Box::new(recip)));
+		props.insert("text", Variant(Box::new(text.to_owned())));
+		if self.delivery_reports.load(Ordering::Relaxed) {
+			props.insert("delivery-report-request", Variant(Box::new(true)));
+		}
+		let (path,): (dbus::Path<'static>,) = self.conn.with(|c| {
+			c.with_proxy(MM_SERVICE, &modem_path, TIMEOUT).method_call(MESSAGING_IFACE, "Create", (props,))
+		})?;
+		let sent = self.send_conn.with(|c| {
+			c.with_proxy(MM_SERVICE, &path, SEND_TIMEOUT).method_call::<(), _, _, _>(SMS_IFACE, "Send", ())
+		});
+		if let Err(e) = sent {
+			/* the outbox creates another when it tries again */
+			if let Err(e) = self.conn.with(|c| c.with_proxy(MM_SERVICE, &modem_path, TIMEOUT)
+				.method_call::<(), _, _, _>(MESSAGING_IFACE, "Delete", (path.clone(),))) {
+				eprintln!("error deleting unsent SMS {}: {}", path, e);
+			}
+			return Err(e.into())
+		}
+		/* the Sms object may have reported this before we returned its path */
+		if let Some(ref sink) = *self.sink.lock().unwrap() {
+			notify(sink, StatusUpdate { path: path.to_string(), status: MessageStatus::Sent });
+		}
+		Ok(Some(path.to_string()))
+	}
+
+	fn stored_sms(&self, modem_path: &str) -> Result<Vec<Notification>, BackendError> {
+		let modem = object_path(modem_path)?;
+		let (paths,): (Vec<dbus::Path<'static>>,) = self.conn.with(|c| {
+			c.with_proxy(MM_SERVICE, &modem, TIMEOUT).method_call(MESSAGING_IFACE, "List", ())
+		})?;
+		let mut received = vec![];
+		for path in paths {
+			if get_property::<u32>(&self.conn, &path, SMS_IFACE, "State")? as u64 == SMS_STATE_RECEIVED {
+				match read_sms(&self.conn, modem_path, &path) {
+					Ok(notif) => received.push(notif),
+					Err(e) => eprintln!("error reading SMS {}: {}", path, e),
+				}
+			}
+		}
+		Ok(received)
+	}
+
+	fn taken_sms(&self, modem_path: &str, stored: &str) -> Result<(), BackendError> {
+		/* delete it, so the modem's storage doesn't fill up */
+		let (modem, sms) = (object_path(modem_path)?, object_path(stored)?);
+		Ok(self.conn.with(|c| c.with_proxy(MM_SERVICE, &modem, TIMEOUT)
+			.method_call::<(), _, _, _>(MESSAGING_IFACE, "Delete", (sms,)))?)
+	}
+
+	fn stored_mms(&self, chat_numbers: &[String]) -> Result<Vec<StoredMms>, BackendError> {
+		Ok(crate::dbus::get_stored_mms(&self.mms_conn, chat_numbers)?)
+	}
+}
+
+#[test]
+fn test_parse_sms() {
+	let mut props: Props = HashMap::new();
+	props.insert("Number".into(), Variant(Box::new("+15555550123".to_owned())));
+	props.insert("Text".into(), Variant(Box::new("hi".to_owned())));
+	props.insert("Timestamp".into(), Variant(Box::new("2021-03-07T12:34:56+01".to_owned())));
+	match parse_sms(&props, "/org/freedesktop/ModemManager1/Modem/0", "/org/freedesktop/ModemManager1/SMS/3").unwrap() {
+		SmsReceived { message, date, sender, modem, stored } => {
+			assert_eq!(message, "hi");
+			assert_eq!(date, "2021-03-07T12:34:56+01:00");
+			assert_eq!(sender, "+15555550123");
+			assert_eq!(modem, "/org/freedesktop/ModemManager1/Modem/0");
+			assert_eq!(stored.as_deref(), Some("/org/freedesktop/ModemManager1/SMS/3"));
+		},
+		other => panic!("unexpected {:?}", other),
+	}
+	assert_eq!(rfc3339_timestamp("2021-03-07T12:34:56+01:00"), "2021-03-07T12:34:56+01:00");
+
+	props.insert("State".into(), Variant(Box::new(SMS_STATE_SENT as u32)));
+	assert_eq!(sms_status(&props), Some(MessageStatus::Sent));
+	props.insert("DeliveryState".into(), Variant(Box::new(0x100u32)));
+	assert_eq!(sms_status(&props), Some(MessageStatus::Sent));
+	props.insert("DeliveryState".into(), Variant(Box::new(0u32)));
+	assert_eq!(sms_status(&props), Some(MessageStatus::Delivered));
+}
+
+/* talk to a scripted ModemManager and mmsd-tng on a private bus, which also stands in for the session bus */
+#[test]
+fn test_modemmanager_backend() {
+	use crate::backend::MessagingBackend;
+	use dbus::Message;
+	use futures::stream::StreamExt;
+	use std::io::BufRead;
+
+	/* how long to wait for anything the backend should report */
+	const DEADLINE: Duration = Duration::from_secs(10);
+
+	/* stops the bus even if the test fails */
+	struct Daemon(std::process::Child);
+	impl Drop for Daemon {
+		fn drop(&mut self) {
+			let _ = self.0.kill();
+			let _ = self.0.wait();
+		}
+	}
+	let mut daemon = match std::process::Command::new("dbus-daemon")
+		.args(&["--session", "--nofork", "--print-address=1"])
+		.stdout(std::process::Stdio::piped())
+		.stderr(std::process::Stdio::null())
+		.spawn() {
+		Ok(daemon) => Daemon(daemon),
+		Err(e) => {
+			eprintln!("skipping: cannot run dbus-daemon: {}", e);
+			return
+		},
+	};
+	let mut address = String::new();
+	std::io::BufReader::new(daemon.0.stdout.take().unwrap()).read_line(&mut address).unwrap();
+	let bus = Bus::Address(address.trim().to_owned());
+
+	const MODEM: &str = "/org/freedesktop/ModemManager1/Modem/0";
+	const SENT_SMS: &str = "/org/freedesktop/ModemManager1/SMS/0";
+	const RECEIVED_SMS: &str = "/org/freedesktop/ModemManager1/SMS/1";
+	const FAILING_SMS: &str = "/org/freedesktop/ModemManager1/SMS/2";
+	const SIM: &str = "/org/freedesktop/ModemManager1/SIM/0";
+	/* mmsd-tng serves whatever modem there is through one service */
+	const MMS_MANAGER: &str = "/org/ofono/mms";
+	const MMS_SERVICE: &str = "/org/ofono/mms/modemmanager";
+	const SENT_MMS: &str = "/org/ofono/mms/modemmanager/0123456789abcdef0123456789abcdef01234567";
+	const STORED_MMS: &str = "/org/ofono/mms/modemmanager/89abcdef0123456789abcdef0123456789abcdef";
+	let stand_in = bus.connect().unwrap();
+	stand_in.request_name(MM_SERVICE, false, true, false).unwrap();
+	stand_in.request_name("org.ofono.mms", false, true, false).unwrap();
+	/* the recipients of the MMS sent, and the text mmsd read from its text part */
+	let mms_sent = Arc::new(Mutex::new(None));
+	let mms_sent_ = mms_sent.clone();
+	let created = Arc::new(Mutex::new(None));
+	let deleted = Arc::new(Mutex::new(vec![]));
+	let (created_, deleted_) = (created.clone(), deleted.clone());
+	let stop = Arc::new(AtomicBool::new(false));
+	let stop_ = stop.clone();
+	let stand_in_thread = std::thread::spawn(move || {
+		let channel = stand_in.channel();
+		let mut announced = std::time::Instant::now();
+		while !stop_.load(Ordering::Relaxed) {
+			/* announce an incoming SMS until it is taken */
+			if deleted_.lock().unwrap().is_empty() && announced.elapsed() > Duration::from_millis(100) {
+				let signal = Message::new_signal(MODEM, MESSAGING_IFACE, "Added").unwrap()
+					.append2(dbus::Path::from(RECEIVED_SMS), true);
+				channel.send(signal).unwrap();
+				announced = std::time::Instant::now();
+			}
+			let msg = match channel.blocking_pop_message(Duration::from_millis(50)).unwrap() {
+				Some(msg) => msg,
+				None => continue,
+			};
+			if msg.msg_type() != dbus::MessageType::MethodCall {
+				continue
+			}
+			let path = msg.path().unwrap().to_string();
+			let reply = match (&*msg.member().unwrap(), &*path) {
+				("GetManagedObjects", MM_PATH) => {
+					let mut ifaces: HashMap<String, Props> = HashMap::new();
+					ifaces.insert(MODEM_IFACE.into(), HashMap::new());
+					ifaces.insert(MESSAGING_IFACE.into(), HashMap::new());
+					let mut objects = HashMap::new();
+					objects.insert(dbus::Path::from(MODEM), ifaces);
+					msg.method_return().append1(objects)
+				},
+				("Get", MODEM) => match msg.read2::<&str, &str>().unwrap().1 {
+					"Sim" => msg.method_return().append1(Variant(dbus::Path::from(SIM))),
+					"OwnNumbers" => msg.method_return().append1(Variant(vec!["+15555550100".to_owned()])),
+					"UnlockRequired" => msg.method_return().append1(Variant(4u32)),
+					"UnlockRetries" => {
+						let mut retries = HashMap::new();
+						retries.insert(4u32, 9u32);
+						msg.method_return().append1(Variant(retries))
+					},
+					name => panic!("unexpected property {}", name),
+				},
+				("Get", RECEIVED_SMS) => msg.method_return().append1(Variant(SMS_STATE_RECEIVED as u32)),
+				("GetAll", RECEIVED_SMS) => {
+					let mut props: Props = HashMap::new();
+					props.insert("Number".into(), Variant(Box::new("+15555550123".to_owned())));
+					props.insert("Text".into(), Variant(Box::new("hello".to_owned())));
+					props.insert("Timestamp".into(), Variant(Box::new("2021-03-07T12:34:56+01".to_owned())));
+					props.insert("State".into(), Variant(Box::new(SMS_STATE_RECEIVED as u32)));
+					msg.method_return().append1(props)
+				},
+				("Create", MODEM) => {
+					let props: Props = msg.read1().unwrap();
+					let get = |name: &str| props.get(name).and_then(|v| v.0.as_str()).map(|s| s.to_owned());
+					/* an SMS saying "fail" is one the network won't take */
+					let path = if get("text").as_deref() == Some("fail") { FAILING_SMS } else { SENT_SMS };
+					*created_.lock().unwrap() = Some((get("number"), get("text")));
+					msg.method_return().append1(dbus::Path::from(path))
+				},
+				("Send", SENT_SMS) => msg.method_return(),
+				("Send", FAILING_SMS) => msg.error(&dbus::strings::ErrorName::from("org.freedesktop.ModemManager1.Error.Core.Failed"),
+					&std::ffi::CString::new("not registered").unwrap()),
+				("Get", SIM) => msg.method_return().append1(Variant("310260000000001".to_owned())),
+				("GetServices", MMS_MANAGER) => {
+					let services: Vec<(dbus::Path, Props)> = vec![(dbus::Path::from(MMS_SERVICE), HashMap::new())];
+					msg.method_return().append1(services)
+				},
+				("SendMessage", MMS_SERVICE) => {
+					let (recipients, _smil, attachments): (Vec<String>, String, Vec<(String, String, String)>) = msg.read3().unwrap();
+					let text = attachments.iter().find(|(_name, mime_type, _path)| mime_type == "text/plain")
+						.map(|(_name, _mime_type, path)| std::fs::read_to_string(path).unwrap());
+					*mms_sent_.lock().unwrap() = Some((recipients, text, attachments));
+					msg.method_return().append1(dbus::Path::from(SENT_MMS))
+				},
+				("GetMessages", MMS_SERVICE) => {
+					let mut props: Props = HashMap::new();
+					props.insert("Status".into(), Variant(Box::new("received".to_owned())));
+					props.insert("Sender".into(), Variant(Box::new("+15555550123".to_owned())));
+					props.insert("Date".into(), Variant(Box::new("2021-03-07T12:34:56+01:00".to_owned())));
+					props.insert("Recipients".into(), Variant(Box::new(vec!["+15555550100".to_owned()])));
+					props.insert("Attachments".into(), Variant(Box::new(
+						vec![("cat.png".to_owned(), "image/png".to_owned(), "/nonexistent/cat.png".to_owned(), 0u64, 4u64)])));
+					let messages: Vec<(dbus::Path, Props)> = vec![(dbus::Path::from(STORED_MMS), props)];
+					msg.method_return().append1(messages)
+				},
+				("Delete", MODEM) => {
+					deleted_.lock().unwrap().push(msg.read1::<dbus::Path>().unwrap().to_string());
+					msg.method_return()
+				},
+				(member, path) => panic!("unexpected call {} on {}", member, path),
+			};
+			channel.send(reply).unwrap();
+		}
+	});
+
+	let backend = ModemManagerBackend::new(bus.clone(), bus);
+	/* notifications are passed on to a channel that can be waited on with a deadline */
+	let (notif_sink, notifs) = std::sync::mpsc::channel();
+	let stream = backend.start_recv();
+	std::thread::spawn(move || futures::executor::block_on(stream.for_each(move |notif| {
+		let _ = notif_sink.send(notif);
+		futures::future::ready(())
+	})));
+	assert_eq!(backend.modems().unwrap(), vec![MODEM.to_owned()]);
+	assert_eq!(backend.own_number(MODEM).unwrap(), Some("+15555550100".to_owned()));
+	assert_eq!(backend.pin_required(MODEM).unwrap(), ("puk".to_owned(), Some(9)));
+
+	let msg = MessageInfo {
+		sender: crate::types::Number::new(15555550100),
+		chat: vec![crate::types::Number::new(15555550100), crate::types::Number::new(15555550123)],
+		time: 0,
+		seq: 0,
+		contents: vec![crate::types::MessageItem::Text("hi there".into())],
+		status: MessageStatus::Draft,
+	};
+	let sent = backend.send_message(MODEM, &msg, &HashMap::new()).unwrap();
+	assert_eq!(sent.as_deref(), Some(SENT_SMS));
+	assert_eq!(*created.lock().unwrap(), Some((Some("15555550123".to_owned()), Some("hi there".to_owned()))));
+
+	/* the announced SMS arrives, and the one we sent is reported sent */
+	let (mut received, mut reported_sent) = (None, false);
+	let deadline = std::time::Instant::now() + DEADLINE;
+	while !(received.is_some() && reported_sent) {
+		let left = deadline.saturating_duration_since(std::time::Instant::now());
+		match notifs.recv_timeout(left) {
+			Ok(SmsReceived { message, sender, modem, stored, .. }) => {
+				assert_eq!((&*message, &*sender, &*modem), ("hello", "+15555550123", MODEM));
+				/* it stays on the modem until saved */
+				assert!(deleted.lock().unwrap().is_empty());
+				received = stored;
+			},
+			Ok(StatusUpdate { path, status: MessageStatus::Sent }) if path == SENT_SMS => reported_sent = true,
+			Ok(_) => (),
+			Err(std::sync::mpsc::RecvTimeoutError::Timeout) => panic!("timed out waiting for {}",
+				if received.is_none() { "the announced SMS" } else { "the sent SMS to be reported sent" }),
+			Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => panic!("notification stream ended"),
+		}
+	}
+	backend.taken_sms(MODEM, &received.unwrap()).unwrap();
+	assert_eq!(deleted.lock().unwrap()[0], RECEIVED_SMS);
+
+	/* a failed send is reported as an error for the outbox to retry, and its SMS is deleted */
+	let failing = MessageInfo { contents: vec![crate::types::MessageItem::Text("fail".into())], ..msg };
+	assert!(backend.send_message(MODEM, &failing, &HashMap::new()).is_err());
+	assert_eq!(deleted.lock().unwrap().last().map(|p| &**p), Some(FAILING_SMS));
+
+	/* a message to several people goes to mmsd-tng as an MMS, whose text part is cleaned up after */
+	let group = MessageInfo {
+		chat: vec![crate::types::Number::new(15555550100), crate::types::Number::new(15555550123),
+			crate::types::Number::new(15555550124)],
+		contents: vec![crate::types::MessageItem::Text("hi all".into())],
+		..failing
+	};
+	let sent = backend.send_message(MODEM, &group, &HashMap::new()).unwrap();
+	assert_eq!(sent.as_deref(), Some(SENT_MMS));
+	let (recipients, text, attachments) = mms_sent.lock().unwrap().take().unwrap();
+	assert_eq!(recipients, vec!["15555550123".to_owned(), "15555550124".to_owned()]);
+	assert_eq!(text.as_deref(), Some("hi all"));
+	assert!(attachments.iter().all(|(_name, _mime_type, path)| !std::path::Path::new(path).exists()));
+
+	/* and the MMS mmsd-tng stored are fetched from it */
+	let stored = backend.stored_mms(&[]).unwrap();
+	assert_eq!(stored.len(), 1);
+	assert_eq!((&*stored[0].path, stored[0].sent_status), (STORED_MMS, None));
+	match &stored[0].received {
+		Some(MmsReceived { sender, recipients, attachments, .. }) => {
+			assert_eq!((&**sender, &**recipients), ("+15555550123", &["+15555550100".to_owned()][..]));
+			assert_eq!(attachments[0].mime_type, "image/png");
+		},
+		other => panic!("unexpected stored MMS {:?}", other),
+	}
+
+	stop.store(true, Ordering::Relaxed);
+	stand_in_thread.join().unwrap();
+}
@@ -35,7 +35,7 @@ pub enum UiMessage {
 }
 
 /* find an ancestor of a widget with the given type */
-fn find_ancestor<W: glib::IsA<Widget>, A: glib::IsA<Widget>>(w: &W) -> Option<A> {
+pub fn find_ancestor<W: glib::IsA<Widget>, A: glib::IsA<Widget>>(w: &W) -> Option<A> {
 	use glib::object::Cast;
 	let mut w: Widget = w.clone().upcast();
 	let mut count = 10;
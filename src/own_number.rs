@@ -0,0 +1,115 @@
+use vgtk::lib::gtk::{*, Box as GtkBox};
+use vgtk::{gtk, Component, UpdateAction, VNode};
+
+use std::default::Default;
+use std::sync::{Arc, Mutex};
+
+use crate::types::*;
+
+/* asks for our own number and home country, for SIMs that don't report them */
+#[derive(Clone, Default)]
+pub struct OwnNumberDialog {
+	pub number: String,
+	pub country: String,
+	/* why setting what was entered last time failed, if this is another try */
+	pub error: Option<String>,
+	/* (number, country) as entered, read when the dialog is accepted */
+	pub shared: Arc<Mutex<(String, String)>>,
+}
+
+#[derive(Clone, Debug)]
+pub enum UiMessage {
+	NumberChanged(String),
+	CountryChanged(String),
+	Nop,
+}
+
+impl OwnNumberDialog {
+	fn valid(&self) -> bool {
+		match self.country.trim().to_uppercase().parse::<Country>() {
+			Ok(country) => Number::normalize(self.number.trim(), country).is_some(),
+			Err(_) => false,
+		}
+	}
+
+	fn share(&self) {
+		*self.shared.lock().unwrap() = (self.number.clone(), self.country.clone());
+	}
+}
+
+impl Component for OwnNumberDialog {
+	type Message = UiMessage;
+	type Properties = Self;
+
+	fn create(props: Self) -> Self {
+		props.share();
+		props
+	}
+
+	fn change(&mut self, props: Self) -> UpdateAction<Self> {
+		*self = props;
+		self.share();
+		UpdateAction::Render
+	}
+
+	fn update(&mut self, msg: Self::Message) -> UpdateAction<Self> {
+		use UiMessage::*;
+		match msg {
+			NumberChanged(number) => self.number = number,
+			CountryChanged(country) => self.country = country,
+			Nop => return UpdateAction::None,
+		}
+		self.share();
+		UpdateAction::Render
+	}
+
+	fn view(&self) -> VNode<Self> {
+		use vgtk::ext::WindowExtHelpers;
+		use vgtk::ext::GridExtHelpers;
+		use crate::new_chat::find_ancestor;
+		let valid = self.valid();
+		gtk! {
+			/* our own buttons, so OK can be insensitive until the number is valid */
+			<Dialog::with_buttons(Some("Own number"), vgtk::current_window().as_ref(),
+				DialogFlags::MODAL | DialogFlags::DESTROY_WITH_PARENT,
+				&[])
+			>
+				<GtkBox::new(Orientation::Vertical, 6)>
+					<Label text="Your SIM doesn't say what its phone number is. Enter it to send and receive messages."
+						line_wrap=true xalign=0.0 />
+					{
+						if let Some(error) = self.error.clone() { Some(gtk! {
+							<Label text=error line_wrap=true xalign=0.0 />
+						}) } else { None }
+					}
+					<Grid row_spacing=6 column_spacing=6>
+						<Label text="Phone number" Grid::left=0 Grid::top=0 xalign=1.0 />
+						<Entry Grid::left=1 Grid::top=0
+							text=self.number.clone()
+							input_purpose=InputPurpose::Phone
+							on changed=|entry| UiMessage::NumberChanged(entry.get_text().to_string())
+							on realize=|entry| { entry.grab_focus(); UiMessage::Nop }
+						/>
+						<Label text="Country code" Grid::left=0 Grid::top=1 xalign=1.0 />
+						<Entry Grid::left=1 Grid::top=1
+							text=self.country.clone()
+							placeholder_text="e.g. US"
+							width_chars=4
+							on changed=|entry| UiMessage::CountryChanged(entry.get_text().to_string())
+						/>
+					</Grid>
+					<Label text={if valid || self.number.is_empty() { "" } else { "Not a phone number of that country" }}
+						xalign=0.0 />
+					<GtkBox::new(Orientation::Horizontal, 0) homogeneous=true >
+						<Button label="_Cancel" use_underline=true
+							on clicked=|w| { find_ancestor::<_, Dialog>(w).unwrap().response(ResponseType::Cancel); UiMessage::Nop }
+						/>
+						<Button label="_OK" use_underline=true sensitive=valid
+							on clicked=|w| { find_ancestor::<_, Dialog>(w).unwrap().response(ResponseType::Accept); UiMessage::Nop }
+						/>
+					</GtkBox>
+				</GtkBox>
+			</Dialog>
+		}
+	}
+}
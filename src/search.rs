@@ -0,0 +1,159 @@
+use vgtk::lib::{glib, gtk::{*, Box as GtkBox}};
+use vgtk::{gtk, Component, UpdateAction, VNode};
+
+use std::default::Default;
+use std::sync::{Arc, Mutex, RwLock};
+
+use crate::db::SearchHit;
+use crate::types::*;
+
+#[derive(Clone, Default)]
+pub struct SearchDialog {
+	pub state: Arc<RwLock<VgmmsState>>,
+	/* the chat open when the dialog was opened, which the search may be limited to */
+	pub current_chat: Option<Chat>,
+	pub only_current_chat: bool,
+	pub query: String,
+	/* shared with the row activation handler, which is only connected once */
+	pub hits: Arc<Mutex<Vec<SearchHit>>>,
+	/* the chat and message of the chosen hit */
+	pub selected_shared: Arc<Mutex<Option<(Vec<Number>, MessageId)>>>,
+}
+
+#[derive(Clone, Debug)]
+pub enum UiMessage {
+	QueryChanged(String),
+	OnlyCurrentChat(bool),
+	Nop,
+}
+
+impl SearchDialog {
+	fn run_search(&mut self) {
+		let state = self.state.read().unwrap();
+		let chat_filter = if self.only_current_chat { self.current_chat.as_ref() } else { None };
+		*self.hits.lock().unwrap() = state.search(&self.query, chat_filter);
+	}
+}
+
+impl Component for SearchDialog {
+	type Message = UiMessage;
+	type Properties = Self;
+
+	fn create(props: Self) -> Self {
+		props
+	}
+
+	fn change(&mut self, props: Self) -> UpdateAction<Self> {
+		*self = props;
+		UpdateAction::Render
+	}
+
+	fn update(&mut self, msg: Self::Message) -> UpdateAction<Self> {
+		use UiMessage::*;
+		match msg {
+			QueryChanged(query) => {
+				self.query = query;
+				self.run_search();
+				UpdateAction::Render
+			},
+			OnlyCurrentChat(only) => {
+				self.only_current_chat = only;
+				self.run_search();
+				UpdateAction::Render
+			},
+			Nop => {
+				UpdateAction::None
+			},
+		}
+	}
+
+	fn view(&self) -> VNode<Self> {
+		fn set_expand_fill<P: glib::IsA<Widget>>(w: &P) {
+			if let Some(p) = w.get_parent() {
+				use glib::object::Cast;
+				p.downcast_ref::<GtkBox>()
+					.expect("not gtkbox")
+					.set_child_packing(w, true, true, 0, PackType::Start);
+			}
+		}
+		fn create_hit_row(hit: &SearchHit, my_number: &Number) -> VNode<SearchDialog> {
+			use chrono::offset::TimeZone;
+			let time = if let chrono::offset::LocalResult::Single(time) = chrono::Local.timestamp_millis_opt(hit.time as i64) {
+				time.format("%Y-%m-%d %k:%M").to_string()
+			} else {
+				format!("@{}", hit.time)
+			};
+			let name = Chat { numbers: hit.chat.clone() }.get_name(my_number);
+			let mut label_markup = "<b>".to_owned();
+			label_markup.push_str(&glib::markup_escape_text(&name));
+			label_markup.push_str("</b> ");
+			label_markup.push_str(&glib::markup_escape_text(&time));
+			label_markup.push_str("\n	<small>");
+			label_markup.push_str(&glib::markup_escape_text(&hit.snippet));
+			label_markup.push_str("</small>");
+			gtk! {
+				<ListBoxRow activatable=true>
+					<Label text=label_markup
+						use_markup=true
+						xalign=0.0
+						ellipsize=pango::EllipsizeMode::End
+					/>
+				</ListBoxRow>
+			}
+		}
+		let hits = self.hits.clone();
+		let selected_shared = self.selected_shared.clone();
+		let state = self.state.read().unwrap();
+		let hit_widgets = self.hits.lock().unwrap().iter()
+			.map(|hit| create_hit_row(hit, &state.own_number_of(&Chat { numbers: hit.chat.clone() })))
+			.collect::<Vec<_>>();
+		let has_current_chat = self.current_chat.is_some();
+		use vgtk::ext::WindowExtHelpers;
+		gtk! {
+			<Dialog::with_buttons(Some("Search messages"), vgtk::current_window().as_ref(),
+				DialogFlags::MODAL | DialogFlags::DESTROY_WITH_PARENT,
+				&[("_Cancel", ResponseType::Cancel)])
+				default_height=300
+			>
+				<GtkBox::new(Orientation::Vertical, 0)
+					on parent_set=|w, _old| { set_expand_fill(w); UiMessage::Nop }
+				>
+					<SearchEntry
+						text=self.query.clone()
+						on search_changed=|entry| {
+							let text = entry.get_text().to_string();
+							UiMessage::QueryChanged(text)
+						}
+						on realize=|entry| { entry.grab_focus(); UiMessage::Nop }
+					/>
+					{
+						if has_current_chat { Some(gtk! {
+							<CheckButton label="Only this chat"
+								active=self.only_current_chat
+								on toggled=|b| UiMessage::OnlyCurrentChat(b.get_active())
+							/>
+						}) } else { None }
+					}
+					<ScrolledWindow GtkBox::fill=true GtkBox::expand=true>
+						<ListBox
+							on row_activated=|_box, row| {
+								if let Some(hit) = hits.lock().unwrap().get(row.get_index() as usize) {
+									*selected_shared.lock().unwrap() = Some((hit.chat.clone(), hit.id));
+									use glib::object::Cast;
+									if let Some(dialog) = row.get_toplevel() {
+										if let Some(dialog) = dialog.downcast_ref::<Dialog>() {
+											dialog.response(ResponseType::Accept);
+										}
+									}
+								}
+								UiMessage::Nop
+							}
+						>
+						{hit_widgets.into_iter()}
+						</ListBox>
+					</ScrolledWindow>
+				</GtkBox>
+			</Dialog>
+		}
+	}
+}
@@ -8,16 +8,28 @@ use crate::types::*;
 
 #[derive(Clone, Default)]
 pub struct SelectChat {
-	pub my_number: Number,
-	pub chats_summaries: Vec<(Chat, String)>,
+	/* our own numbers; with several, chats can be shown for one of them at a time */
+	pub identities: Vec<Number>,
+	/* each chat with the own number it belongs to and its summary */
+	pub chats_summaries: Vec<(Chat, Number, String)>,
 	pub on_select: Callback<Vec<Number>>,
 	pub on_new_chat: Callback<()>,
 	pub numbers: Vec<Number>,
+	/* the own number to show chats of, or None for all */
+	pub filter: Option<Number>,
+}
+
+impl SelectChat {
+	fn shown_chats(&self) -> impl Iterator<Item=&(Chat, Number, String)> {
+		let filter = self.filter;
+		self.chats_summaries.iter().filter(move |(_c, own, _summary)| filter.map_or(true, |f| f == *own))
+	}
 }
 
 #[derive(Clone, Debug)]
 pub enum UiMessage {
 	SelectionChanged(usize),
+	SetFilter(Option<Number>),
 	NewChat,
 	Nop,
 }
@@ -31,7 +43,9 @@ impl Component for SelectChat {
 	}
 
 	fn change(&mut self, props: Self) -> UpdateAction<Self> {
+		let filter = self.filter;
 		*self = props;
+		self.filter = filter;
 		UpdateAction::Render
 	}
 
@@ -39,8 +53,8 @@ impl Component for SelectChat {
 		use UiMessage::*;
 		match msg {
 			SelectionChanged(chat_idx) => {
-				let nums = match self.chats_summaries.iter().nth(chat_idx) {
-					Some((c, _summary)) => c.numbers.clone(),
+				let nums = match self.shown_chats().nth(chat_idx) {
+					Some((c, _own, _summary)) => c.numbers.clone(),
 					None => {
 						eprintln!("selected chat could not be found!");
 						return UpdateAction::Render
@@ -50,6 +64,10 @@ impl Component for SelectChat {
 				self.on_select.send(self.numbers.clone());
 				UpdateAction::None
 			},
+			SetFilter(filter) => {
+				self.filter = filter;
+				UpdateAction::Render
+			},
 			NewChat => {
 				self.on_new_chat.send(());
 				UpdateAction::None
@@ -88,17 +106,36 @@ impl Component for SelectChat {
 				</ListBoxRow>
 			}
 		}
+		let filter_selector = if self.identities.len() > 1 {
+			let (identities, filter) = (self.identities.clone(), self.filter);
+			Some(gtk! {
+				<ComboBoxText tooltip_text="Show chats of"
+					on realize=|c| {
+						c.append(Some(""), "All numbers");
+						for n in &identities {
+							c.append(Some(&n.to_string()), &n.to_string());
+						}
+						c.set_active_id(Some(&filter.map(|n| n.to_string()).unwrap_or_default()));
+						UiMessage::Nop
+					}
+					on changed=|c| UiMessage::SetFilter(c.get_active_id().and_then(|id| id.parse().ok()).map(Number::new))
+				/>
+			})
+		} else {
+			None
+		};
 		gtk! {
 			<GtkBox::new(Orientation::Vertical, 0)
 				on parent_set=|w, _old| { set_expand_fill(w); UiMessage::Nop }>
 				<GtkBox::new(Orientation::Horizontal, 0)>
 					<Button::from_icon_name(Some("add"), IconSize::Menu) on clicked=|_| UiMessage::NewChat />
+					{ filter_selector }
 				</GtkBox>
 				{
-					let chat_widgets = self.chats_summaries.iter().map(
-						|(c, desc)| create_chat_row(c, &desc, &self.my_number)
-					);
-					if self.chats_summaries.len() > 0 { gtk! {
+					let chat_widgets = self.shown_chats().map(
+						|(c, own, desc)| create_chat_row(c, &desc, own)
+					).collect::<Vec<_>>();
+					if chat_widgets.len() > 0 { gtk! {
 						<ScrolledWindow GtkBox::fill=true GtkBox::expand=true>
 							<ListBox
 								on row_activated=|_box, row| UiMessage::SelectionChanged(row.get_index() as usize)
@@ -119,8 +156,8 @@ impl Component for SelectChat {
 
 #[derive(Clone, Default)]
 pub struct SelectChatDialog {
-	pub my_number: Number,
-	pub chats_summaries: Vec<(Chat, String)>,
+	pub identities: Vec<Number>,
+	pub chats_summaries: Vec<(Chat, Number, String)>,
 	pub numbers_shared: Arc<Mutex<Vec<Number>>>,
 	pub on_new_chat: Callback<()>,
 	pub numbers: Vec<Number>,
@@ -169,7 +206,7 @@ impl Component for SelectChatDialog {
 				default_height=300
 			>
 				<@SelectChat
-					my_number=self.my_number.clone()
+					identities=self.identities.clone()
 					chats_summaries=self.chats_summaries.clone()
 					on select=|nums| {UiMessageDialog::Selected(nums)}
 					on new_chat=|_| {UiMessageDialog::NewChat}
@@ -0,0 +1,105 @@
+use vgtk::lib::gtk::{*, Box as GtkBox};
+use vgtk::{gtk, Component, UpdateAction, VNode};
+
+use std::default::Default;
+use std::sync::{Arc, Mutex};
+
+/* asks for the PIN, or the PUK and a new PIN, that the SIM of a modem is locked with */
+#[derive(Clone, Default)]
+pub struct SimPinDialog {
+	pub modem: String,
+	/* oFono's name of what is needed, e.g. "pin" or "puk" */
+	pub pin_type: String,
+	pub retries: Option<u8>,
+	/* why the last attempt failed, if this is another */
+	pub error: Option<String>,
+	/* (pin or puk, new pin) as entered, read when the dialog is accepted */
+	pub shared: Arc<Mutex<(String, String)>>,
+}
+
+#[derive(Clone, Debug)]
+pub enum UiMessage {
+	PinChanged(String),
+	NewPinChanged(String),
+	Nop,
+}
+
+/* whether a PinRequired value asks for a PUK, which unblocks a PIN by setting a new one */
+pub fn is_puk(pin_type: &str) -> bool {
+	pin_type.ends_with("puk")
+}
+
+impl Component for SimPinDialog {
+	type Message = UiMessage;
+	type Properties = Self;
+
+	fn create(props: Self) -> Self {
+		props
+	}
+
+	fn change(&mut self, props: Self) -> UpdateAction<Self> {
+		*self = props;
+		UpdateAction::Render
+	}
+
+	fn update(&mut self, msg: Self::Message) -> UpdateAction<Self> {
+		use UiMessage::*;
+		let mut shared = self.shared.lock().unwrap();
+		match msg {
+			PinChanged(pin) => shared.0 = pin,
+			NewPinChanged(pin) => shared.1 = pin,
+			Nop => (),
+		}
+		UpdateAction::None
+	}
+
+	fn view(&self) -> VNode<Self> {
+		use vgtk::ext::WindowExtHelpers;
+		use vgtk::ext::GridExtHelpers;
+		let puk = is_puk(&self.pin_type);
+		let mut prompt = format!("The SIM of modem {} is locked. Enter its {}.", self.modem, self.pin_type.to_uppercase());
+		if let Some(n) = self.retries {
+			prompt.push_str(&format!(" {} attempt{} left.", n, if n == 1 { "" } else { "s" }));
+		}
+		let code_label = if puk { "PUK" } else { "PIN" };
+		gtk! {
+			<Dialog::with_buttons(Some("Unlock SIM"), vgtk::current_window().as_ref(),
+				DialogFlags::MODAL | DialogFlags::DESTROY_WITH_PARENT,
+				&[("_Cancel", ResponseType::Cancel),
+				("_Unlock", ResponseType::Accept)])
+			>
+				<GtkBox::new(Orientation::Vertical, 6)>
+					{
+						if let Some(error) = self.error.clone() { Some(gtk! {
+							<Label text=error line_wrap=true xalign=0.0 />
+						}) } else { None }
+					}
+					<Label text=prompt line_wrap=true xalign=0.0 />
+					<Grid row_spacing=6 column_spacing=6>
+						<Label text=code_label Grid::left=0 Grid::top=0 xalign=1.0 />
+						<Entry Grid::left=1 Grid::top=0
+							visibility=false
+							input_purpose=InputPurpose::Pin
+							on changed=|entry| UiMessage::PinChanged(entry.get_text().to_string())
+							on realize=|entry| { entry.grab_focus(); UiMessage::Nop }
+						/>
+						{
+							if puk { Some(gtk! {
+								<Label text="New PIN" Grid::left=0 Grid::top=1 xalign=1.0 />
+							}) } else { None }
+						}
+						{
+							if puk { Some(gtk! {
+								<Entry Grid::left=1 Grid::top=1
+									visibility=false
+									input_purpose=InputPurpose::Pin
+									on changed=|entry| UiMessage::NewPinChanged(entry.get_text().to_string())
+								/>
+							}) } else { None }
+						}
+					</Grid>
+				</GtkBox>
+			</Dialog>
+		}
+	}
+}
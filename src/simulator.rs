@@ -0,0 +1,255 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::backend::{Error, ModemId, NotifSink, Notification, ReceivedAttachment, StoredMms, notify};
+use crate::backend::Notification::*;
+use crate::types::{Attachment, AttachmentId, MessageInfo, MessageItem, MessageStatus};
+
+/* the one modem of the pretend phone, and the number of its SIM */
+pub const MODEM_PATH: &str = "/vgmms/simulator/modem";
+pub const OWN_NUMBER: &str = "+15555550100";
+
+/* how long a sent message spends in each status */
+const STATUS_DELAY: Duration = Duration::from_millis(1000);
+
+/* what runs when no fixture directory is given */
+const DEFAULT_SCRIPT: &str = "\
+2	+15555550123	hi! this is the vgmms simulator.
+3	+15555550123	messages you send go through Sending to Sent. ones whose text contains \"fail\" fail instead.
+";
+
+/* an incoming message of a script: an MMS if it has attachments, else an SMS */
+#[derive(Debug, PartialEq)]
+pub struct Scripted {
+	/* since the previous message arrived */
+	pub delay: Duration,
+	pub sender: String,
+	pub text: String,
+	pub attachments: Vec<PathBuf>,
+}
+
+/**
+  parse a script of incoming messages, one per line:
+
+	SECONDS<tab>SENDER<tab>TEXT[<tab>ATTACHMENT]...
+
+  where attachments are file names relative to dir. blank lines and lines starting with #
+  are ignored.
+*/
+pub fn parse_script(script: &str, dir: &Path) -> Result<Vec<Scripted>, String> {
+	let mut messages = vec![];
+	for (i, line) in script.lines().enumerate() {
+		if line.trim().is_empty() || line.starts_with('#') {
+			continue
+		}
+		let mut fields = line.split('\t');
+		let (delay, sender, text) = match (fields.next(), fields.next(), fields.next()) {
+			(Some(delay), Some(sender), Some(text)) => (delay, sender, text),
+			_ => return Err(format!("line {}: expected seconds, sender and text separated by tabs", i + 1)),
+		};
+		let delay = delay.trim().parse::<f64>()
+			.map_err(|e| format!("line {}: bad delay {:?}: {}", i + 1, delay, e))?;
+		messages.push(Scripted {
+			delay: Duration::from_millis((delay * 1000.0) as u64),
+			sender: sender.to_owned(),
+			text: text.to_owned(),
+			attachments: fields.map(|name| dir.join(name)).collect(),
+		});
+	}
+	Ok(messages)
+}
+
+/* the notification announcing a scripted message as it arrives */
+fn arrival(msg: &Scripted) -> std::io::Result<Notification> {
+	use rand::Rng;
+	let date = chrono::Local::now().to_rfc3339();
+	if msg.attachments.is_empty() {
+		return Ok(SmsReceived {
+			message: msg.text.clone(),
+			date,
+			sender: msg.sender.clone(),
+			modem: MODEM_PATH.into(),
+			stored: None,
+		})
+	}
+	let id: [u8; 20] = rand::thread_rng().gen();
+	let mut attachments = vec![];
+	if !msg.text.is_empty() {
+		/* like mmsd, hand over the text as a file */
+		let dir = std::env::temp_dir().join("vgmms-simulator");
+		std::fs::create_dir_all(&dir)?;
+		let path = dir.join(format!("{}.txt", hex::encode(&id[..])));
+		std::fs::write(&path, &msg.text)?;
+		attachments.push(ReceivedAttachment {
+			name: "text.txt".into(),
+			mime_type: "text/plain;charset=utf-8".into(),
+			disk_path: path,
+			start: 0,
+			len: msg.text.len() as u64,
+		});
+	}
+	for path in &msg.attachments {
+		attachments.push(ReceivedAttachment {
+			name: path.file_name().unwrap_or_default().to_string_lossy().into_owned(),
+			mime_type: tree_magic::from_filepath(path),
+			disk_path: path.clone(),
+			start: 0,
+			len: std::fs::metadata(path)?.len(),
+		});
+	}
+	Ok(MmsReceived {
+		id,
+		date,
+		subject: None,
+		sender: msg.sender.clone(),
+		recipients: vec![OWN_NUMBER.into()],
+		attachments,
+		smil: None,
+	})
+}
+
+/* a pretend phone, for working on vgmms without a modem: it accepts every message, and
+receives the messages of a script */
+pub struct SimulatedBackend {
+	script: Mutex<Vec<Scripted>>,
+	sink: Mutex<Option<NotifSink>>,
+	next_message: AtomicU32,
+	delivery_reports: AtomicBool,
+}
+
+impl SimulatedBackend {
+	pub fn new(script: Vec<Scripted>) -> Self {
+		SimulatedBackend {
+			script: Mutex::new(script),
+			sink: Mutex::new(None),
+			next_message: AtomicU32::new(0),
+			delivery_reports: AtomicBool::new(false),
+		}
+	}
+
+	/* a simulator running the script in fixture_dir, or the default one if there is none */
+	pub fn load(fixture_dir: Option<&Path>) -> Result<Self, String> {
+		let script = match fixture_dir {
+			Some(dir) => {
+				let path = dir.join("script");
+				let script = std::fs::read_to_string(&path)
+					.map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+				parse_script(&script, dir).map_err(|e| format!("{}: {}", path.display(), e))?
+			},
+			None => parse_script(DEFAULT_SCRIPT, Path::new(""))?,
+		};
+		Ok(Self::new(script))
+	}
+}
+
+impl crate::backend::MessagingBackend for SimulatedBackend {
+	fn start_recv(&self) -> crate::backend::NotifStream {
+		use futures::stream::StreamExt;
+		let (sink, stream) = futures::channel::mpsc::unbounded();
+		*self.sink.lock().unwrap() = Some(sink.clone());
+		let script = std::mem::replace(&mut *self.script.lock().unwrap(), vec![]);
+		std::thread::spawn(move || for msg in script {
+			std::thread::sleep(msg.delay);
+			match arrival(&msg) {
+				Ok(notif) => notify(&sink, notif),
+				Err(e) => eprintln!("simulator cannot deliver message from {}: {}", msg.sender, e),
+			}
+		});
+		stream.boxed()
+	}
+
+	fn modems(&self) -> Result<Vec<ModemId>, Error> {
+		Ok(vec![MODEM_PATH.into()])
+	}
+
+	fn own_number(&self, _modem: &str) -> Result<Option<String>, Error> {
+		Ok(Some(OWN_NUMBER.into()))
+	}
+
+	fn pin_required(&self, _modem: &str) -> Result<(String, Option<u8>), Error> {
+		Ok(("none".into(), None))
+	}
+
+	fn enter_pin(&self, _modem: &str, _pin_type: &str, _pin: &str, _new_pin: Option<&str>) -> Result<(), Error> {
+		Ok(())
+	}
+
+	fn set_delivery_reports(&self, _modem: &str, enabled: bool) -> Result<(), Error> {
+		self.delivery_reports.store(enabled, Ordering::Relaxed);
+		Ok(())
+	}
+
+	fn send_message(&self, _modem: &str, msg: &MessageInfo,
+		_atts: &HashMap<AttachmentId, Attachment>) -> Result<Option<String>, Error> {
+		let path = format!("/vgmms/simulator/message/{}", self.next_message.fetch_add(1, Ordering::Relaxed));
+		let fails = msg.contents.iter().any(|item| match item {
+			MessageItem::Text(t) => t.contains("fail"),
+			_ => false,
+		});
+		let mut statuses = vec![MessageStatus::Sending, if fails { MessageStatus::Failed } else { MessageStatus::Sent }];
+		if !fails && self.delivery_reports.load(Ordering::Relaxed) {
+			statuses.push(MessageStatus::Delivered);
+		}
+		if let Some(sink) = self.sink.lock().unwrap().clone() {
+			let path = path.clone();
+			std::thread::spawn(move || for status in statuses {
+				std::thread::sleep(STATUS_DELAY);
+				notify(&sink, StatusUpdate { path: path.clone(), status });
+			});
+		}
+		Ok(Some(path))
+	}
+
+	fn stored_mms(&self, _chat_numbers: &[String]) -> Result<Vec<StoredMms>, Error> {
+		Ok(vec![])
+	}
+}
+
+#[test]
+fn test_parse_script() {
+	let script = "# a comment\n\n1.5\t+15555550123\thello\n0\t+15555550124\tlook\tcat.png\tdog.jpg\n";
+	let parsed = parse_script(script, Path::new("/fixtures")).unwrap();
+	assert_eq!(parsed, vec![
+		Scripted { delay: Duration::from_millis(1500), sender: "+15555550123".into(), text: "hello".into(), attachments: vec![] },
+		Scripted { delay: Duration::from_millis(0), sender: "+15555550124".into(), text: "look".into(),
+			attachments: vec!["/fixtures/cat.png".into(), "/fixtures/dog.jpg".into()] },
+	]);
+	assert!(parse_script("1\tno text\n", Path::new("")).is_err());
+	assert!(parse_script("soon\t+15555550123\thi\n", Path::new("")).is_err());
+	assert!(parse_script(DEFAULT_SCRIPT, Path::new("")).is_ok());
+}
+
+#[test]
+fn test_simulated_send() {
+	use crate::backend::MessagingBackend;
+	use crate::types::Number;
+	use futures::stream::StreamExt;
+
+	let backend = SimulatedBackend::new(vec![]);
+	let mut stream = backend.start_recv();
+	let mut msg = MessageInfo {
+		sender: Number::new(15555550100),
+		chat: vec![Number::new(15555550100), Number::new(15555550123)],
+		time: 0,
+		seq: 0,
+		contents: vec![MessageItem::Text("please fail".into())],
+		status: MessageStatus::Draft,
+	};
+	let failing = backend.send_message(MODEM_PATH, &msg, &HashMap::new()).unwrap().unwrap();
+	msg.contents = vec![MessageItem::Text("hi".into())];
+	let sent = backend.send_message(MODEM_PATH, &msg, &HashMap::new()).unwrap().unwrap();
+	assert_ne!(failing, sent);
+
+	let mut statuses = HashMap::new();
+	for _ in 0..4 {
+		match futures::executor::block_on(stream.next()) {
+			Some(StatusUpdate { path, status }) => statuses.entry(path).or_insert(vec![]).push(status),
+			other => panic!("unexpected {:?}", other),
+		}
+	}
+	assert_eq!(statuses[&*failing], vec![MessageStatus::Sending, MessageStatus::Failed]);
+	assert_eq!(statuses[&*sent], vec![MessageStatus::Sending, MessageStatus::Sent]);
+}
@@ -1,17 +1,9 @@
 use std::default::Default;
 
 use std::ffi::OsString;
-use crate::{db, dbus, types::*};
-
-fn read_file_chunk(path: &std::path::Path, start: u64, len: u64) -> Result<Vec<u8>, std::io::Error> {
-	use std::io::{Read, Seek, SeekFrom};
-
-	let mut file = std::fs::File::open(path)?;
-	file.seek(SeekFrom::Start(start))?;
-	let mut out = vec![0; len as usize];
-	file.read_exact(&mut out[..])?;
-	Ok(out)
-}
+use std::sync::Arc;
+use crate::{android_backup, db, store, types::*};
+use crate::backend::{MessagingBackend, NotifSink, Notification, StoredMms};
 
 fn parse_date(date: &str) -> chrono::format::ParseResult<u64> {
 	match chrono::DateTime::parse_from_rfc3339(&date) {
@@ -21,7 +13,7 @@ fn parse_date(date: &str) -> chrono::format::ParseResult<u64> {
 			chrono::DateTime::parse_from_rfc3339(&date)
 		},
 		x => x,
-	}.map(|x| x.timestamp() as u64)
+	}.map(|x| x.timestamp_millis() as u64)
 }
 
 impl VgmmsState {
@@ -37,9 +29,32 @@ impl VgmmsState {
 		id
 	}
 
-	pub fn summarize_all(&self) -> Vec<(Chat, String)> {
+	/* copy an attachment's data into vgmms's own storage so it outlives the file it came from.
+	if that fails, the attachment keeps pointing at its original file. */
+	fn store_attachment(&self, att: Attachment) -> Attachment {
+		let (ref path, start, len) = att.data;
+		match store::import(&db::attachment_dir(), path, start, len) {
+			Ok(stored) => Attachment {
+				data: (stored, 0, len),
+				..att
+			},
+			Err(e) => {
+				eprintln!("error storing attachment from {}: {}", path.display(), e);
+				att
+			},
+		}
+	}
+
+	/* every chat with the identity it belongs to and a summary of its last message, newest first */
+	pub fn summarize_all(&self) -> Vec<(Chat, Number, String)> {
 		println!("summarize_all");
-		let mut sorted = self.chats.iter().collect::<Vec<_>>();
+		/* loaded chats know their newest message from the index; others from the db */
+		let mut sorted = self.chats.iter().map(|(c, last)| {
+			match self.chat_messages.get(c).and_then(|ids| ids.iter().next_back()) {
+				Some((&(time, _seq), &id)) => (c, Some((time, id))),
+				None => (c, *last),
+			}
+		}).collect::<Vec<_>>();
 		/* sort chats by highest timestamp, treating any as greater than none */
 		sorted.sort_by(|(_c1, md1), (_c2, md2)| {
 			match (md1, md2) {
@@ -50,14 +65,51 @@ impl VgmmsState {
 			}
 		});
 		/* summarize each chat */
-		sorted.into_iter().map(|(c, maybe_ts_msg)| (c.clone(), match maybe_ts_msg {
-			Some((_, msg_id)) => self.summarize(msg_id),
+		sorted.into_iter().map(|(c, maybe_ts_msg)| (c.clone(), self.own_number_of(c), match maybe_ts_msg {
+			Some((_, msg_id)) => self.summarize(&msg_id),
 			_ => "".into(),
 		})).collect()
 	}
 
+	/* the loaded messages of a chat, oldest first */
+	pub fn chat_messages<'a>(&'a self, chat: &Chat) -> impl Iterator<Item=(&'a MessageId, &'a MessageInfo)> + 'a {
+		self.chat_messages.get(chat).into_iter().flat_map(move |ids| {
+			ids.values().filter_map(move |id| self.messages.get_key_value(id))
+		})
+	}
+
+	fn index_message(&mut self, id: MessageId, message: MessageInfo) {
+		let chat = Chat { numbers: message.chat.clone() };
+		self.chat_messages.entry(chat).or_default().insert(message.order(), id);
+		self.messages.insert(id, message);
+	}
+
+	fn unindex_message(&mut self, id: &MessageId) -> Option<MessageInfo> {
+		let message = self.messages.remove(id)?;
+		let chat = Chat { numbers: message.chat.clone() };
+		if let Some(ids) = self.chat_messages.get_mut(&chat) {
+			ids.remove(&message.order());
+		}
+		Some(message)
+	}
+
 	pub fn summarize(&self, msg_id: &MessageId) -> String {
-		if let Some(msg) = self.messages.get(msg_id) {
+		/* the message may belong to a chat whose log is not loaded */
+		let loaded;
+		let msg = match self.messages.get(msg_id) {
+			Some(msg) => Some(msg),
+			None => {
+				loaded = db::Query::new(&self.db_conn).and_then(|mut q| q.message(msg_id));
+				match loaded {
+					Ok(ref msg) => msg.as_ref(),
+					Err(ref e) => {
+						eprintln!("error loading message to summarize: {}", e);
+						None
+					},
+				}
+			},
+		};
+		if let Some(msg) = msg {
 			let mut summary = String::new();
 			for item in &msg.contents {
 				match item {
@@ -65,15 +117,19 @@ impl VgmmsState {
 						summary.push_str(t);
 					},
 					MessageItem::Attachment(ref id) => {
-						match self.attachments.get(id) {
-							Some(att) => summary.push_str(&format!("[attachment of type {}]", att.mime_type)),
+						let mime_type = match self.attachments.get(id) {
+							Some(att) => Some(att.mime_type.clone()),
+							None => db::get_attachment(&self.db_conn, *id).ok().flatten().map(|att| att.mime_type),
+						};
+						match mime_type {
+							Some(mime_type) => summary.push_str(&format!("[attachment of type {}]", mime_type)),
 							None => summary.push_str("[attachment {} not found]"),
 						};
 					},
 				}
 			}
 			use chrono::offset::TimeZone;
-			let summary = if let chrono::offset::LocalResult::Single(time) = chrono::Local.timestamp_opt(msg.time as i64, 0) {
+			let summary = if let chrono::offset::LocalResult::Single(time) = chrono::Local.timestamp_millis_opt(msg.time as i64) {
 				format!("[{}] {}: {}", time.format("%k:%M"), msg.sender.to_string(), summary)
 			} else {
 				format!("[@{}] {}: {}", msg.time, msg.sender.to_string(), summary)
@@ -85,32 +141,251 @@ impl VgmmsState {
 		}
 	}
 
-	pub fn add_message(&mut self, id: MessageId, message: MessageInfo) {
+	/* find messages matching query, newest first */
+	pub fn search(&self, query: &str, chat_filter: Option<&Chat>) -> Vec<db::SearchHit> {
+		match db::search(&self.db_conn, query, chat_filter, 100) {
+			Ok(hits) => hits,
+			Err(e) => {
+				eprintln!("error searching messages: {}", e);
+				vec![]
+			},
+		}
+	}
+
+	/* make sure the newest page of a chat's messages is loaded */
+	pub fn load_chat(&mut self, chat: &Chat) {
+		if !self.loaded_chats.contains_key(chat) {
+			self.loaded_chats.insert(chat.clone(), ChatPages::default());
+			self.load_older(chat);
+		}
+	}
+
+	/* load the page of a chat's messages before the oldest loaded one. returns false if there were none. */
+	pub fn load_older(&mut self, chat: &Chat) -> bool {
+		let before = match self.loaded_chats.get(chat) {
+			Some(pages) if pages.complete => return false,
+			Some(pages) => pages.oldest,
+			None => None,
+		};
+		let page = match db::Query::new(&self.db_conn).and_then(|mut q| q.chat_page(chat, before, PAGE_SIZE)) {
+			Ok(page) => page,
+			Err(e) => {
+				eprintln!("error loading messages: {}", e);
+				return false
+			},
+		};
+
+		let pages = self.loaded_chats.entry(chat.clone()).or_default();
+		pages.complete = page.len() < PAGE_SIZE as usize;
+		if let Some((_id, msg)) = page.first() {
+			pages.oldest = Some(msg.order());
+		}
+		let loaded_any = !page.is_empty();
+		for (id, msg) in page {
+			for item in &msg.contents {
+				if let MessageItem::Attachment(att_id) = item {
+					self.load_attachment(*att_id);
+				}
+			}
+			self.index_message(id, msg);
+		}
+		loaded_any
+	}
+
+	/* load pages of a chat until the given message is loaded */
+	pub fn load_chat_through(&mut self, chat: &Chat, id: &MessageId) {
+		self.load_chat(chat);
+		while !self.messages.contains_key(id) && self.load_older(chat) {}
+	}
+
+	/* drop the loaded messages of a chat, and attachments only they used */
+	pub fn evict_chat(&mut self, chat: &Chat) {
+		if self.loaded_chats.remove(chat).is_none() {
+			return
+		}
+		let mut evicted_atts = std::collections::HashSet::new();
+		for id in self.chat_messages.remove(chat).unwrap_or_default().values() {
+			if let Some(msg) = self.messages.remove(id) {
+				for item in &msg.contents {
+					if let MessageItem::Attachment(att_id) = item {
+						evicted_atts.insert(*att_id);
+					}
+				}
+			}
+		}
+		for msg in self.messages.values() {
+			for item in &msg.contents {
+				if let MessageItem::Attachment(att_id) = item {
+					evicted_atts.remove(att_id);
+				}
+			}
+		}
+		for att_id in evicted_atts {
+			self.attachments.remove(&att_id);
+		}
+	}
+
+	fn load_attachment(&mut self, id: AttachmentId) {
+		if self.attachments.contains_key(&id) {
+			return
+		}
+		match db::get_attachment(&self.db_conn, id) {
+			Ok(Some(att)) => { self.attachments.insert(id, att); },
+			Ok(None) => eprintln!("attachment {} not found", id),
+			Err(e) => eprintln!("error loading attachment {}: {}", id, e),
+		}
+	}
+
+	/* add the messages of a backup that android_backup::Importer read */
+	pub fn import_android_backup(&mut self, backup: android_backup::Backup) -> Result<android_backup::ImportStats, android_backup::BackupError> {
+		/* our connection may be read-only while no modem is present */
+		let mut conn = db::connect()?;
+		let stats = backup.insert(&mut conn, &mut self.next_message_id, &mut self.next_attachment_id)?;
+		for own in self.own_numbers() {
+			if let Err(e) = db::claim_own_number(&mut conn, own) {
+				eprintln!("error assigning chats to {}: {}", own.to_string(), e);
+			}
+		}
+		self.reload_chats();
+		Ok(stats)
+	}
+
+	pub fn export_android_backup(&self, path: &std::path::Path) -> Result<usize, android_backup::BackupError> {
+		let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+		android_backup::export(&self.db_conn, |chat| self.own_number_of(chat), &mut file)
+	}
+
+	pub fn has_modem(&self) -> bool {
+		self.identities.iter().any(|i| i.modem.is_some())
+	}
+
+	pub fn own_numbers(&self) -> Vec<Number> {
+		self.identities.iter().map(|i| i.number).collect()
+	}
+
+	/* own numbers whose modem is present to send from */
+	pub fn sending_identities(&self) -> Vec<Number> {
+		self.identities.iter().filter(|i| i.modem.is_some()).map(|i| i.number).collect()
+	}
+
+	fn modem_for(&self, own: Number) -> Option<crate::backend::ModemId> {
+		self.identities.iter().find(|i| i.number == own).and_then(|i| i.modem.clone())
+	}
+
+	fn identity_of_modem(&self, modem_path: &str) -> Option<&Identity> {
+		self.identities.iter().find(|i| i.modem.as_deref() == Some(modem_path))
+	}
+
+	/* which of our numbers a chat belongs to: as recorded, else the own number among its members */
+	pub fn own_number_of(&self, chat: &Chat) -> Number {
+		match self.chat_owners.get(chat) {
+			Some(own) => *own,
+			None => chat.own_number(&self.own_numbers()).unwrap_or(self.my_number),
+		}
+	}
+
+	/* record own as the identity of the chats it is a member of that don't have one yet */
+	fn claim_chats(&mut self, own: Number) {
+		if let Err(e) = db::claim_own_number(&mut self.db_conn, own) {
+			eprintln!("error assigning chats to {}: {}", own.to_string(), e);
+		}
+		match db::get_chat_owners(&self.db_conn) {
+			Ok(owners) => self.chat_owners = owners,
+			Err(e) => eprintln!("error loading chat identities: {}", e),
+		}
+	}
+
+	/* re-read chats and the messages of loaded chats after the db was changed behind our back */
+	fn reload_chats(&mut self) {
+		match db::get_chat_owners(&self.db_conn) {
+			Ok(owners) => self.chat_owners = owners,
+			Err(e) => eprintln!("error loading chat identities: {}", e),
+		}
+		match db::get_all_chats(&self.db_conn) {
+			Ok(chats) => for (chat, _tab_id, last) in chats {
+				self.chats.insert(chat, last);
+			},
+			Err(e) => eprintln!("error loading chats: {}", e),
+		}
+		let loaded = self.loaded_chats.keys().cloned().collect::<Vec<_>>();
+		for chat in loaded {
+			self.evict_chat(&chat);
+			self.load_chat(&chat);
+		}
+	}
+
+	/* add a message to its chat, creating the chat if needed. false if it couldn't be saved. */
+	pub fn add_message(&mut self, id: MessageId, mut message: MessageInfo) -> bool {
+		let chat = Chat { numbers: message.chat.clone() };
 		/* create a chat for it if one doesn't exist */
-		if !self.chats.get(&message.chat).is_some() {
-			let chat = Chat { numbers: message.chat.clone() };
+		if !self.chats.get(&chat).is_some() {
 			if let Err(e) = db::insert_chat(&mut self.db_conn, &chat, -1, None) {
 				eprintln!("error while saving message: error saving chat: {}", e);
 			}
-			self.chats.insert(chat, None);
-		} else {
-			self.chats.insert(Chat { numbers: message.chat.clone() }, Some((message.time, id)));
 		}
+		/* so the message is saved as belonging to the chat's identity */
+		if !self.chat_owners.contains_key(&chat) {
+			self.claim_chats(self.own_number_of(&chat));
+		}
+		self.chats.insert(chat.clone(), Some((message.time, id)));
 
-		if let Err(e) = db::insert_message(&mut self.db_conn, &id, &message) {
-			eprintln!("error saving message: {}", e);
+		let saved = match db::insert_message(&mut self.db_conn, &id, &message) {
+			Ok(seq) => {
+				message.seq = seq;
+				true
+			},
+			Err(e) => {
+				eprintln!("error saving message: {}", e);
+				false
+			},
+		};
+		/* messages of chats that aren't loaded are read from the db when they are */
+		if self.loaded_chats.contains_key(&chat) {
+			self.index_message(id, message);
+		}
+		saved
+	}
+
+	pub fn delete_message(&mut self, id: &MessageId) {
+		match db::delete_message(&mut self.db_conn, id) {
+			Ok(removed) => for att_id in removed {
+				self.attachments.remove(&att_id);
+			},
+			Err(e) => eprintln!("error deleting message: {}", e),
+		}
+		if let Some(msg) = self.unindex_message(id) {
+			/* the chat's summary should now show the message before this one */
+			let chat = Chat { numbers: msg.chat };
+			if let Some(Some((_, last_id))) = self.chats.get(&chat) {
+				if last_id == id {
+					let last = self.chat_messages.get(&chat).and_then(|ids| ids.iter().next_back())
+						.map(|(&(time, _seq), &id)| (time, id));
+					self.chats.insert(chat, last);
+				}
+			}
 		}
-		self.messages.insert(id, message);
 	}
 
-	pub fn send_message(&mut self, chat: &Chat, draft_items: Vec<DraftItem>) {
+	/* send a message to the other members of chat from the own number `from`. sending from another
+	identity than the chat's own puts the message in that identity's chat with the same people. */
+	pub fn send_message(&mut self, chat: &Chat, from: Number, draft_items: Vec<DraftItem>) {
 		if draft_items.len() == 0 {
 			return
 		}
+		if self.modem_for(from).is_none() {
+			eprintln!("cannot send message: no modem for {}", from.to_string());
+			return
+		}
+		let own = self.own_number_of(chat);
+		let mut numbers = chat.numbers.iter().cloned().filter(|n| *n != own).collect::<Vec<_>>();
+		numbers.push(from);
+		numbers.sort();
+		numbers.dedup();
 		let items = {
 			draft_items.into_iter().map(|item| match item {
 				DraftItem::Attachment(att) =>
 					MessageItem::Attachment({
+						let att = self.store_attachment(att);
 						let id = self.next_attachment_id();
 						if let Err(e) = db::insert_attachment(&mut self.db_conn, &id, &att) {
 							eprintln!("error saving attachment: {}", e);
@@ -124,32 +399,439 @@ impl VgmmsState {
 		};
 
 		let id = self.next_message_id();
-		let num = self.my_number;
 		let message = MessageInfo {
-			sender: num,
-			chat: chat.numbers.clone(),
-			time: chrono::offset::Local::now().timestamp() as u64,
+			sender: from,
+			chat: numbers,
+			time: chrono::offset::Local::now().timestamp_millis() as u64,
+			seq: 0,
 			contents: items,
 			status: MessageStatus::Sending,
 		};
 		println!("inserting send {}: {:?}", hex::encode(&id[..]), message);
-		match crate::dbus::send_message(&self.modem_path, &message, &self.attachments) {
-			Ok(_) => (),
-			Err(e) => eprintln!("error sending message: {}", e),
-		};
+		let time = message.time;
 		self.add_message(id.clone(), message);
+		self.queue_message(&id, OutboxEntry { attempts: 0, next_attempt: time });
+		self.try_send(&id);
+	}
+
+	fn queue_message(&mut self, id: &MessageId, entry: OutboxEntry) {
+		if let Err(e) = db::queue_message(&self.db_conn, id, &entry) {
+			eprintln!("error saving message {} to outbox: {}", hex::encode(&id[..]), e);
+		}
+		self.outbox.insert(*id, entry);
+	}
+
+	fn dequeue_message(&mut self, id: &MessageId) {
+		if let Err(e) = db::dequeue_message(&self.db_conn, id) {
+			eprintln!("error removing message {} from outbox: {}", hex::encode(&id[..]), e);
+		}
+		self.outbox.remove(id);
+		self.send_errors.remove(id);
+	}
+
+	/* hand a message in the outbox to the send worker, unless it is already sending it */
+	fn try_send(&mut self, id: &MessageId) {
+		if !self.outbox.contains_key(id) || self.sending.contains(id) {
+			return
+		}
+
+		/* the message may belong to a chat that isn't loaded */
+		let message = match self.messages.get(id) {
+			Some(msg) => Some(msg.clone()),
+			None => db::Query::new(&self.db_conn).and_then(|mut q| q.message(id)).unwrap_or_else(|e| {
+				eprintln!("error loading message {} to send: {}", hex::encode(&id[..]), e);
+				None
+			}),
+		};
+		let message = match message {
+			Some(msg) => msg,
+			None => {
+				self.dequeue_message(id);
+				return
+			},
+		};
+		/* it waits in the outbox until the modem of the number it is from is attached */
+		let modem_path = match self.modem_for(message.sender) {
+			Some(path) => path,
+			None => return,
+		};
+		let mut attachments = std::collections::HashMap::new();
+		for item in &message.contents {
+			if let MessageItem::Attachment(att_id) = item {
+				self.load_attachment(*att_id);
+				if let Some(att) = self.attachments.get(att_id) {
+					attachments.insert(*att_id, att.clone());
+				}
+			}
+		}
+		let job = SendJob { id: *id, modem: modem_path, message, attachments };
+		if self.send_queue.send(job).is_err() {
+			eprintln!("cannot send message {}: the send worker has exited", hex::encode(&id[..]));
+			return
+		}
+		self.sending.insert(*id);
 	}
 
-	pub fn handle_notif(&mut self, notif: dbus::DbusNotification) {
-		use self::dbus::DbusNotification::*;
+	/* take in what the send worker reports on a message, scheduling another attempt if it failed */
+	fn send_finished(&mut self, id: &MessageId, result: Result<Option<String>, crate::backend::Error>) {
+		self.sending.remove(id);
+		let status = self.messages.get(id).map(|msg| msg.status);
+		match result {
+			Ok(path) => {
+				let attempts = self.outbox.get(id).map(|entry| entry.attempts);
+				self.dequeue_message(id);
+				if status != Some(MessageStatus::Sending) {
+					self.set_message_status(id, MessageStatus::Sending);
+				}
+				/* follow the message through the backend's object for it */
+				if let Some(path) = path {
+					if let Err(e) = db::set_remote_path(&self.db_conn, id, Some(&path)) {
+						eprintln!("error linking message to {}: {}", path, e);
+					}
+					/* unless it was cancelled meanwhile, it is retried if the backend reports it failed */
+					if let Some(attempts) = attempts {
+						self.handed_over.insert(*id, attempts);
+					}
+					if let Some(status) = self.early_statuses.remove(&path) {
+						self.remote_status(id, status);
+					}
+				}
+			},
+			Err(e) => {
+				eprintln!("error sending message: {}", e);
+				/* unless it was cancelled meanwhile */
+				if let Some(attempts) = self.outbox.get(id).map(|entry| entry.attempts) {
+					self.send_failed(id, attempts, e);
+				}
+				if status != Some(MessageStatus::Failed) {
+					self.set_message_status(id, MessageStatus::Failed);
+				}
+			},
+		}
+		/* any left belong to messages sent by others */
+		if self.sending.is_empty() {
+			self.early_statuses.clear();
+		}
+	}
+
+	/* schedule another attempt at a message that failed to send after `attempts` earlier ones,
+	unless the failure is for good or it has been tried often enough. then it stays failed, out
+	of the outbox, for the user to retry or not. */
+	fn send_failed(&mut self, id: &MessageId, attempts: u32, error: crate::backend::Error) {
+		let attempts = attempts + 1;
+		if let crate::backend::Error::Rejected(_) = error {
+			println!("message {} was rejected; not retrying it", hex::encode(&id[..]));
+			self.dequeue_message(id);
+		} else if attempts >= MAX_ATTEMPTS {
+			println!("message {} failed to send {} times; giving up", hex::encode(&id[..]), attempts);
+			self.dequeue_message(id);
+		} else {
+			let now = chrono::offset::Local::now().timestamp_millis() as u64;
+			self.queue_message(id, OutboxEntry { attempts, next_attempt: now + retry_delay(attempts) });
+			self.send_errors.insert(*id, error);
+		}
+	}
+
+	/* record a status the backend reported for a message. one handed over to it this run that
+	it failed to send goes back in the outbox, as if send_message had returned an error. */
+	fn remote_status(&mut self, id: &MessageId, status: MessageStatus) {
+		self.set_message_status(id, status);
+		match status {
+			MessageStatus::Sending => (),
+			MessageStatus::Failed => if let Some(attempts) = self.handed_over.remove(id) {
+				self.send_failed(id, attempts, crate::backend::Error::Failed("the message could not be sent".into()));
+			},
+			/* a failure reported once it was sent is one of delivery (e.g. ModemManager's permanent
+			DeliveryStates), which sending it again won't fix */
+			_ => {
+				self.handed_over.remove(id);
+			},
+		}
+	}
+
+	/* try sending queued messages whose retry time has come, or all of them if `all`.
+	returns whether any were tried. */
+	pub fn flush_outbox(&mut self, all: bool) -> bool {
+		let now = chrono::offset::Local::now().timestamp_millis() as u64;
+		let due = self.outbox.iter()
+			.filter(|(_id, entry)| all || entry.next_attempt <= now)
+			.map(|(id, _entry)| *id)
+			.collect::<Vec<_>>();
+		for id in &due {
+			self.try_send(id);
+		}
+		!due.is_empty()
+	}
+
+	/* send a failed message again now, putting it back in the outbox if it was cancelled */
+	pub fn retry_message(&mut self, id: &MessageId) {
+		let now = chrono::offset::Local::now().timestamp_millis() as u64;
+		self.queue_message(id, OutboxEntry { attempts: 0, next_attempt: now });
+		self.try_send(id);
+	}
+
+	/* stop retrying a message; it stays in its chat as failed */
+	pub fn cancel_message(&mut self, id: &MessageId) {
+		self.handed_over.remove(id);
+		self.dequeue_message(id);
+	}
+
+	pub fn set_delivery_reports(&mut self, enabled: bool) {
+		self.delivery_reports = enabled;
+		if let Err(e) = db::set_setting(&self.db_conn, db::SETTING_DELIVERY_REPORTS, if enabled { "1" } else { "0" }) {
+			eprintln!("error saving setting: {}", e);
+		}
+		for modem_path in self.identities.iter().filter_map(|i| i.modem.as_ref()) {
+			if let Err(e) = self.backend.set_delivery_reports(modem_path, enabled) {
+				eprintln!("error setting delivery reports on {}: {}", modem_path, e);
+			}
+		}
+	}
+
+	/* whether to ask the user for our number: a modem doesn't report it, or there is nothing to go by */
+	pub fn needs_own_number(&self) -> bool {
+		/* a locked SIM may still tell us once it is unlocked */
+		self.locked_modems.is_empty() && (!self.unidentified_modems.is_empty() || self.identities.is_empty())
+	}
+
+	/* unlock the SIM of a modem waiting for a PIN, or a PUK and a new PIN. the modem is used once
+	the backend reports the SIM unlocked, and identified once the SIM reports its number. */
+	pub fn unlock_sim(&mut self, modem_path: &str, pin_type: &str, pin: &str, new_pin: Option<&str>) -> Result<(), String> {
+		self.backend.enter_pin(modem_path, pin_type, pin, new_pin)
+			.map_err(|e| format!("error unlocking SIM: {}", e))
+	}
+
+	/* the own number and home country the user entered, if any */
+	pub fn manual_own_number(&self) -> Option<(Number, Country)> {
+		let home = home_country(&self.db_conn);
+		remembered_number(&self.db_conn, db::SETTING_OWN_NUMBER)
+			.and_then(|num| parse_my_number(&num, home))
+	}
+
+	/* set our number for modems that don't report one. chats of a number entered
+	before are moved to the new one. */
+	pub fn set_own_number(&mut self, number: &str, country: &str) -> Result<(), String> {
+		let country: Country = country.trim().to_uppercase().parse()
+			.map_err(|_| format!("unknown country code {}", country))?;
+		let number = Number::normalize(number.trim(), country)
+			.ok_or_else(|| format!("could not parse phone number {}", number))?;
+		let old = self.manual_own_number().map(|(num, _)| num);
+
+		/* our connection may be read-only while no modem is present */
+		let mut conn = db::connect().map_err(|e| format!("error opening database: {}", e))?;
+		db::set_setting(&conn, db::SETTING_OWN_NUMBER, &number.to_string())
+			.and_then(|_| db::set_setting(&conn, db::SETTING_HOME_COUNTRY, country.as_ref()))
+			.map_err(|e| format!("error saving own number: {}", e))?;
+
+		match old {
+			Some(old) if old != number && self.identities.iter().any(|i| i.number == old) => {
+				match db::rekey_own_number(&mut conn, old, number) {
+					Ok(n) => println!("moved {} chats from {} to {}", n, old.to_string(), number.to_string()),
+					Err(e) => return Err(format!("error moving chats to new number: {}", e)),
+				}
+				/* the old identity becomes the new one, merging with it if it existed */
+				let modem_path = self.identities.iter().find(|i| i.number == old).and_then(|i| i.modem.clone());
+				self.identities.retain(|i| i.number != old);
+				match self.identities.iter_mut().find(|i| i.number == number) {
+					Some(identity) => if identity.modem.is_none() {
+						identity.modem = modem_path;
+					},
+					None => self.identities.push(Identity { number, country, modem: modem_path }),
+				}
+				if self.my_number == old {
+					self.my_number = number;
+					self.my_country = country;
+				}
+			},
+			_ => (),
+		}
+		if self.identities.is_empty() {
+			self.identities.push(Identity { number, country, modem: None });
+			self.my_number = number;
+			self.my_country = country;
+		}
+		for modem_path in std::mem::take(&mut self.unidentified_modems) {
+			self.attach_modem(modem_path);
+		}
+		self.reload_all_chats();
+		Ok(())
+	}
+
+	/* re-read all chats, tabs and loaded messages after chats were re-keyed */
+	fn reload_all_chats(&mut self) {
+		let loaded = self.loaded_chats.keys().cloned().collect::<Vec<_>>();
+		for chat in loaded {
+			self.evict_chat(&chat);
+		}
+		match load_chats(&self.db_conn) {
+			Ok((chats, open_chats)) => {
+				self.chats = chats;
+				self.open_chats = open_chats;
+			},
+			Err(e) => eprintln!("error loading chats: {}", e),
+		}
+		for own in self.sending_identities() {
+			self.claim_chats(own);
+		}
+		match db::get_chat_owners(&self.db_conn) {
+			Ok(owners) => self.chat_owners = owners,
+			Err(e) => eprintln!("error loading chat identities: {}", e),
+		}
+		for chat in self.open_chats.clone() {
+			self.load_chat(&chat);
+		}
+	}
+
+	/* start using the modem at modem_path, reopening the db for writing if we had none */
+	pub fn attach_modem(&mut self, modem_path: crate::backend::ModemId) {
+		if self.identity_of_modem(&modem_path).is_some() {
+			return
+		}
+		if let Some(pin_type) = sim_lock(&*self.backend, &modem_path) {
+			eprintln!("SIM of modem {} needs its {}; waiting for it to be unlocked", modem_path, pin_type);
+			if !self.locked_modems.iter().any(|(p, _)| *p == modem_path) {
+				self.locked_modems.push((modem_path, pin_type));
+			}
+			return
+		}
+		let had_modem = self.has_modem();
+		if !had_modem {
+			match db::connect() {
+				Ok(conn) => self.db_conn = conn,
+				Err(e) => {
+					eprintln!("could not reopen database for writing: {}", e);
+					return
+				},
+			}
+		}
+		let (number, country) = match identify_modem(&*self.backend, &self.db_conn, &modem_path) {
+			Some(identity) => identity,
+			None => {
+				eprintln!("cannot identify modem {}; waiting for our number to be entered", modem_path);
+				if !self.unidentified_modems.contains(&modem_path) {
+					self.unidentified_modems.push(modem_path);
+				}
+				return
+			},
+		};
+		self.unidentified_modems.retain(|p| *p != modem_path);
+		println!("using modem {} as {}", modem_path, number.to_string());
+		if let Err(e) = self.backend.set_delivery_reports(&modem_path, self.delivery_reports) {
+			eprintln!("error setting delivery reports on {}: {}", modem_path, e);
+		}
+		match self.identities.iter_mut().find(|i| i.number == number) {
+			Some(identity) => {
+				identity.country = country;
+				identity.modem = Some(modem_path.clone());
+			},
+			None => self.identities.push(Identity { number, country, modem: Some(modem_path.clone()) }),
+		}
+		if !had_modem {
+			self.my_number = number;
+			self.my_country = country;
+		}
+		self.claim_chats(number);
+		self.fetch_stored_sms(&modem_path);
+		self.backfill_mms();
+		self.flush_outbox(true);
+	}
+
+	/* save SMS the modem at modem_path stored while we weren't listening */
+	fn fetch_stored_sms(&mut self, modem_path: &str) {
+		match self.backend.stored_sms(modem_path) {
+			Ok(stored) => for notif in stored {
+				self.handle_notif(notif);
+			},
+			Err(e) => eprintln!("error getting stored SMS of {}: {}", modem_path, e),
+		}
+	}
+
+	/* stop using the modem at modem_path; the chats of its number stay readable */
+	pub fn detach_modem(&mut self, modem_path: &str) {
+		self.unidentified_modems.retain(|p| &**p != modem_path);
+		self.locked_modems.retain(|(p, _)| &**p != modem_path);
+		let had_modem = self.has_modem();
+		for identity in &mut self.identities {
+			if identity.modem.as_deref() == Some(modem_path) {
+				println!("modem {} of {} went away", modem_path, identity.number.to_string());
+				identity.modem = None;
+			}
+		}
+		if had_modem && !self.has_modem() {
+			println!("no modem left; message history is read-only");
+			match db::connect_read_only() {
+				Ok(read_only) => self.db_conn = read_only,
+				Err(e) => eprintln!("could not reopen database read-only: {}", e),
+			}
+		}
+	}
+
+	/* attach the modems the backend has that we aren't using yet */
+	fn rescan_modems(&mut self) {
+		match self.backend.modems() {
+			Ok(paths) => for path in paths {
+				/* ones we use may have received messages while we weren't listening */
+				if self.identity_of_modem(&path).is_some() {
+					self.fetch_stored_sms(&path);
+				} else {
+					self.attach_modem(path);
+				}
+			},
+			Err(e) => eprintln!("error listing modems: {}", e),
+		}
+		if self.has_modem() {
+			self.backfill_mms();
+		}
+	}
+
+	/* record a status transition of a message, in the db and the loaded copy if any */
+	pub fn set_message_status(&mut self, id: &MessageId, status: MessageStatus) {
+		match db::update_message_status(&self.db_conn, id, status) {
+			Ok(true) => (),
+			Ok(false) => eprintln!("cannot find message {} to update status", hex::encode(&id[..])),
+			Err(e) => eprintln!("error saving status of message {}: {}", hex::encode(&id[..]), e),
+		}
+		if let Some(msg) = self.messages.get_mut(id) {
+			msg.status = status;
+		}
+	}
+
+	pub fn handle_notif(&mut self, notif: Notification) {
+		use self::Notification::*;
 		match notif {
-			MmsStatusUpdate {
-				id, status,
+			StatusUpdate {
+				path, status,
 			} => {
-				if let Some(msg) = self.messages.get_mut(&id) {
-					msg.status = status;
-				} else {
-					eprintln!("cannot find message {} to update status", hex::encode(&id[..]));
+				match db::find_message_by_remote_path(&self.db_conn, &path) {
+					Ok(Some(id)) => self.remote_status(&id, status),
+					/* the backend may report on a message before the send worker returns its path */
+					Ok(None) => if !self.sending.is_empty() {
+						self.early_statuses.insert(path, status);
+					},
+					Err(e) => eprintln!("error looking up message for {}: {}", path, e),
+				}
+			},
+			SendFinished {
+				id, result,
+			} => self.send_finished(&id, result),
+			StoredMmsFetched {
+				result,
+			} => match result {
+				/* the modem may have gone, leaving the db read-only, while they were fetched */
+				Ok(_) if !self.has_modem() => (),
+				Ok(stored) => self.backfill_fetched(stored),
+				Err(e) => eprintln!("error fetching stored MMS from mmsd: {}", e),
+			},
+			MessageRemoved {
+				path,
+			} => {
+				/* its last status stays; there is nothing left to track */
+				match db::find_message_by_remote_path(&self.db_conn, &path) {
+					Ok(Some(id)) => if let Err(e) = db::set_remote_path(&self.db_conn, &id, None) {
+						eprintln!("error unlinking message from {}: {}", path, e);
+					},
+					Ok(None) => (),
+					Err(e) => eprintln!("error looking up message for {}: {}", path, e),
 				}
 			},
 			MmsReceived {
@@ -157,6 +839,12 @@ impl VgmmsState {
 				recipients, attachments,
 				smil: _,
 			} => {
+				/* backfilled messages may already have been seen arrive */
+				match db::has_message(&self.db_conn, &id) {
+					Ok(true) => return,
+					Ok(false) => (),
+					Err(e) => eprintln!("error looking up message {}: {}", hex::encode(&id[..]), e),
+				}
 				let time = match parse_date(&date) {
 					Ok(d) => d,
 					Err(e) => {
@@ -169,18 +857,18 @@ impl VgmmsState {
 				for att in attachments {
 					if att.mime_type.starts_with("text/plain") {
 						/* fall back to remembering its attachment if we fail to read text from MMS file */
-						if let Ok(new_text) = read_file_chunk(&att.disk_path, att.start, att.len) {
+						if let Ok(new_text) = store::read_file_chunk(&att.disk_path, att.start, att.len) {
 							let read = String::from_utf8_lossy(&*new_text);
 							text.push_str(&*read);
 							continue;
 						}
 					}
 
-					let att = Attachment {
+					let att = self.store_attachment(Attachment {
 						name: OsString::from(att.name),
 						mime_type: att.mime_type,
 						data: (att.disk_path, att.start, att.len),
-					};
+					});
 					let id = self.next_attachment_id();
 					if let Err(e) = db::insert_attachment(&mut self.db_conn, &id, &att) {
 						eprintln!("error saving attachment: {}", e);
@@ -199,6 +887,7 @@ impl VgmmsState {
 						sender,
 						chat,
 						time,
+						seq: 0,
 						contents,
 						status: MessageStatus::Received,
 					};
@@ -209,8 +898,15 @@ impl VgmmsState {
 				}
 			},
 			SmsReceived {
-				message, date, sender,
+				message, date, sender, modem, stored,
 			} => {
+				/* announced again before the modem forgot it, e.g. also found stored on attaching */
+				if let Some(ref stored) = stored {
+					if self.saved_sms.contains(stored) {
+						self.take_sms(&modem, stored);
+						return
+					}
+				}
 				let time = match parse_date(&date) {
 					Ok(d) => d,
 					Err(e) => {
@@ -218,22 +914,134 @@ impl VgmmsState {
 						return
 					},
 				};
-				if let Some(sender) = Number::normalize(&*sender, self.my_country) {
-					let mut chat = vec![sender, self.my_number];
+				/* it belongs to the identity of the modem it arrived on */
+				let (own, country) = match self.identity_of_modem(&modem) {
+					Some(identity) => (identity.number, identity.country),
+					None => (self.my_number, self.my_country),
+				};
+				if let Some(sender) = Number::normalize(&*sender, country) {
+					let mut chat = vec![sender, own];
 					chat.sort();
 					let id = self.next_message_id();
 					let message = MessageInfo {
 						sender,
 						chat,
 						time,
+						seq: 0,
 						contents: vec![MessageItem::Text(message)],
 						status: MessageStatus::Received,
 					};
 					println!("inserting sms {}: {:?}", hex::encode(&id[..]), message);
-					self.add_message(id, message);
+					/* unsaved, it stays stored to be fetched again when the modem is next attached */
+					if self.add_message(id, message) {
+						if let Some(stored) = stored {
+							self.take_sms(&modem, &stored);
+							self.saved_sms.insert(stored);
+						}
+					}
 				} else {
 					eprintln!("cannot parse number {}", sender);
 				}
+			},
+			MmsServiceAppeared => {
+				self.backfill_mms();
+				self.flush_outbox(true);
+			},
+			MmsServiceVanished => {
+				println!("an mmsd service went away; MMS will be queued until one is back");
+			},
+			ModemAvailable => {
+				self.flush_outbox(true);
+			},
+			ModemServiceAppeared => {
+				self.rescan_modems();
+				self.flush_outbox(true);
+			},
+			ModemServiceVanished => {
+				let paths = self.identities.iter().filter_map(|i| i.modem.clone())
+					.chain(self.unidentified_modems.iter().cloned())
+					.chain(self.locked_modems.iter().map(|(p, _)| p.clone()))
+					.collect::<Vec<_>>();
+				for path in paths {
+					self.detach_modem(&path);
+				}
+			},
+			ModemRemoved {
+				modem,
+			} => {
+				self.detach_modem(&modem);
+			},
+			ModemAdded {
+				modem,
+			} => {
+				self.attach_modem(modem);
+			},
+			PinRequired {
+				modem,
+				pin_type,
+			} => {
+				self.locked_modems.retain(|(p, _)| *p != modem);
+				if pin_type == "none" {
+					println!("SIM of modem {} unlocked", modem);
+					self.attach_modem(modem);
+				} else {
+					self.locked_modems.push((modem, pin_type));
+				}
+			},
+			OwnNumberChanged {
+				modem,
+			} => {
+				/* a SIM just unlocked may only now say whose it is */
+				if self.unidentified_modems.contains(&modem) {
+					self.attach_modem(modem);
+				}
+			},
+		}
+	}
+
+	/* let the modem forget a stored SMS we saved */
+	fn take_sms(&self, modem: &str, stored: &str) {
+		if let Err(e) = self.backend.taken_sms(modem, stored) {
+			eprintln!("error removing saved SMS {} from modem {}: {}", stored, modem, e);
+		}
+	}
+
+	/* have the backfill worker fetch MMS that mmsd received while we weren't listening */
+	pub fn backfill_mms(&mut self) {
+		let my_numbers = self.own_numbers();
+		let mut numbers = self.chats.keys()
+			.flat_map(|c| c.numbers.iter())
+			.filter(|n| !my_numbers.contains(n))
+			.map(|n| format!("+{}", n.to_string()))
+			.collect::<Vec<_>>();
+		numbers.sort();
+		numbers.dedup();
+		if self.backfill_queue.send(numbers).is_err() {
+			eprintln!("cannot fetch stored MMS: the backfill worker has exited");
+		}
+	}
+
+	/* import the stored MMS the backfill worker fetched that we don't have yet */
+	fn backfill_fetched(&mut self, stored: Vec<StoredMms>) {
+		for mms in stored {
+			/* messages we sent are linked by path; catch up on their status */
+			match db::find_message_by_remote_path(&self.db_conn, &mms.path) {
+				Ok(Some(id)) => {
+					if let Some(status) = mms.sent_status {
+						self.remote_status(&id, status);
+					}
+					continue
+				},
+				Ok(None) => (),
+				Err(e) => {
+					eprintln!("error looking up message for {}: {}", mms.path, e);
+					continue
+				},
+			}
+			/* "read" is also the status of received messages that have been read */
+			match (mms.received, mms.sent_status) {
+				(Some(notif), None) | (Some(notif), Some(MessageStatus::Read)) => self.handle_notif(notif),
+				_ => (),
 			}
 		}
 	}
@@ -241,10 +1049,163 @@ impl VgmmsState {
 
 use std::collections::BTreeMap;
 
-impl Default for VgmmsState {
-	fn default() -> Self {
+/* how many messages of a chat to load at a time */
+pub const PAGE_SIZE: u32 = 50;
+
+/* the subscriber number and its country of the modem at modem_path, remembering them for
+when no modem is present. if the modem can't tell us, use the number the user entered, else
+the ones remembered from before. */
+fn identify_modem(backend: &dyn MessagingBackend, conn: &rusqlite::Connection, modem_path: &str) -> Option<(Number, Country)> {
+	let modem_key = db::modem_setting(db::SETTING_MY_NUMBER, modem_path);
+	let fallback = || remembered_number(conn, db::SETTING_OWN_NUMBER)
+		.or_else(|| remembered_number(conn, &modem_key))
+		.or_else(|| remembered_number(conn, db::SETTING_MY_NUMBER));
+	let my_number = match backend.own_number(modem_path) {
+		Ok(Some(num)) => {
+			for key in &[&*modem_key, db::SETTING_MY_NUMBER] {
+				if let Err(e) = db::set_setting(conn, key, &num) {
+					eprintln!("error saving subscriber number: {}", e);
+				}
+			}
+			num
+		},
+		Ok(None) => fallback()?,
+		Err(e) => {
+			eprintln!("error getting subscriber number: {}", e);
+			fallback()?
+		},
+	};
+	parse_my_number(&my_number, home_country(conn))
+}
+
+/* the PIN or PUK the SIM of a modem is waiting for, if it is locked */
+fn sim_lock(backend: &dyn MessagingBackend, modem_path: &str) -> Option<String> {
+	match backend.pin_required(modem_path) {
+		Ok((pin_type, _)) if pin_type != "none" => Some(pin_type),
+		Ok(_) => None,
+		Err(e) => {
+			eprintln!("error checking SIM lock of {}: {}", modem_path, e);
+			None
+		},
+	}
+}
+
+fn remembered_number(conn: &rusqlite::Connection, key: &str) -> Option<String> {
+	match db::get_setting(conn, key) {
+		Ok(num) => num,
+		Err(e) => {
+			eprintln!("error loading subscriber number: {}", e);
+			None
+		},
+	}
+}
+
+/* the country the user said they are in, for numbers that don't include one */
+fn home_country(conn: &rusqlite::Connection) -> Option<Country> {
+	match db::get_setting(conn, db::SETTING_HOME_COUNTRY) {
+		Ok(country) => country.and_then(|c| c.parse().ok()),
+		Err(e) => {
+			eprintln!("error loading home country: {}", e);
+			None
+		},
+	}
+}
+
+fn parse_my_number(my_number: &str, home_country: Option<Country>) -> Option<(Number, Country)> {
+	let my_country = match Number::get_country(my_number).or(home_country) {
+		Some(country) => country,
+		None => {
+			eprintln!("could not determine country of subscriber phone number {}", my_number);
+			return None
+		},
+	};
+	match Number::normalize(my_number, my_country) {
+		Some(num) => Some((num, my_country)),
+		None => {
+			eprintln!("could not parse subscriber phone number {}", my_number);
+			None
+		},
+	}
+}
+
+/* all chats with their last messages, and the chats open in tabs in tab order */
+fn load_chats(conn: &rusqlite::Connection) -> rusqlite::Result<(BTreeMap<Chat, Option<(u64, MessageId)>>, Vec<Chat>)> {
+	let mut chats = BTreeMap::new();
+	let mut open_chats = vec![];
+	for (c, tab_id, last_msg_info) in db::get_all_chats(conn)?.into_iter() {
+		/* insert into open_chats if open */
+		if tab_id >= 0 {
+			let tab_id = tab_id as usize;
+			/* ensure sufficient room in open_chats */
+			while open_chats.len() <= tab_id {
+				open_chats.push(Default::default());
+			}
+			open_chats[tab_id] = c.clone();
+		}
+		/* insert into chats map */
+		chats.insert(c, last_msg_info);
+	}
+	Ok((chats, open_chats))
+}
+
+/* how many times a message is tried before it is left failed, about two hours after the first */
+const MAX_ATTEMPTS: u32 = 8;
+
+/* how long to wait before retrying a message that has failed to send `attempts` times:
+30 seconds, doubling each time up to an hour */
+fn retry_delay(attempts: u32) -> u64 {
+	const FIRST: u64 = 30 * 1000;
+	const MAX: u64 = 60 * 60 * 1000;
+	FIRST.saturating_mul(1 << attempts.saturating_sub(1).min(16)).min(MAX)
+}
+
+/* hand the messages try_send queues to the backend one at a time, so a chat's messages go out in
+order, reporting on each in a SendFinished on results */
+fn spawn_send_worker(backend: Arc<dyn MessagingBackend>, results: NotifSink) -> std::sync::mpsc::Sender<SendJob> {
+	let (queue, jobs) = std::sync::mpsc::channel::<SendJob>();
+	std::thread::spawn(move || for job in jobs {
+		let result = backend.send_message(&job.modem, &job.message, &job.attachments);
+		crate::backend::notify(&results, Notification::SendFinished { id: job.id, result });
+	});
+	queue
+}
+
+/* ask the backend for the MMS it has stored whenever backfill_mms queues the numbers of our
+chats, reporting in a StoredMmsFetched on results. the D-Bus calls this takes can block for
+seconds. */
+fn spawn_backfill_worker(backend: Arc<dyn MessagingBackend>, results: NotifSink) -> std::sync::mpsc::Sender<Vec<String>> {
+	let (queue, jobs) = std::sync::mpsc::channel::<Vec<String>>();
+	std::thread::spawn(move || while let Ok(numbers) = jobs.recv() {
+		/* mmsd restarting or several modems appearing ask for it again and again; once is enough */
+		let numbers = jobs.try_iter().last().unwrap_or(numbers);
+		let result = backend.stored_mms(&numbers);
+		crate::backend::notify(&results, Notification::StoredMmsFetched { result });
+	});
+	queue
+}
+
+impl VgmmsState {
+	/* open the database and set up the modems the backend has. the send and backfill workers
+	report on results, which should reach handle_notif like the backend's notifications. */
+	pub fn new(backend: Box<dyn MessagingBackend>, results: NotifSink) -> Self {
+		let backend: Arc<dyn MessagingBackend> = backend.into();
 		let mut conn = db::connect().unwrap();
-		let _ = db::create_tables(&mut conn);
+		match db::migrate(&mut conn) {
+			Ok(from) if from != db::SCHEMA_VERSION => {
+				println!("upgraded database from schema version {} to {}", from, db::SCHEMA_VERSION);
+			},
+			Ok(_) => (),
+			Err(e) => {
+				eprintln!("could not open database: {}", e);
+				std::process::exit(1);
+			},
+		}
+		/* nothing is being sent yet, so anything marked Sending was interrupted */
+		match db::mark_interrupted_sends(&conn) {
+			Ok(0) => (),
+			Ok(n) => println!("{} messages were being sent when vgmms last exited; their status is unknown", n),
+			Err(e) => eprintln!("error checking for interrupted sends: {}", e),
+		}
 
 		let next_message_id = match db::get_next_message_id(&mut conn) {
 			Ok(id) => id,
@@ -258,64 +1219,247 @@ impl Default for VgmmsState {
 			_ => 1,
 		};
 
-		let mut messages = BTreeMap::new();
-		{
-			let mut q = db::Query::new(&mut conn).unwrap();
-
-			for res in db::get_all_messages(&mut q).unwrap().unwrap() {
-				match res {
-					Ok((id, m)) => {
-						//println!("from db inserting {:?}", m);
-						messages.insert(id, m);
-					},
-					Err(e) => {
-						eprintln!("error loading messages from db: {}", e)
-					},
+		let modem_paths = backend.modems().unwrap_or_else(|e| {
+			eprintln!("error listing modems: {}", e);
+			vec![]
+		});
+		let mut identities: Vec<Identity> = vec![];
+		let mut unidentified_modems = vec![];
+		let mut locked_modems = vec![];
+		for path in modem_paths {
+			if let Some(pin_type) = sim_lock(&*backend, &path) {
+				eprintln!("SIM of modem {} needs its {}; waiting for it to be unlocked", path, pin_type);
+				locked_modems.push((path, pin_type));
+				continue
+			}
+			match identify_modem(&*backend, &conn, &path) {
+				Some((number, _)) if identities.iter().any(|i| i.number == number) =>
+					eprintln!("modem {} has the same number as another; not using it", path),
+				Some((number, country)) => identities.push(Identity { number, country, modem: Some(path) }),
+				None => {
+					eprintln!("cannot identify modem {}; waiting for our number to be entered", path);
+					unidentified_modems.push(path);
+				},
+			}
+		}
+		let have_modem = !identities.is_empty();
+		/* identities whose modems are absent can still show their chats */
+		let mut absent = db::get_own_numbers(&conn).unwrap_or_else(|e| {
+			eprintln!("error loading own numbers: {}", e);
+			vec![]
+		});
+		let home = home_country(&conn);
+		absent.extend([db::SETTING_OWN_NUMBER, db::SETTING_MY_NUMBER].iter()
+			.filter_map(|key| remembered_number(&conn, key))
+			.filter_map(|num| parse_my_number(&num, home)).map(|(num, _)| num));
+		for number in absent {
+			if identities.iter().any(|i| i.number == number) {
+				continue
+			}
+			if let Some((number, country)) = parse_my_number(&number.to_string(), home) {
+				identities.push(Identity { number, country, modem: None });
+			}
+		}
+		/* with neither a modem nor a number remembered from one, chats show all their members */
+		let (my_number, my_country) = match identities.first() {
+			Some(identity) => (identity.number, identity.country),
+			None => {
+				eprintln!("subscriber phone number unknown");
+				(Number::default(), phonenumber::country::Id::US)
+			},
+		};
+		for identity in &identities {
+			if identity.modem.is_some() {
+				if let Err(e) = db::claim_own_number(&mut conn, identity.number) {
+					eprintln!("error assigning chats to {}: {}", identity.number.to_string(), e);
 				}
 			}
 		}
+		let chat_owners = db::get_chat_owners(&conn).unwrap_or_else(|e| {
+			eprintln!("error loading chat identities: {}", e);
+			Default::default()
+		});
 
-		let attachments = db::get_all_attachments(&mut conn).unwrap();
-
-		let modem_path = match &*dbus::get_modem_paths().unwrap() {
-			[m] => m.to_owned(),
-			ms => panic!("expected 1 modem, got {}", ms.len()),
+		let delivery_reports = match db::get_setting(&conn, db::SETTING_DELIVERY_REPORTS) {
+			Ok(value) => value.as_deref() == Some("1"),
+			Err(e) => {
+				eprintln!("error loading settings: {}", e);
+				false
+			},
 		};
-		let my_number = dbus::get_my_number(&modem_path).unwrap()
-			.expect("could not determine subscriber phone number");
-		let my_country = Number::get_country(&my_number)
-			.expect("could not determine country of subscriber phone number");
-		let my_number = Number::normalize(&my_number, my_country)
-			.expect("could not parse subscriber phone number");
-
-		let mut chats = BTreeMap::new();
-		let mut open_chats = vec![];
-		for (c, tab_id, last_msg_info) in db::get_all_chats(&mut conn).unwrap().into_iter() {
-			/* insert into open_chats if open */
-			if tab_id >= 0 {
-				let tab_id = tab_id as usize;
-				/* ensure sufficient room in open_chats */
-				while open_chats.len() <= tab_id {
-					open_chats.push(Default::default());
-				}
-				open_chats[tab_id] = c.clone();
+		for modem_path in identities.iter().filter_map(|i| i.modem.as_ref()) {
+			if let Err(e) = backend.set_delivery_reports(modem_path, delivery_reports) {
+				eprintln!("error setting delivery reports on {}: {}", modem_path, e);
 			}
-			/* insert into chats map */
-			chats.insert(c, last_msg_info);
 		}
 
-		VgmmsState {
+		let outbox = db::get_outbox(&conn).unwrap_or_else(|e| {
+			eprintln!("error loading outbox: {}", e);
+			Default::default()
+		});
+
+		if !have_modem {
+			println!("no modem found; message history is read-only");
+			match db::connect_read_only() {
+				Ok(read_only) => conn = read_only,
+				Err(e) => eprintln!("could not reopen database read-only: {}", e),
+			}
+		}
+		let (chats, open_chats) = load_chats(&conn).unwrap();
+
+		let mut state = VgmmsState {
 			open_chats,
 			chats,
-			messages,
+			messages: Default::default(),
+			chat_messages: Default::default(),
+			loaded_chats: Default::default(),
 			contacts: Default::default(),
-			attachments,
+			attachments: Default::default(),
 			next_message_id,
 			next_attachment_id,
 			my_number,
 			my_country,
-			modem_path,
+			identities,
+			unidentified_modems,
+			locked_modems,
+			chat_owners,
+			delivery_reports,
+			outbox,
+			sending: Default::default(),
+			handed_over: Default::default(),
+			send_queue: spawn_send_worker(backend.clone(), results.clone()),
+			backfill_queue: spawn_backfill_worker(backend.clone(), results),
+			early_statuses: Default::default(),
+			saved_sms: Default::default(),
+			send_errors: Default::default(),
+			backend,
 			db_conn: conn,
+		};
+		if have_modem {
+			state.backfill_mms();
+			state.flush_outbox(true);
+		}
+		for chat in state.open_chats.clone() {
+			state.load_chat(&chat);
 		}
+		state
 	}
 }
+
+/* nothing, shown until the window is handed the backend to start with */
+impl Default for VgmmsState {
+	fn default() -> Self {
+		let mut next_message_id = [0u8; 20];
+		next_message_id.increment();
+		VgmmsState {
+			open_chats: Default::default(),
+			chats: Default::default(),
+			messages: Default::default(),
+			chat_messages: Default::default(),
+			loaded_chats: Default::default(),
+			contacts: Default::default(),
+			attachments: Default::default(),
+			next_message_id,
+			next_attachment_id: 1,
+			my_number: Number::default(),
+			my_country: phonenumber::country::Id::US,
+			identities: Default::default(),
+			unidentified_modems: Default::default(),
+			locked_modems: Default::default(),
+			chat_owners: Default::default(),
+			delivery_reports: false,
+			outbox: Default::default(),
+			sending: Default::default(),
+			handed_over: Default::default(),
+			/* nothing is sent, and there is no one to report to */
+			send_queue: std::sync::mpsc::channel().0,
+			backfill_queue: std::sync::mpsc::channel().0,
+			early_statuses: Default::default(),
+			saved_sms: Default::default(),
+			send_errors: Default::default(),
+			backend: Arc::new(crate::backend::NoBackend),
+			db_conn: rusqlite::Connection::open_in_memory().expect("could not open in-memory database"),
+		}
+	}
+}
+
+#[test]
+fn test_retry_delay() {
+	assert_eq!(retry_delay(1), 30 * 1000);
+	assert_eq!(retry_delay(3), 120 * 1000);
+	assert_eq!(retry_delay(40), 60 * 60 * 1000);
+}
+
+#[test]
+fn test_failure_reported_later() {
+	let mut state = VgmmsState::default();
+	db::migrate(&mut state.db_conn).unwrap();
+	let id = state.next_message_id();
+	state.add_message(id, MessageInfo {
+		sender: Number::new(1), chat: vec![Number::new(1), Number::new(2)], time: 0, seq: 0,
+		contents: vec![MessageItem::Text("hi".into())], status: MessageStatus::Sending,
+	});
+	state.queue_message(&id, OutboxEntry { attempts: 0, next_attempt: 0 });
+	state.sending.insert(id);
+
+	/* the backend takes it, then reports that it failed */
+	state.handle_notif(Notification::SendFinished { id, result: Ok(Some("/message/1".into())) });
+	assert!(state.outbox.is_empty());
+	state.handle_notif(Notification::StatusUpdate { path: "/message/1".into(), status: MessageStatus::Failed });
+	assert_eq!(state.outbox[&id].attempts, 1);
+	assert!(state.outbox[&id].next_attempt >= retry_delay(1));
+	assert!(state.send_errors.contains_key(&id));
+	let saved = db::Query::new(&state.db_conn).unwrap().message(&id).unwrap().unwrap();
+	assert_eq!(saved.status, MessageStatus::Failed);
+
+	/* once sent on another attempt, a later failure report is not retried again */
+	state.sending.insert(id);
+	state.handle_notif(Notification::SendFinished { id, result: Ok(Some("/message/2".into())) });
+	state.handle_notif(Notification::StatusUpdate { path: "/message/2".into(), status: MessageStatus::Sent });
+	state.handle_notif(Notification::StatusUpdate { path: "/message/2".into(), status: MessageStatus::Failed });
+	assert!(state.outbox.is_empty());
+}
+
+#[test]
+fn test_give_up_sending() {
+	use crate::backend::Error;
+	let mut state = VgmmsState::default();
+	db::migrate(&mut state.db_conn).unwrap();
+	let send = |state: &mut VgmmsState| {
+		let id = state.next_message_id();
+		state.add_message(id, MessageInfo {
+			sender: Number::new(1), chat: vec![Number::new(1), Number::new(2)], time: 0, seq: 0,
+			contents: vec![MessageItem::Text("fail".into())], status: MessageStatus::Sending,
+		});
+		state.queue_message(&id, OutboxEntry { attempts: 0, next_attempt: 0 });
+		id
+	};
+
+	/* a message that keeps failing is tried MAX_ATTEMPTS times, however the failure is reported */
+	let id = send(&mut state);
+	for attempt in 1..=MAX_ATTEMPTS {
+		assert!(state.outbox.contains_key(&id));
+		state.sending.insert(id);
+		if attempt % 2 == 0 {
+			state.handle_notif(Notification::SendFinished { id, result: Err(Error::Failed("no network".into())) });
+		} else {
+			let path = format!("/message/{}", attempt);
+			state.handle_notif(Notification::SendFinished { id, result: Ok(Some(path.clone())) });
+			state.handle_notif(Notification::StatusUpdate { path, status: MessageStatus::Failed });
+		}
+	}
+	assert!(!state.outbox.contains_key(&id));
+	assert!(!state.send_errors.contains_key(&id));
+	let saved = db::Query::new(&state.db_conn).unwrap().message(&id).unwrap().unwrap();
+	assert_eq!(saved.status, MessageStatus::Failed);
+
+	/* one the stack rejects is not retried at all */
+	let id = send(&mut state);
+	state.sending.insert(id);
+	state.handle_notif(Notification::SendFinished { id, result: Err(Error::Rejected("invalid number".into())) });
+	assert!(!state.outbox.contains_key(&id));
+
+	/* retrying by hand starts over */
+	state.retry_message(&id);
+	assert_eq!(state.outbox[&id].attempts, 0);
+}
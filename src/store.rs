@@ -0,0 +1,95 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+/*
+	content-addressed attachment storage: each distinct blob is kept once, at
+	<dir>/<first two hex digits of its sha256>/<sha256 in hex>
+*/
+
+pub fn read_file_chunk(path: &Path, start: u64, len: u64) -> Result<Vec<u8>, io::Error> {
+	let mut file = File::open(path)?;
+	file.seek(SeekFrom::Start(start))?;
+	let mut out = vec![0; len as usize];
+	file.read_exact(&mut out[..])?;
+	Ok(out)
+}
+
+/* copy len bytes at start in src into the store in dir, returning the path of the stored copy */
+pub fn import(dir: &Path, src: &Path, start: u64, len: u64) -> Result<PathBuf, io::Error> {
+	let mut file = File::open(src)?;
+	file.seek(SeekFrom::Start(start))?;
+	import_from(dir, file.take(len), len)
+}
+
+/* copy data into the store in dir, returning the path of the stored copy */
+pub fn import_bytes(dir: &Path, data: &[u8]) -> Result<PathBuf, io::Error> {
+	import_from(dir, data, data.len() as u64)
+}
+
+fn import_from<R: Read>(dir: &Path, mut src: R, len: u64) -> Result<PathBuf, io::Error> {
+	use rand::Rng;
+	std::fs::create_dir_all(dir)?;
+	let tmp_path = dir.join(format!(".tmp-{:x}", rand::thread_rng().gen::<u32>()));
+	let mut tmp = File::create(&tmp_path)?;
+
+	/* hash while copying into a temporary file, then move it into place */
+	let res = (|| {
+		let mut hasher = Sha256::new();
+		let mut buf = [0u8; 64 * 1024];
+		let mut copied = 0;
+		loop {
+			let n = src.read(&mut buf)?;
+			if n == 0 {
+				break
+			}
+			hasher.update(&buf[..n]);
+			tmp.write_all(&buf[..n])?;
+			copied += n as u64;
+		}
+		if copied != len {
+			return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+				format!("expected {} bytes, read {}", len, copied)))
+		}
+		tmp.sync_all()?;
+		Ok(hex::encode(hasher.finalize()))
+	})();
+	let hash = match res {
+		Ok(hash) => hash,
+		Err(e) => {
+			let _ = std::fs::remove_file(&tmp_path);
+			return Err(e)
+		},
+	};
+
+	let mut path = dir.join(&hash[..2]);
+	std::fs::create_dir_all(&path)?;
+	path.push(&hash);
+	if path.exists() {
+		/* identical content is already stored */
+		std::fs::remove_file(&tmp_path)?;
+	} else {
+		std::fs::rename(&tmp_path, &path)?;
+	}
+	Ok(path)
+}
+
+#[test]
+fn test_import_dedup() {
+	use rand::Rng;
+	let dir = std::env::temp_dir().join(format!("vgmms-test-{:x}", rand::thread_rng().gen::<u32>()));
+	let a = import_bytes(&dir, b"hello").unwrap();
+	let b = import_bytes(&dir, b"hello").unwrap();
+	let c = import_bytes(&dir, b"world").unwrap();
+	assert_eq!(a, b);
+	assert_ne!(a, c);
+	assert!(a.starts_with(&dir));
+	assert_eq!(std::fs::read(&a).unwrap(), b"hello");
+
+	/* importing part of a file stores just that part */
+	let d = import(&dir, &c, 1, 3).unwrap();
+	assert_eq!(std::fs::read(&d).unwrap(), b"orl");
+	std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -25,7 +25,6 @@ impl Attachment {
 pub type Country = phonenumber::country::Id;
 
 #[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
-#[repr(C)]
 pub struct Number {
 	pub num: u64,
 }
@@ -135,7 +134,7 @@ pub struct Contact {
 }
 
 #[allow(dead_code)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 #[repr(u8)]
 pub enum MessageStatus {
 	Received = 0,
@@ -143,24 +142,54 @@ pub enum MessageStatus {
 	Sending,
 	Sent,
 	Failed,
+	/* was Sending when vgmms stopped; whether it went out is not known */
+	Unknown,
+	Delivered,
+	Read,
 }
 
 impl MessageStatus {
 	pub fn from_u8(n: u8) -> Option<MessageStatus> {
 		use MessageStatus::*;
-		[Received, Draft, Sending, Sent, Failed].get(n as usize).cloned()
+		[Received, Draft, Sending, Sent, Failed, Unknown, Delivered, Read].get(n as usize).cloned()
+	}
+
+	pub fn name(&self) -> &'static str {
+		use MessageStatus::*;
+		match self {
+			Received => "received",
+			Draft => "draft",
+			Sending => "sending",
+			Sent => "sent",
+			Failed => "failed",
+			Unknown => "unknown",
+			Delivered => "delivered",
+			Read => "read",
+		}
 	}
 }
 
+/* (time, seq): the order messages are shown in */
+pub type MessageOrder = (u64, u64);
+
 #[derive(Clone, Debug)]
 pub struct MessageInfo {
 	pub sender: Number,
 	pub chat: Vec<Number>,
+	/* milliseconds since the epoch */
 	pub time: u64,
+	/* order in which messages were saved; assigned by db::insert_message */
+	pub seq: u64,
 	pub contents: Vec<MessageItem>,
 	pub status: MessageStatus,
 }
 
+impl MessageInfo {
+	pub fn order(&self) -> MessageOrder {
+		(self.time, self.seq)
+	}
+}
+
 #[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Chat {
 	pub numbers: Vec<Number>,
@@ -179,20 +208,90 @@ impl Chat {
 			.map(|x| x.to_string())
 			.collect::<Vec<_>>().join(", ")
 	}
+
+	/* the first member that is one of our own numbers */
+	pub fn own_number(&self, my_numbers: &[Number]) -> Option<Number> {
+		self.numbers.iter().cloned().find(|n| my_numbers.contains(n))
+	}
+}
+
+/* one of our own phone numbers and the modem it belongs to */
+#[derive(Clone, Debug)]
+pub struct Identity {
+	pub number: Number,
+	pub country: Country,
+	/* None if its modem isn't present; its history can still be read */
+	pub modem: Option<crate::backend::ModemId>,
 }
 
 use std::collections::{BTreeMap, HashMap};
 
+/* which of a chat's messages are loaded into VgmmsState::messages */
+#[derive(Clone, Debug, Default)]
+pub struct ChatPages {
+	/* order of the oldest loaded message, before which older pages are loaded */
+	pub oldest: Option<MessageOrder>,
+	/* whether there are no older messages to load */
+	pub complete: bool,
+}
+
+/* a message waiting to be handed to the backend */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutboxEntry {
+	/* how many times sending it has failed */
+	pub attempts: u32,
+	/* when to try again automatically, in ms since the epoch */
+	pub next_attempt: u64,
+}
+
+/* a message of the outbox for the send worker to hand to the backend */
+pub struct SendJob {
+	pub id: MessageId,
+	pub modem: crate::backend::ModemId,
+	pub message: MessageInfo,
+	/* the attachments of the message */
+	pub attachments: HashMap<AttachmentId, Attachment>,
+}
+
 pub struct VgmmsState {
 	pub open_chats: Vec<Chat>,
 	pub chats: BTreeMap<Chat, Option<(u64, MessageId)>>,
+	/* messages of loaded chats only; see loaded_chats */
 	pub messages: BTreeMap<MessageId, MessageInfo>,
+	/* ids of each loaded chat's messages in chronological order */
+	pub chat_messages: BTreeMap<Chat, BTreeMap<MessageOrder, MessageId>>,
+	pub loaded_chats: BTreeMap<Chat, ChatPages>,
 	pub contacts: HashMap<Number, Contact>,
 	pub attachments: HashMap<AttachmentId, Attachment>,
 	pub next_message_id: MessageId,
 	pub next_attachment_id: AttachmentId,
+	/* the identity used where nothing says which one to use */
 	pub my_number: Number,
 	pub my_country: Country,
-	pub modem_path: dbus::strings::Path<'static>,
+	pub identities: Vec<Identity>,
+	/* modems present whose number we don't know; they are used once the user enters it */
+	pub unidentified_modems: Vec<crate::backend::ModemId>,
+	/* modems whose SIM waits for a PIN or PUK, with the kind it waits for */
+	pub locked_modems: Vec<(crate::backend::ModemId, String)>,
+	/* which identity each chat belongs to, where the db records it */
+	pub chat_owners: BTreeMap<Chat, Number>,
+	/* whether to request delivery reports for SMS we send */
+	pub delivery_reports: bool,
+	pub outbox: BTreeMap<MessageId, OutboxEntry>,
+	/* messages of the outbox the send worker has yet to report on */
+	pub sending: std::collections::HashSet<MessageId>,
+	/* messages the backend took this run but may yet report failed, with the attempts made at them */
+	pub handed_over: HashMap<MessageId, u32>,
+	pub send_queue: std::sync::mpsc::Sender<SendJob>,
+	/* the numbers of our chats, for the backfill worker to ask the backend for stored MMS with */
+	pub backfill_queue: std::sync::mpsc::Sender<Vec<String>>,
+	/* statuses reported while messages were being sent, for paths not yet linked to one */
+	pub early_statuses: HashMap<String, MessageStatus>,
+	/* SMS the backend stores until they are saved, that we have saved this run */
+	pub saved_sms: std::collections::HashSet<String>,
+	/* why each queued message last failed to send */
+	pub send_errors: BTreeMap<MessageId, crate::backend::Error>,
+	/* what messages are sent and received through */
+	pub backend: std::sync::Arc<dyn crate::backend::MessagingBackend>,
 	pub db_conn: rusqlite::Connection,
 }
@@ -1,7 +1,7 @@
 use vgtk::ext::*;
 use vgtk::lib::gtk::{self, *, Box as GtkBox};
-use vgtk::lib::glib;
-use vgtk::lib::gio::{ActionExt, ApplicationFlags, SimpleAction};
+use vgtk::lib::glib::{self, ToVariant};
+use vgtk::lib::gio::{self, ActionExt, ApplicationFlags, SimpleAction};
 use vgtk::{gtk, Component, UpdateAction, VNode};
 
 use std::default::Default;
@@ -10,19 +10,48 @@ use std::boxed::Box;
 
 use crate::types::*;
 
-use crate::{chat_log, file_chooser, new_chat, select_chat};
-use crate::{db, dbus, once};
+use crate::{chat_log, file_chooser, new_chat, own_number, search, select_chat, sim_pin};
+use crate::{android_backup, backend, db, export, once};
 
 #[derive(Clone, Default)]
 pub struct WindowModel {
 	state: Arc<RwLock<VgmmsState>>,
 	current_page: i32,
+	/* message to scroll to, e.g. a search hit */
+	scroll_to: Option<MessageId>,
+	/* whether we asked for our number without being asked to, so we don't keep asking */
+	asked_own_number: bool,
+	/* whether the SIM unlock dialog is open */
+	asking_pin: bool,
+	/* why the last unlock failed, to show when asking again */
+	pin_error: Option<String>,
+	/* the number and country last entered and why setting them failed, to show when asking again */
+	own_number_error: Option<(String, String, String)>,
+}
+
+/* the backend for the window to start with, and where its send worker is to report, handed
+over in a UiMessage */
+#[derive(Clone)]
+pub struct Handover(Arc<std::sync::Mutex<Option<(Box<dyn backend::MessagingBackend>, backend::NotifSink)>>>);
+
+impl Handover {
+	pub fn new(backend: Box<dyn backend::MessagingBackend>, results: backend::NotifSink) -> Self {
+		Handover(Arc::new(std::sync::Mutex::new(Some((backend, results)))))
+	}
+}
+
+impl std::fmt::Debug for Handover {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(f, "Handover")
+	}
 }
 
 #[derive(Clone, Debug)]
 pub enum UiMessage {
-	Notif(dbus::DbusNotification),
-	Send((Chat, Vec<DraftItem>)),
+	/* open the database and set up modems through the backend; sent once, first */
+	Start(Handover),
+	Notif(backend::Notification),
+	Send((Chat, Number, Vec<DraftItem>)),
 	AskDelete(MessageId),
 	Delete(MessageId),
 	Exit,
@@ -31,7 +60,22 @@ pub enum UiMessage {
 	SelectChat,
 	DefineChat,
 	OpenChat(Vec<Number>),
+	OpenChatAt(Vec<Number>, MessageId),
+	Search,
 	SaveAttachmentDialog(AttachmentId),
+	ExportChat(export::Format),
+	ImportBackup,
+	ExportBackup,
+	SetDeliveryReports(bool),
+	/* ask to unlock locked SIMs, then for our number if no modem tells us */
+	CheckModems,
+	AskPin,
+	/* modem path, pin type, PIN or PUK, new PIN with a PUK */
+	UnlockSim(String, String, String, Option<String>),
+	AskOwnNumber,
+	SetOwnNumber(String, String),
+	FlushOutbox,
+	Refresh,
 	Nop,
 }
 
@@ -44,6 +88,53 @@ fn message_id_from_hex(s: &str) -> MessageId {
 	id
 }
 
+/* ask for a single file, resolving to None if the dialog is cancelled */
+fn choose_file(action: FileChooserAction, title: &str, accept_label: &str, default_name: Option<String>)
+	-> impl std::future::Future<Output=Option<std::path::PathBuf>> {
+	let (notify, path_result) = futures::channel::oneshot::channel();
+	let fut = vgtk::run_dialog_props::<file_chooser::FileChooser>(vgtk::current_window().as_ref(),
+		file_chooser::FileChooser {
+			on_choose: {let cb: vgtk::Callback<Vec<std::path::PathBuf>> = Box::new(once::once(move |filenames| {
+				let _ = notify.send(filenames);
+			})).into(); cb},
+			action: Some(action),
+			title: title.into(),
+			select_multiple: false,
+			accept_label: accept_label.into(),
+			default_name,
+		});
+	async move {
+		if let Ok(ResponseType::Accept) = fut.await {
+			if let Ok(mut paths) = path_result.await {
+				if paths.len() == 1 {
+					return paths.pop()
+				}
+			}
+		}
+		None
+	}
+}
+
+/* menu of actions on the current chat tab */
+fn chat_menu() -> gio::Menu {
+	let menu = gio::Menu::new();
+	for &(label, format) in &[("Export as _HTML...", "html"), ("Export as _text...", "text"), ("Export as _JSON...", "json")] {
+		let item = gio::MenuItem::new(Some(label), None);
+		item.set_action_and_target_value(Some("app.export-chat"), Some(&format.into()));
+		menu.append_item(&item);
+	}
+	let backup = gio::Menu::new();
+	backup.append(Some("_Import Android backup..."), Some("app.import-android-backup"));
+	backup.append(Some("Export _Android backup..."), Some("app.export-android-backup"));
+	menu.append_section(None, &backup);
+	let settings = gio::Menu::new();
+	settings.append(Some("_Delivery reports"), Some("app.delivery-reports"));
+	settings.append(Some("_Own number..."), Some("app.own-number"));
+	settings.append(Some("_Unlock SIM..."), Some("app.unlock-sim"));
+	menu.append_section(None, &settings);
+	menu
+}
+
 fn apply_tab_label(nb: &Notebook, child: &Widget)
 {
 	let text = child.get_widget_name();
@@ -63,17 +154,36 @@ impl Component for WindowModel {
 	fn update(&mut self, msg: Self::Message) -> UpdateAction<Self> {
 		use UiMessage::*;
 		match msg {
+			Start(Handover(backend)) => {
+				if let Some((backend, results)) = backend.lock().unwrap().take() {
+					*self.state.write().unwrap() = VgmmsState::new(backend, results);
+				}
+				UpdateAction::Render
+			},
 			Notif(notif) => {
+				let pin_changed = match notif {
+					backend::Notification::PinRequired { .. } => true,
+					_ => false,
+				};
 				let mut state = self.state.write().unwrap();
 				state.handle_notif(notif);
+				if pin_changed && !self.asking_pin && !state.locked_modems.is_empty() {
+					drop(state);
+					return self.update(AskPin)
+				}
+				if !self.asked_own_number && state.needs_own_number() && !state.unidentified_modems.is_empty() {
+					drop(state);
+					return self.update(AskOwnNumber)
+				}
 				UpdateAction::Render
 			},
-			Send((chat, draft_items)) => {
+			Send((chat, from, draft_items)) => {
 				if draft_items.len() == 0 {
 					return UpdateAction::None
 				}
 				let mut state = self.state.write().unwrap();
-				state.send_message(&chat, draft_items);
+				state.send_message(&chat, from, draft_items);
+				self.scroll_to = None;
 				UpdateAction::Render
 			},
 			AskDelete(_msg_id) => {
@@ -82,10 +192,7 @@ impl Component for WindowModel {
 			},
 			Delete(msg_id) => {
 				let mut state = self.state.write().unwrap();
-				if let Err(e) = db::delete_message(&mut state.db_conn, &msg_id) {
-					eprintln!("error deleting message: {}", e);
-				}
-				state.messages.remove(&msg_id);
+				state.delete_message(&msg_id);
 				UpdateAction::Render
 			},
 			Exit => {
@@ -106,6 +213,7 @@ impl Component for WindowModel {
 					if let Err(e) = db::close_chat(&mut state.db_conn, &chat) {
 						eprintln!("error saving chat state: {}", e);
 					}
+					state.evict_chat(&chat);
 					if self.current_page >= state.open_chats.len() as i32 {
 						self.current_page -= 1;
 					}
@@ -124,7 +232,7 @@ impl Component for WindowModel {
 
 				let fut = vgtk::run_dialog_props::<select_chat::SelectChatDialog>(vgtk::current_window().as_ref(),
 					select_chat::SelectChatDialog {
-						my_number: state.my_number,
+						identities: state.own_numbers(),
 						chats_summaries: state.summarize_all(),
 						numbers_shared: numbers_shared.clone(),
 						on_new_chat: {let cb: vgtk::Callback<()> = Box::new(once::once(move |()| {
@@ -185,7 +293,8 @@ impl Component for WindowModel {
 				let mut state = self.state.write().unwrap();
 				let my_number = state.my_number;
 
-				if !nums.contains(&my_number) {
+				/* chats not with one of our numbers are with the default one */
+				if nums.iter().all(|n| !state.identities.iter().any(|i| i.number == *n)) {
 					nums.push(my_number);
 				}
 				nums.sort();
@@ -218,11 +327,54 @@ impl Component for WindowModel {
 							}
 							state.chats.insert(chat.clone(), None);
 						}
+						state.load_chat(&chat);
 						state.open_chats.insert(self.current_page as usize, chat);
 					},
 				}
 				UpdateAction::Render
 			},
+			OpenChatAt(nums, msg_id) => {
+				self.scroll_to = Some(msg_id);
+				let action = self.update(OpenChat(nums));
+				let mut state = self.state.write().unwrap();
+				if let Some(chat) = state.open_chats.get(self.current_page as usize).cloned() {
+					state.load_chat_through(&chat, &msg_id);
+				}
+				action
+			},
+			Search => {
+				use std::sync::Mutex;
+				let selected_shared: Arc<Mutex<Option<(Vec<Number>, MessageId)>>> = Default::default();
+				let state = self.state.read().unwrap();
+
+				let current_chat = if self.current_page >= 0 {
+					state.open_chats.get(self.current_page as usize).cloned()
+				} else {
+					None
+				};
+				let fut = vgtk::run_dialog_props::<search::SearchDialog>(vgtk::current_window().as_ref(),
+					search::SearchDialog {
+						state: self.state.clone(),
+						current_chat,
+						only_current_chat: false,
+						query: String::new(),
+						hits: Default::default(),
+						selected_shared: selected_shared.clone(),
+					});
+
+				let fut = async move {
+					if let Ok(ResponseType::Accept) = fut.await {
+						match selected_shared.lock().unwrap().take() {
+							Some((nums, msg_id)) => OpenChatAt(nums, msg_id),
+							None => Nop,
+						}
+					} else {
+						Nop
+					}
+				};
+
+				UpdateAction::Defer(Box::pin(fut))
+			},
 			SaveAttachmentDialog(att_id) => {
 				let state = self.state.read().unwrap();
 				let (notify, path_result) = futures::channel::oneshot::channel();
@@ -265,6 +417,226 @@ impl Component for WindowModel {
 
 				UpdateAction::Defer(Box::pin(fut))
 			},
+			ExportChat(format) => {
+				let state = self.state.read().unwrap();
+				let chat = match state.open_chats.get(self.current_page as usize) {
+					Some(chat) if self.current_page >= 0 => chat.clone(),
+					_ => return UpdateAction::None,
+				};
+				let own_number = state.own_number_of(&chat);
+				let history = match export::History::load(&state.db_conn, &chat, own_number) {
+					Ok(history) => history,
+					Err(e) => {
+						eprintln!("error loading chat to export: {}", e);
+						return UpdateAction::None
+					},
+				};
+				let default_name = format!("{}{}", chat.get_name(&own_number), format.extension());
+				let fut = choose_file(FileChooserAction::Save, "Export conversation", "_Export", Some(default_name));
+
+				let fut = async move {
+					if let Some(path) = fut.await {
+						if let Err(e) = export::export(&history, format, &path) {
+							eprintln!("error exporting chat: {}", e);
+						}
+					}
+					Nop
+				};
+
+				UpdateAction::Defer(Box::pin(fut))
+			},
+			ImportBackup => {
+				let state = self.state.clone();
+				let (my_number, my_country) = {
+					let state = state.read().unwrap();
+					(state.my_number, state.my_country)
+				};
+				let fut = choose_file(FileChooserAction::Open, "Import Android SMS backup", "_Import", None);
+
+				let fut = async move {
+					if let Some(path) = fut.await {
+						/* reading a big backup takes a while, so it is done on a thread of its own */
+						let (done, read) = futures::channel::oneshot::channel();
+						let path_ = path.clone();
+						std::thread::spawn(move || {
+							let dir = db::attachment_dir();
+							let importer = android_backup::Importer { my_number, my_country, attachment_dir: &dir };
+							let _ = done.send(importer.read_file(&path_));
+						});
+						let backup = match read.await {
+							Ok(backup) => backup,
+							Err(_cancelled) => return Nop,
+						};
+						match backup.and_then(|backup| state.write().unwrap().import_android_backup(backup)) {
+							Ok(stats) => println!("imported {} messages ({} already present, {} skipped)",
+								stats.imported, stats.duplicates, stats.skipped),
+							Err(e) => eprintln!("error importing {}: {}", path.display(), e),
+						}
+						Refresh
+					} else {
+						Nop
+					}
+				};
+
+				UpdateAction::Defer(Box::pin(fut))
+			},
+			ExportBackup => {
+				let state = self.state.clone();
+				let fut = choose_file(FileChooserAction::Save, "Export Android SMS backup", "_Export", Some("sms-vgmms.xml".into()));
+
+				let fut = async move {
+					if let Some(path) = fut.await {
+						match state.read().unwrap().export_android_backup(&path) {
+							Ok(count) => println!("exported {} messages", count),
+							Err(e) => eprintln!("error exporting {}: {}", path.display(), e),
+						}
+					}
+					Nop
+				};
+
+				UpdateAction::Defer(Box::pin(fut))
+			},
+			SetDeliveryReports(enabled) => {
+				self.state.write().unwrap().set_delivery_reports(enabled);
+				UpdateAction::None
+			},
+			CheckModems => {
+				let (locked, needs_own_number) = {
+					let state = self.state.read().unwrap();
+					(!state.locked_modems.is_empty(), state.needs_own_number())
+				};
+				if locked && !self.asking_pin {
+					self.update(AskPin)
+				} else if needs_own_number && !self.asked_own_number {
+					self.update(AskOwnNumber)
+				} else {
+					UpdateAction::None
+				}
+			},
+			AskPin => {
+				use std::sync::Mutex;
+				let mut state = self.state.write().unwrap();
+				let (modem, cached_type) = match state.locked_modems.first() {
+					Some((modem, pin_type)) => (modem.clone(), pin_type.clone()),
+					None => return UpdateAction::None,
+				};
+				/* a wrong PIN may have blocked it since, so that it wants the PUK */
+				let (pin_type, retries) = match state.backend.pin_required(&modem) {
+					Ok(required) => required,
+					Err(e) => {
+						eprintln!("error getting SIM PIN retries: {}", e);
+						(cached_type, None)
+					},
+				};
+				/* unlocked meanwhile; the backend will say so */
+				if pin_type == "none" {
+					return UpdateAction::None
+				}
+				if let Some(locked) = state.locked_modems.iter_mut().find(|(p, _)| *p == modem) {
+					locked.1 = pin_type.clone();
+				}
+				drop(state);
+				self.asking_pin = true;
+				let shared: Arc<Mutex<(String, String)>> = Default::default();
+
+				let fut = vgtk::run_dialog_props::<sim_pin::SimPinDialog>(vgtk::current_window().as_ref(),
+					sim_pin::SimPinDialog {
+						modem: modem.to_string(),
+						pin_type: pin_type.clone(),
+						retries,
+						error: self.pin_error.take(),
+						shared: shared.clone(),
+					});
+
+				let fut = async move {
+					if let Ok(ResponseType::Accept) = fut.await {
+						let (pin, new_pin) = shared.lock().unwrap().clone();
+						let new_pin = if sim_pin::is_puk(&pin_type) { Some(new_pin) } else { None };
+						UnlockSim(modem.to_string(), pin_type, pin, new_pin)
+					} else {
+						UnlockSim(modem.to_string(), pin_type, String::new(), None)
+					}
+				};
+
+				UpdateAction::Defer(Box::pin(fut))
+			},
+			UnlockSim(modem, pin_type, pin, new_pin) => {
+				self.asking_pin = false;
+				self.pin_error = None;
+				/* cancelled */
+				if pin.is_empty() {
+					return UpdateAction::Render
+				}
+				let result = self.state.write().unwrap().unlock_sim(&modem, &pin_type, &pin, new_pin.as_deref());
+				match result {
+					/* the backend tells us once the SIM is unlocked, and we ask for our number then if needed */
+					Ok(()) => UpdateAction::Render,
+					Err(e) => {
+						eprintln!("{}", e);
+						self.pin_error = Some(e);
+						/* ask again */
+						match self.update(CheckModems) {
+							UpdateAction::None => UpdateAction::Render,
+							ask => ask,
+						}
+					},
+				}
+			},
+			AskOwnNumber => {
+				use std::sync::Mutex;
+				self.asked_own_number = true;
+				let state = self.state.read().unwrap();
+				let (number, country, error) = match (self.own_number_error.take(), state.manual_own_number()) {
+					(Some((number, country, error)), _) => (number, country, Some(error)),
+					(None, Some((number, country))) => (number.to_string(), country.as_ref().to_owned(), None),
+					(None, None) => (String::new(), state.my_country.as_ref().to_owned(), None),
+				};
+				let shared: Arc<Mutex<(String, String)>> = Default::default();
+
+				let fut = vgtk::run_dialog_props::<own_number::OwnNumberDialog>(vgtk::current_window().as_ref(),
+					own_number::OwnNumberDialog {
+						number,
+						country,
+						error,
+						shared: shared.clone(),
+					});
+
+				let fut = async move {
+					if let Ok(ResponseType::Accept) = fut.await {
+						let (number, country) = shared.lock().unwrap().clone();
+						SetOwnNumber(number, country)
+					} else {
+						Nop
+					}
+				};
+
+				UpdateAction::Defer(Box::pin(fut))
+			},
+			SetOwnNumber(number, country) => {
+				let mut state = self.state.write().unwrap();
+				if let Err(e) = state.set_own_number(&number, &country) {
+					eprintln!("{}", e);
+					/* ask again, saying why */
+					drop(state);
+					self.own_number_error = Some((number, country, e));
+					return self.update(AskOwnNumber)
+				}
+				/* re-keying may have merged open chats */
+				if self.current_page >= state.open_chats.len() as i32 {
+					self.current_page = state.open_chats.len() as i32 - 1;
+				}
+				UpdateAction::Render
+			},
+			FlushOutbox => {
+				if self.state.write().unwrap().flush_outbox(false) {
+					UpdateAction::Render
+				} else {
+					UpdateAction::None
+				}
+			},
+			Refresh => {
+				UpdateAction::Render
+			},
 			Nop => {
 				UpdateAction::None
 			},
@@ -273,17 +645,22 @@ impl Component for WindowModel {
 
 	fn view(&self) -> VNode<WindowModel> {
 		let state = self.state.read().unwrap();
-		let my_number = state.my_number;
 		let my_country = state.my_country;
 		let no_chats = state.chats.len() == 0;
 		let no_chats_open = state.open_chats.len() == 0;
+		let delivery_reports = state.delivery_reports;
+		let have_modem = state.has_modem();
+		let sim_locked = !state.locked_modems.is_empty();
+		let identities = state.own_numbers();
+		let tab_names = state.open_chats.iter().map(|c| c.get_name(&state.own_number_of(c))).collect::<Vec<_>>();
+		let scroll_to = self.scroll_to;
 		let actions = vec![
 			gtk! {<SimpleAction::new("save-attachment-dialog",
 				Some(glib::VariantTy::new("t").unwrap())) enabled=true
 				on activate=|_a, id| UiMessage::SaveAttachmentDialog(id.unwrap().get().unwrap())
 			/>},
 			gtk! {<SimpleAction::new("delete-message",
-				Some(glib::VariantTy::new("s").unwrap())) enabled=true
+				Some(glib::VariantTy::new("s").unwrap())) enabled=have_modem
 				on activate=|_a, id| UiMessage::Delete(message_id_from_hex(&id.unwrap().get::<String>().unwrap()))
 			/>},
 			gtk! {<SimpleAction::new("exit", None) Application::accels=["<Ctrl>q"].as_ref() enabled=true
@@ -295,6 +672,41 @@ impl Component for WindowModel {
 			gtk! {<SimpleAction::new("close-tab", None) Application::accels=["<Ctrl>w"].as_ref() enabled=true
 				on activate=|_a, _| UiMessage::CloseCurrentChat
 			/>},
+			gtk! {<SimpleAction::new("search", None) Application::accels=["<Ctrl>f"].as_ref() enabled=true
+				on activate=|_a, _| UiMessage::Search
+			/>},
+			gtk! {<SimpleAction::new("import-android-backup", None) enabled=true
+				on activate=|_a, _| UiMessage::ImportBackup
+			/>},
+			gtk! {<SimpleAction::new("export-android-backup", None) enabled=true
+				on activate=|_a, _| UiMessage::ExportBackup
+			/>},
+			gtk! {<SimpleAction::new_stateful("delivery-reports", None, &delivery_reports.to_variant()) enabled=have_modem
+				on change_state=|a, value| {
+					let value = value.unwrap();
+					a.set_state(value);
+					UiMessage::SetDeliveryReports(value.get::<bool>().unwrap())
+				}
+			/>},
+			gtk! {<SimpleAction::new("unlock-sim", None) enabled=sim_locked
+				on activate=|_a, _| UiMessage::AskPin
+			/>},
+			gtk! {<SimpleAction::new("own-number", None) enabled=true
+				on activate=|_a, _| UiMessage::AskOwnNumber
+			/>},
+			gtk! {<SimpleAction::new("export-chat",
+				Some(glib::VariantTy::new("s").unwrap())) enabled=!no_chats_open
+				on activate=|_a, format| {
+					let format = format.unwrap().get::<String>().unwrap();
+					match export::Format::from_name(&format) {
+						Some(format) => UiMessage::ExportChat(format),
+						None => {
+							eprintln!("unknown export format '{}'", format);
+							UiMessage::Nop
+						},
+					}
+				}
+			/>},
 			gtk! {<SimpleAction::new("open-chat",
 				/* the glib crate has not yet released a version with array variant support */
 				Some(glib::VariantTy::new("s"/*"as"*/).unwrap())) enabled=true
@@ -328,7 +740,16 @@ impl Component for WindowModel {
 						UiMessage::Nop
 					}
 				>
-					<GtkBox::new(Orientation::Vertical, 0)>{
+					<GtkBox::new(Orientation::Vertical, 0)>
+					{
+						/* without a modem, history can be read but nothing sent or received */
+						if have_modem { None } else { Some(gtk! {
+							<InfoBar message_type=MessageType::Warning>
+								<Label label="No modem found; showing message history only" line_wrap=true />
+							</InfoBar>
+						}) }
+					}
+					{
 						if no_chats { gtk! {
 							<Button::from_icon_name(Some("list-add"), IconSize::Button)
 								GtkBox::expand=true valign=Align::Center
@@ -337,7 +758,7 @@ impl Component for WindowModel {
 							/>
 						} } else if no_chats_open { gtk! {
 							<@select_chat::SelectChat
-								my_number=my_number
+								identities=identities.clone()
 								chats_summaries=state.summarize_all()
 								on select=|nums| UiMessage::OpenChat(nums)
 								on new_chat=|_| UiMessage::DefineChat
@@ -361,6 +782,9 @@ impl Component for WindowModel {
 								}>
 								<GtkBox::new(Orientation::Horizontal, 0)
 									Notebook::action_widget_end=true>
+									<MenuButton relief=ReliefStyle::None
+										on realize=|b| { b.set_menu_model(Some(&chat_menu())); UiMessage::Nop }
+									/>
 									<Button::from_icon_name(Some("window-close"), IconSize::Menu)
 										relief=ReliefStyle::None
 										on clicked=|_| UiMessage::CloseCurrentChat
@@ -371,13 +795,14 @@ impl Component for WindowModel {
 									/>
 								</GtkBox>
 								{
-									state.open_chats.iter().map(move |c| gtk! {
+									state.open_chats.iter().zip(tab_names).map(move |(c, name)| gtk! {
 										<EventBox Notebook::tab_expand=true
-											Notebook::tab_label=c.get_name(&my_number)
-											widget_name=c.get_name(&my_number)>
+											Notebook::tab_label=name.clone()
+											widget_name=name>
 											<@chat_log::ChatLog
 												chat=c
 												state=self.state.clone()
+												scroll_to=scroll_to
 												on send=|c_drafts| UiMessage::Send(c_drafts)
 											/>
 										</EventBox>})